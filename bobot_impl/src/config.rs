@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use thiserror::Error;
+use toml::value::{Table, Value};
+
+use crate::persist::codec::{Codec, Format, DEFAULT_COMPRESS_MIN_BYTES};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const FILE: &str = r#"
+        [telegram]
+        token = "1234:abcd"
+
+        [database]
+        url = "postgres://localhost/bobot"
+
+        [redis]
+        url = "redis://localhost"
+
+        [modules.test]
+        limit = 5
+    "#;
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
+    struct TestConfig {
+        limit: u32,
+        name: String,
+    }
+
+    impl ModuleConfig for TestConfig {
+        const NAME: &'static str = "test";
+    }
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_override_test() {
+        let config = Config::from_sources(
+            Some(FILE),
+            vars(&[
+                ("BOBOT__REDIS__URL", "redis://cache"),
+                ("BOBOT__MODULES__TEST__NAME", "env"),
+                ("UNRELATED", "x"),
+            ]),
+        )
+        .unwrap();
+        assert!(config.telegram.token == "1234:abcd");
+        assert!(config.redis.url == "redis://cache");
+        let module: TestConfig = config.module().unwrap();
+        assert!(module.limit == 5);
+        assert!(module.name == "env");
+    }
+
+    #[test]
+    fn aggregate_errors_test() {
+        let err = Config::from_sources(None, vars(&[("BOBOT__DATABASE__URL", "mysql://db")]))
+            .unwrap_err();
+        assert!(err.0.len() == 3);
+        assert!(err.0.iter().any(|e| e.starts_with("telegram.token")));
+        assert!(err.0.iter().any(|e| e.starts_with("database.url must")));
+        assert!(err.0.iter().any(|e| e.starts_with("redis.url")));
+    }
+
+    #[test]
+    fn redis_codec_test() {
+        let config = Config::from_sources(Some(FILE), vars(&[])).unwrap();
+        assert!(config.redis.codec() == Codec::default());
+        let config = Config::from_sources(
+            Some(FILE),
+            vars(&[
+                ("BOBOT__REDIS__FORMAT", "json"),
+                ("BOBOT__REDIS__COMPRESS_MIN_BYTES", "0"),
+            ]),
+        )
+        .unwrap();
+        assert!(config.redis.format == Format::Json);
+        assert!(config.redis.codec().compress_min_bytes == 0);
+        let err = Config::from_sources(Some(FILE), vars(&[("BOBOT__REDIS__FORMAT", "xml")]));
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn write_cache_retries_test() {
+        let err = Config::from_sources(
+            Some(FILE),
+            vars(&[("BOBOT__WRITE_CACHE__MAX_RETRIES", "65")]),
+        )
+        .unwrap_err();
+        assert!(err.0.len() == 1);
+        assert!(err.0[0].starts_with("write_cache.max_retries"));
+    }
+
+    #[test]
+    fn module_errors_test() {
+        let config =
+            Config::from_sources(Some(FILE), vars(&[("BOBOT__MODULES__TEST__LIMIT", "lots")]))
+                .unwrap();
+        assert!(config.module_errors::<TestConfig>().len() == 1);
+        let config = Config::from_sources(Some(FILE), vars(&[])).unwrap();
+        assert!(config.module_errors::<TestConfig>().is_empty());
+    }
+}
+
+// config file used when no path is passed on the command line
+pub const DEFAULT_CONFIG_PATH: &str = "bobot.toml";
+
+// environment variables starting with this override config file values.
+// Nested keys are separated by double underscores, so
+// BOBOT__MODULES__SCHEDULE__POLL_INTERVAL_SECS sets
+// modules.schedule.poll_interval_secs
+const ENV_PREFIX: &str = "BOBOT__";
+const ENV_SEPARATOR: &str = "__";
+
+// every problem found while loading the config, reported together
+#[derive(Debug, Error)]
+#[error("invalid configuration:\n  {}", .0.join("\n  "))]
+pub struct ConfigError(pub Vec<String>);
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct TelegramConfig {
+    pub token: String,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct DatabaseConfig {
+    pub url: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RedisConfig {
+    pub url: String,
+    // format for values written to redis, msgpack or json
+    pub format: Format,
+    // values at least this many bytes are compressed, 0 never compresses
+    pub compress_min_bytes: usize,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            format: Format::default(),
+            compress_min_bytes: DEFAULT_COMPRESS_MIN_BYTES,
+        }
+    }
+}
+
+impl RedisConfig {
+    pub fn codec(&self) -> Codec {
+        Codec {
+            format: self.format,
+            compress_min_bytes: self.compress_min_bytes,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct DispatchConfig {
+    // how long a module may spend on one update before it is abandoned
+    pub timeout_ms: u64,
+}
+
+impl Default for DispatchConfig {
+    fn default() -> Self {
+        Self { timeout_ms: 30_000 }
+    }
+}
+
+// more retries only hold a failing batch back longer before it is dead-lettered
+const MAX_WRITE_RETRIES: u32 = 20;
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct WriteCacheConfig {
+    pub flush_interval_ms: u64,
+    // rows written to postgres per statement
+    pub batch_size: usize,
+    // attempts after the first failed write before a flush gives up and
+    // dead-letters the batch, with the backoff doubling every time
+    pub max_retries: u32,
+    pub retry_backoff_ms: u64,
+}
+
+impl Default for WriteCacheConfig {
+    fn default() -> Self {
+        Self {
+            flush_interval_ms: 5_000,
+            batch_size: 500,
+            max_retries: 3,
+            retry_backoff_ms: 200,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub telegram: TelegramConfig,
+    pub database: DatabaseConfig,
+    pub redis: RedisConfig,
+    pub dispatch: DispatchConfig,
+    pub write_cache: WriteCacheConfig,
+    // raw per-module sections, read through ModuleConfig
+    pub modules: HashMap<String, Value>,
+}
+
+// A module's section in the config file under [modules.<NAME>]. Missing
+// sections and fields fall back to Default
+pub trait ModuleConfig: DeserializeOwned + Default {
+    const NAME: &'static str;
+}
+
+impl Config {
+    // read the config file if present, apply environment overrides from
+    // the process environment and validate the result
+    pub fn load<P: AsRef<Path>>(path: Option<P>) -> Result<Self, ConfigError> {
+        let text = match path {
+            Some(path) => std::fs::read_to_string(path.as_ref())
+                .map(Some)
+                .map_err(|err| {
+                    ConfigError(vec![format!(
+                        "failed to read {}: {}",
+                        path.as_ref().display(),
+                        err
+                    )])
+                })?,
+            None => std::fs::read_to_string(DEFAULT_CONFIG_PATH).ok(),
+        };
+        Self::from_sources(text.as_deref(), env::vars())
+    }
+
+    // layer environment overrides on top of an optional config file
+    pub fn from_sources<I>(text: Option<&str>, vars: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut errors = Vec::new();
+        let mut root = match text.map(toml::from_str::<Table>) {
+            Some(Ok(table)) => table,
+            Some(Err(err)) => return Err(ConfigError(vec![format!("parse error: {}", err)])),
+            None => Table::new(),
+        };
+
+        for (key, val) in vars {
+            if let Some(path) = key.strip_prefix(ENV_PREFIX) {
+                let path: Vec<String> = path
+                    .split(ENV_SEPARATOR)
+                    .map(|k| k.to_lowercase())
+                    .collect();
+                if let Err(err) = set_path(&mut root, &path, env_value(val)) {
+                    errors.push(format!("{}: {}", key, err));
+                }
+            }
+        }
+
+        let config: Config = match Value::Table(root).try_into() {
+            Ok(config) => config,
+            Err(err) => {
+                errors.push(err.to_string());
+                return Err(ConfigError(errors));
+            }
+        };
+        errors.append(&mut config.validate());
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError(errors))
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.telegram.token.is_empty() {
+            errors.push("telegram.token is required".to_owned());
+        }
+        check_url(
+            &mut errors,
+            "database.url",
+            &self.database.url,
+            &["postgres", "postgresql"],
+        );
+        check_url(
+            &mut errors,
+            "redis.url",
+            &self.redis.url,
+            &["redis", "rediss"],
+        );
+        if self.dispatch.timeout_ms == 0 {
+            errors.push("dispatch.timeout_ms must be positive".to_owned());
+        }
+        if self.write_cache.flush_interval_ms == 0 {
+            errors.push("write_cache.flush_interval_ms must be positive".to_owned());
+        }
+        if self.write_cache.batch_size == 0 {
+            errors.push("write_cache.batch_size must be positive".to_owned());
+        }
+        if self.write_cache.max_retries > MAX_WRITE_RETRIES {
+            errors.push(format!(
+                "write_cache.max_retries must be at most {}",
+                MAX_WRITE_RETRIES
+            ));
+        }
+        errors
+    }
+
+    fn parse_module<T: ModuleConfig>(&self) -> Result<T, ConfigError> {
+        match self.modules.get(T::NAME) {
+            Some(section) => section
+                .clone()
+                .try_into()
+                .map_err(|err| ConfigError(vec![format!("modules.{}: {}", T::NAME, err)])),
+            None => Ok(T::default()),
+        }
+    }
+
+    // a module's config section, or its defaults if the section is missing
+    pub fn module<T: ModuleConfig>(&self) -> crate::persist::Result<T> {
+        Ok(self.parse_module()?)
+    }
+
+    // errors in a module's config section, used to validate every module at
+    // startup before anything connects
+    pub fn module_errors<T: ModuleConfig>(&self) -> Vec<String> {
+        self.parse_module::<T>()
+            .err()
+            .map(|err| err.0)
+            .unwrap_or_default()
+    }
+}
+
+fn check_url(errors: &mut Vec<String>, name: &str, url: &str, schemes: &[&str]) {
+    if url.is_empty() {
+        errors.push(format!("{} is required", name));
+    } else if !schemes
+        .iter()
+        .any(|scheme| url.starts_with(&format!("{}://", scheme)))
+    {
+        errors.push(format!(
+            "{} must start with one of {}",
+            name,
+            schemes
+                .iter()
+                .map(|s| format!("{}://", s))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ));
+    }
+}
+
+// env values are untyped, so numbers and booleans are recognized and
+// everything else is kept as a string
+fn env_value(val: String) -> Value {
+    if let Ok(v) = val.parse::<i64>() {
+        Value::Integer(v)
+    } else if let Ok(v) = val.parse::<f64>() {
+        Value::Float(v)
+    } else if let Ok(v) = val.parse::<bool>() {
+        Value::Boolean(v)
+    } else {
+        Value::String(val)
+    }
+}
+
+fn set_path(table: &mut Table, path: &[String], val: Value) -> Result<(), String> {
+    match path {
+        [] => Err("empty key".to_owned()),
+        [key] if key.is_empty() => Err("empty key".to_owned()),
+        [key] => {
+            table.insert(key.clone(), val);
+            Ok(())
+        }
+        [key, rest @ ..] => match table
+            .entry(key.clone())
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(inner) => set_path(inner, rest, val),
+            _ => Err(format!("{} is not a section", key)),
+        },
+    }
+}
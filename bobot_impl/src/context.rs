@@ -0,0 +1,48 @@
+use std::sync::Arc;
+
+use sea_orm::entity::prelude::DatabaseConnection;
+use sea_orm::{ConnectOptions, Database};
+
+use crate::config::Config;
+use crate::persist::redis::{RedisPool, RedisPoolBuilder};
+use crate::persist::Result;
+use crate::tg::api::TgApi;
+use crate::tg::dialog::{ConversationStore, RedisConversations};
+
+// Everything a module needs to handle an update. Built once per bot instance
+// at startup and passed to every handler, so several instances can share a
+// process and tests can swap in local services or mocks
+#[derive(Clone)]
+pub struct BotContext {
+    pub config: Arc<Config>,
+    pub db: Arc<DatabaseConnection>,
+    pub redis: RedisPool,
+    pub tg: Arc<dyn TgApi>,
+    pub conversations: Arc<dyn ConversationStore>,
+}
+
+impl BotContext {
+    pub fn new(
+        config: Config,
+        db: DatabaseConnection,
+        redis: RedisPool,
+        tg: Arc<dyn TgApi>,
+    ) -> Self {
+        Self {
+            config: Arc::new(config),
+            db: Arc::new(db),
+            conversations: Arc::new(RedisConversations::new(redis.clone())),
+            redis,
+            tg,
+        }
+    }
+
+    pub async fn connect(config: Config, tg: Arc<dyn TgApi>) -> Result<Self> {
+        let db = Database::connect(ConnectOptions::new(config.database.url.clone())).await?;
+        let redis = RedisPoolBuilder::new(&config.redis.url)
+            .codec(config.redis.codec())
+            .build()
+            .await?;
+        Ok(Self::new(config, db, redis, tg))
+    }
+}
@@ -1,11 +1,15 @@
 use std::path::PathBuf;
 
 use async_executors::{TokioTp, TokioTpBuilder};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use config::{Config, ConfigError};
+use context::BotContext;
 use flexi_logger::Logger;
+use futures::task::SpawnExt;
 use lazy_static::lazy_static;
-use sea_orm::ConnectionTrait;
-use statics::*;
+use sea_schema::migration::MigratorTrait;
+use std::sync::Arc;
+use tg::client::TgClient;
 
 lazy_static! {
     pub static ref EXEC: TokioTp = {
@@ -19,37 +23,179 @@ lazy_static! {
 #[derive(Parser)]
 #[clap(author, version, long_about = None)]
 pub struct Args {
-    // Path to mtproto session file
+    // Path to the TOML config file, defaults to bobot.toml if present
     #[clap(short, long)]
-    pub session: PathBuf,
+    pub config: Option<PathBuf>,
+
+    // Apply pending core migrations before polling for updates. Without it
+    // the bot refuses to start while any are pending. Module migrations
+    // always run at startup
+    #[clap(long)]
+    pub migrate: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    // Manage the database schema instead of running the bot
+    Migrate {
+        // Print the SQL that would run without touching the database
+        #[clap(long, global = true)]
+        dry_run: bool,
+
+        #[clap(subcommand)]
+        action: MigrateAction,
+    },
+    // Inspect the live database schema
+    Schema {
+        #[clap(subcommand)]
+        action: SchemaAction,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum MigrateAction {
+    // Apply all pending core and module migrations
+    Up,
+    // Roll back migrations of the core schema or a single module
+    Down {
+        #[clap(short, long, default_value = "1")]
+        num: u32,
+
+        #[clap(short, long)]
+        module: Option<String>,
+    },
+    // Show applied and pending migrations
+    Status,
+    // Drop every table and migrate from scratch
+    Fresh,
+}
+
+#[derive(Subcommand)]
+pub enum SchemaAction {
+    // Compare every entity against the live tables. Exits with 1 when they
+    // differ and 2 when the check could not run
+    Check {
+        // Postgres schema holding the bot's tables
+        #[clap(long, default_value = "public")]
+        schema: String,
+    },
 }
 
 pub fn get_executor() -> TokioTp {
     EXEC.clone()
 }
 
-pub fn init_db() {
-    println!(
-        "db initialized, mock: {}",
-        &statics::DB.is_mock_connection()
-    );
+async fn run_migrate<M: MigratorTrait>(
+    config: &Config,
+    registry: &tg::module::Registry,
+    action: MigrateAction,
+    dry_run: bool,
+) -> persist::Result<()> {
+    let db = persist::schema::connect(&config.database.url).await?;
+    match action {
+        MigrateAction::Up => persist::schema::up::<M>(&db, registry, dry_run).await,
+        MigrateAction::Down { num, module } => {
+            persist::schema::down::<M>(&db, registry, module.as_deref(), num, dry_run).await
+        }
+        MigrateAction::Status => persist::schema::status::<M>(&db, registry).await,
+        MigrateAction::Fresh => persist::schema::fresh::<M>(&db, registry, dry_run).await,
+    }
 }
 
-pub async fn async_main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+async fn check_schema(config: &Config, registry: &tg::module::Registry, schema: &str) -> i32 {
+    match persist::drift::check(&config.database.url, schema, registry).await {
+        Ok(diffs) if diffs.is_empty() => {
+            println!("schema matches entities");
+            0
+        }
+        Ok(diffs) => {
+            for diff in diffs {
+                println!("{}", diff);
+            }
+            1
+        }
+        Err(err) => {
+            eprintln!("schema check failed: {}", err);
+            2
+        }
+    }
+}
+
+// Run the bot, or a migrate or schema subcommand. Core migrations live in the
+// migration crate, which depends on this one, so the binary passes them in
+pub async fn async_main<M: MigratorTrait>() -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+{
     let _logger = Logger::try_with_env_or_str("info, my::info::module=trace")?
         .log_to_stderr()
         .write_mode(flexi_logger::WriteMode::Async)
         .start()?;
 
-    TG.run().await?;
+    let args = Args::parse();
+    let config = Config::load(args.config.as_ref())?;
+    let registry = Arc::new(modules::registry());
+    let errors = registry.validate_config(&config);
+    if !errors.is_empty() {
+        return Err(ConfigError(errors).into());
+    }
+    match args.command {
+        Some(Command::Migrate { dry_run, action }) => {
+            run_migrate::<M>(&config, &registry, action, dry_run).await?;
+            log::logger().flush();
+            return Ok(());
+        }
+        Some(Command::Schema {
+            action: SchemaAction::Check { schema },
+        }) => {
+            let code = check_schema(&config, &registry, &schema).await;
+            log::logger().flush();
+            std::process::exit(code);
+        }
+        None => (),
+    }
+    let client = TgClient::connect(config.telegram.token.clone());
+    let ctx = BotContext::connect(config, Arc::new(client.clone())).await?;
+    if args.migrate {
+        M::up(&ctx.db, None).await?;
+    } else {
+        // module tables may build on core ones, so don't migrate them or run
+        // against a schema that's behind
+        let pending = persist::schema::pending_core_count::<M>(&ctx.db).await?;
+        if pending > 0 {
+            return Err(format!(
+                "{} core migrations pending, run with --migrate to apply them",
+                pending
+            )
+            .into());
+        }
+    }
+    {
+        // a dedicated connection so module migrations can hold a transaction,
+        // closed again before polling starts
+        let migrate_db = persist::schema::connect(&ctx.config.database.url).await?;
+        persist::schema::migrate_modules(&migrate_db, &registry, false).await?;
+    }
+    registry.init(&ctx).await?;
+    let mut caches = registry.write_caches();
+    caches.push(&tg::dialog::DIALOG_WRITES);
+    EXEC.spawn(persist::writecache::flusher(ctx.clone(), caches.clone()))?;
+    tokio::select! {
+        res = client.run(ctx.clone(), registry.clone()) => res?,
+        _ = tokio::signal::ctrl_c() => log::info!("shutting down"),
+    }
+    registry.shutdown(&ctx).await;
+    // anything still queued would otherwise wait for the next start
+    persist::writecache::flush_all(&ctx, &caches).await;
     log::logger().flush();
     Ok(())
 }
 
+pub mod config;
+pub mod context;
 pub mod persist;
 pub mod tg;
 pub(crate) mod util;
 
 pub mod modules;
-
-pub(crate) mod statics;
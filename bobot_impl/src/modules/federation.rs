@@ -0,0 +1,1010 @@
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::context::BotContext;
+use crate::persist::drift::entity_table;
+use crate::persist::Result;
+use crate::tg::admin::{require_admin, target_user};
+use crate::tg::api::TgApi;
+use crate::tg::command::{parse_cmd, rest_args, Arg};
+use crate::tg::module::{BotModule, ModuleCommand, Propagation};
+use crate::util::error::BotError;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use log::info;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::{OnConflict, Query, TableCreateStatement};
+use sea_orm::{ActiveModelTrait, ConnectionTrait, IntoActiveModel, PaginatorTrait, Set};
+use sea_schema::migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
+
+use teloxide::types::{Message, Update, UpdateKind};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn csv_roundtrip() {
+        let bans = vec![
+            BanRecord {
+                user_id: 1234,
+                reason: Some(r#"spam, "crypto" links"#.to_owned()),
+                banned_by: 5678,
+                banned_at: Utc::now(),
+            },
+            BanRecord {
+                user_id: 4321,
+                reason: None,
+                banned_by: 5678,
+                banned_at: Utc::now(),
+            },
+        ];
+        let csv = bans_to_csv(&bans);
+        let parsed = bans_from_csv(&csv).unwrap();
+        assert!(parsed.len() == 2);
+        assert!(parsed[0].user_id == 1234);
+        assert!(parsed[0].reason == bans[0].reason);
+        assert!(parsed[1].reason.is_none());
+    }
+}
+
+const CSV_HEADER: &str = "user_id,reason,banned_by,banned_at";
+
+// rows per statement when importing bans, well under postgres' parameter limit
+const IMPORT_BATCH: usize = 1000;
+
+struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220501_000001_create_federations"
+    }
+}
+
+pub mod entities {
+    use crate::persist::migrate::ManagerHelper;
+    use sea_schema::migration::prelude::*;
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::Migration {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(federations::Entity)
+                        .col(
+                            ColumnDef::new(federations::Column::FedId)
+                                .uuid()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(federations::Column::OwnerId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(federations::Column::Name).text().not_null())
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_table(
+                    Table::create()
+                        .table(fed_chats::Entity)
+                        .col(
+                            ColumnDef::new(fed_chats::Column::ChatId)
+                                .big_integer()
+                                .primary_key(),
+                        )
+                        .col(ColumnDef::new(fed_chats::Column::FedId).uuid().not_null())
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_table(
+                    Table::create()
+                        .table(fed_admins::Entity)
+                        .col(ColumnDef::new(fed_admins::Column::FedId).uuid().not_null())
+                        .col(
+                            ColumnDef::new(fed_admins::Column::UserId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(fed_admins::Column::FedId)
+                                .col(fed_admins::Column::UserId),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_table(
+                    Table::create()
+                        .table(fed_bans::Entity)
+                        .col(ColumnDef::new(fed_bans::Column::FedId).uuid().not_null())
+                        .col(
+                            ColumnDef::new(fed_bans::Column::UserId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(fed_bans::Column::Reason).text())
+                        .col(
+                            ColumnDef::new(fed_bans::Column::BannedBy)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(fed_bans::Column::BannedAt)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(fed_bans::Column::FedId)
+                                .col(fed_bans::Column::UserId),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_foreign_key(
+                    ForeignKey::create()
+                        .from(fed_chats::Entity, fed_chats::Column::FedId)
+                        .to(federations::Entity, federations::Column::FedId)
+                        .on_delete(ForeignKeyAction::Cascade)
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_foreign_key(
+                    ForeignKey::create()
+                        .from(fed_admins::Entity, fed_admins::Column::FedId)
+                        .to(federations::Entity, federations::Column::FedId)
+                        .on_delete(ForeignKeyAction::Cascade)
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_foreign_key(
+                    ForeignKey::create()
+                        .from(fed_bans::Entity, fed_bans::Column::FedId)
+                        .to(federations::Entity, federations::Column::FedId)
+                        .on_delete(ForeignKeyAction::Cascade)
+                        .to_owned(),
+                )
+                .await?;
+
+            Ok(())
+        }
+
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager.drop_table_auto(fed_bans::Entity).await?;
+            manager.drop_table_auto(fed_admins::Entity).await?;
+            manager.drop_table_auto(fed_chats::Entity).await?;
+            manager.drop_table_auto(federations::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod federations {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "federations")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub fed_id: Uuid,
+            pub owner_id: i64,
+            #[sea_orm(column_type = "Text")]
+            pub name: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(has_many = "super::fed_chats::Entity")]
+            FedChats,
+            #[sea_orm(has_many = "super::fed_admins::Entity")]
+            FedAdmins,
+            #[sea_orm(has_many = "super::fed_bans::Entity")]
+            FedBans,
+        }
+
+        impl Related<super::fed_chats::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::FedChats.def()
+            }
+        }
+
+        impl Related<super::fed_admins::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::FedAdmins.def()
+            }
+        }
+
+        impl Related<super::fed_bans::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::FedBans.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod fed_chats {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "fed_chats")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub chat_id: i64,
+            pub fed_id: Uuid,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(
+                belongs_to = "super::federations::Entity",
+                from = "Column::FedId",
+                to = "super::federations::Column::FedId",
+                on_update = "NoAction",
+                on_delete = "Cascade"
+            )]
+            Federations,
+        }
+
+        impl Related<super::federations::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::Federations.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod fed_admins {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "fed_admins")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub fed_id: Uuid,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub user_id: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(
+                belongs_to = "super::federations::Entity",
+                from = "Column::FedId",
+                to = "super::federations::Column::FedId",
+                on_update = "NoAction",
+                on_delete = "Cascade"
+            )]
+            Federations,
+        }
+
+        impl Related<super::federations::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::Federations.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod fed_bans {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "fed_bans")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub fed_id: Uuid,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub user_id: i64,
+            #[sea_orm(column_type = "Text", nullable)]
+            pub reason: Option<String>,
+            pub banned_by: i64,
+            pub banned_at: DateTimeWithTimeZone,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(
+                belongs_to = "super::federations::Entity",
+                from = "Column::FedId",
+                to = "super::federations::Column::FedId",
+                on_update = "NoAction",
+                on_delete = "Cascade"
+            )]
+            Federations,
+        }
+
+        impl Related<super::federations::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::Federations.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+const COMMANDS: &[ModuleCommand] = &[
+    ModuleCommand::new("/newfed", "Create a federation"),
+    ModuleCommand::new("/joinfed", "Add this chat to a federation"),
+    ModuleCommand::new("/leavefed", "Remove this chat from its federation"),
+    ModuleCommand::new("/fedpromote", "Make a user a federation admin"),
+    ModuleCommand::new("/feddemote", "Remove a federation admin"),
+    ModuleCommand::new("/fban", "Ban a user in every federated chat"),
+    ModuleCommand::new("/unfban", "Lift a federation ban"),
+    ModuleCommand::new("/fedinfo", "Show federation details"),
+    ModuleCommand::new("/exportfbans", "Export federation bans as csv"),
+    ModuleCommand::new("/importfbans", "Import federation bans from csv"),
+];
+
+pub struct Module;
+
+#[async_trait]
+impl BotModule for Module {
+    fn name(&self) -> &'static str {
+        "federation"
+    }
+
+    fn description(&self) -> &'static str {
+        "Federations of chats sharing ban lists"
+    }
+
+    fn commands(&self) -> &'static [ModuleCommand] {
+        COMMANDS
+    }
+
+    fn migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        vec![Box::new(Migration)]
+    }
+
+    fn entities(&self) -> Vec<TableCreateStatement> {
+        vec![
+            entity_table(entities::federations::Entity),
+            entity_table(entities::fed_chats::Entity),
+            entity_table(entities::fed_admins::Entity),
+            entity_table(entities::fed_bans::Entity),
+        ]
+    }
+
+    // fbans are enforced even when the message is a disabled command
+    async fn handle_hooks(&self, ctx: &BotContext, update: &Update) -> Propagation {
+        if let UpdateKind::Message(ref message) = update.kind {
+            if let Err(err) = enforce_message(ctx, message).await {
+                log::error!("failed to enforce fbans: {}", err);
+            }
+        }
+        Propagation::Continue
+    }
+
+    async fn handle_update(&self, ctx: &BotContext, update: &Update) -> Propagation {
+        handle_update(ctx, update).await;
+        Propagation::Continue
+    }
+}
+
+// portable representation of a federation ban used for import/export
+#[derive(Serialize, Deserialize)]
+struct BanRecord {
+    user_id: i64,
+    reason: Option<String>,
+    banned_by: i64,
+    banned_at: DateTime<Utc>,
+}
+
+impl From<entities::fed_bans::Model> for BanRecord {
+    fn from(ban: entities::fed_bans::Model) -> Self {
+        BanRecord {
+            user_id: ban.user_id,
+            reason: ban.reason,
+            banned_by: ban.banned_by,
+            banned_at: ban.banned_at.with_timezone(&Utc),
+        }
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn bans_to_csv(bans: &[BanRecord]) -> String {
+    bans.iter().fold(String::from(CSV_HEADER), |mut s, ban| {
+        s.push('\n');
+        s.push_str(
+            format!(
+                "{},{},{},{}",
+                ban.user_id,
+                csv_field(ban.reason.as_deref().unwrap_or("")),
+                ban.banned_by,
+                ban.banned_at.to_rfc3339()
+            )
+            .as_str(),
+        );
+        s
+    })
+}
+
+// minimal rfc4180 reader, quoted fields may contain commas, quotes and newlines
+fn parse_csv(text: &str) -> Vec<Vec<String>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') => quoted = true,
+            (false, ',') => row.push(std::mem::take(&mut field)),
+            (false, '\r') => (),
+            (false, '\n') => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            (false, c) => field.push(c),
+        }
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    rows
+}
+
+fn bans_from_csv(text: &str) -> Result<Vec<BanRecord>> {
+    parse_csv(text)
+        .into_iter()
+        .filter(|row| !row.iter().all(|f| f.is_empty()))
+        .skip_while(|row| row.first().map(|f| f.as_str()) == Some("user_id"))
+        .map(|row| {
+            if let [user_id, reason, banned_by, banned_at] = row.as_slice() {
+                Ok(BanRecord {
+                    user_id: user_id.trim().parse()?,
+                    reason: if reason.is_empty() {
+                        None
+                    } else {
+                        Some(reason.to_owned())
+                    },
+                    banned_by: banned_by.trim().parse()?,
+                    banned_at: DateTime::parse_from_rfc3339(banned_at.trim())?.with_timezone(&Utc),
+                })
+            } else {
+                Err(anyhow!(BotError::new("invalid csv row")))
+            }
+        })
+        .collect()
+}
+
+fn sender_id(message: &Message) -> Result<i64> {
+    Ok(message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id)
+}
+
+async fn get_chat_fed(
+    ctx: &BotContext,
+    chat_id: i64,
+) -> Result<Option<entities::federations::Model>> {
+    let fed = entities::fed_chats::Entity::find_by_id(chat_id)
+        .find_also_related(entities::federations::Entity)
+        .one(&*ctx.db)
+        .await?
+        .map(|(_, fed)| fed)
+        .flatten();
+    Ok(fed)
+}
+
+async fn require_chat_fed(
+    ctx: &BotContext,
+    message: &Message,
+) -> Result<entities::federations::Model> {
+    get_chat_fed(ctx, message.chat.id)
+        .await?
+        .ok_or_else(|| anyhow!(BotError::new("This chat is not part of a federation")))
+}
+
+async fn is_fed_admin(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+    user: i64,
+) -> Result<bool> {
+    if fed.owner_id == user {
+        Ok(true)
+    } else {
+        let admin = entities::fed_admins::Entity::find_by_id((fed.fed_id, user))
+            .one(&*ctx.db)
+            .await?;
+        Ok(admin.is_some())
+    }
+}
+
+async fn require_fed_admin(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+    message: &Message,
+) -> Result<()> {
+    if is_fed_admin(ctx, fed, sender_id(message)?).await? {
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("You are not a federation admin")))
+    }
+}
+
+async fn get_fed_chats(ctx: &BotContext, fed: &entities::federations::Model) -> Result<Vec<i64>> {
+    let chats = entities::fed_chats::Entity::find()
+        .filter(entities::fed_chats::Column::FedId.eq(fed.fed_id))
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .map(|c| c.chat_id)
+        .collect();
+    Ok(chats)
+}
+
+async fn reply(ctx: &BotContext, message: &Message, text: impl Into<String>) -> Result<()> {
+    ctx.tg.reply(message, text).await?;
+    Ok(())
+}
+
+// ban a user in every chat of a federation, skipping chats where we lack permissions
+async fn enforce_fban(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+    user: i64,
+) -> Result<()> {
+    enforce_fbans(ctx, fed, &[user]).await
+}
+
+async fn enforce_fbans(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+    users: &[i64],
+) -> Result<()> {
+    let chats = get_fed_chats(ctx, fed).await?;
+    for user in users {
+        for chat in &chats {
+            if let Err(err) = ctx.tg.ban_chat_member(*chat, *user).await {
+                log::warn!("failed to fban {} in {}: {}", user, chat, err);
+            }
+        }
+    }
+    Ok(())
+}
+
+// the owner and admins of a federation
+async fn fed_admin_ids(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+) -> Result<HashSet<i64>> {
+    let mut admins: HashSet<i64> = entities::fed_admins::Entity::find()
+        .filter(entities::fed_admins::Column::FedId.eq(fed.fed_id))
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .map(|admin| admin.user_id)
+        .collect();
+    admins.insert(fed.owner_id);
+    Ok(admins)
+}
+
+// Insert or overwrite bans in one transaction, a batch of rows per statement.
+// Each user must appear only once
+async fn upsert_bans(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+    bans: &[BanRecord],
+) -> Result<()> {
+    use entities::fed_bans::Column;
+    let txn = ctx.db.begin().await?;
+    for batch in bans.chunks(IMPORT_BATCH) {
+        let mut insert = Query::insert();
+        insert.into_table(entities::fed_bans::Entity).columns([
+            Column::FedId,
+            Column::UserId,
+            Column::Reason,
+            Column::BannedBy,
+            Column::BannedAt,
+        ]);
+        for ban in batch {
+            let banned_at: DateTimeWithTimeZone = ban.banned_at.into();
+            insert.values_panic([
+                fed.fed_id.into(),
+                ban.user_id.into(),
+                ban.reason.clone().into(),
+                ban.banned_by.into(),
+                banned_at.into(),
+            ]);
+        }
+        insert.on_conflict(
+            OnConflict::columns([Column::FedId, Column::UserId])
+                .update_columns([Column::Reason, Column::BannedBy, Column::BannedAt])
+                .to_owned(),
+        );
+        txn.execute(txn.get_database_backend().build(&insert))
+            .await?;
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+async fn upsert_ban(
+    ctx: &BotContext,
+    fed: &entities::federations::Model,
+    ban: BanRecord,
+) -> Result<()> {
+    let existing = entities::fed_bans::Entity::find_by_id((fed.fed_id, ban.user_id))
+        .one(&*ctx.db)
+        .await?;
+    if let Some(existing) = existing {
+        let mut existing = existing.into_active_model();
+        existing.reason = Set(ban.reason);
+        existing.banned_by = Set(ban.banned_by);
+        existing.banned_at = Set(ban.banned_at.into());
+        existing.update(&*ctx.db).await?;
+    } else {
+        let ban = entities::fed_bans::ActiveModel {
+            fed_id: Set(fed.fed_id),
+            user_id: Set(ban.user_id),
+            reason: Set(ban.reason),
+            banned_by: Set(ban.banned_by),
+            banned_at: Set(ban.banned_at.into()),
+        };
+        ban.insert(&*ctx.db).await?;
+    }
+    Ok(())
+}
+
+async fn new_fed(ctx: &BotContext, message: &Message) -> Result<()> {
+    let name = rest_args(message.text().unwrap_or_default(), 1);
+    if name.is_empty() {
+        return Err(anyhow!(BotError::new("Usage: /newfed <name>")));
+    }
+    let fed = entities::federations::ActiveModel {
+        fed_id: Set(Uuid::new_v4()),
+        owner_id: Set(sender_id(message)?),
+        name: Set(name),
+    }
+    .insert(&*ctx.db)
+    .await?;
+    reply(
+        ctx,
+        message,
+        format!(
+            "Created federation {}\nUse /joinfed {} in a chat to add it",
+            fed.name, fed.fed_id
+        ),
+    )
+    .await
+}
+
+async fn join_fed(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    require_admin(ctx, message).await?;
+    if let Some(Arg::Arg(fed_id)) = args.get(1) {
+        let fed_id = Uuid::from_str(fed_id)?;
+        let fed = entities::federations::Entity::find_by_id(fed_id)
+            .one(&*ctx.db)
+            .await?
+            .ok_or_else(|| BotError::new("Federation does not exist"))?;
+        entities::fed_chats::Entity::delete_many()
+            .filter(entities::fed_chats::Column::ChatId.eq(message.chat.id))
+            .exec(&*ctx.db)
+            .await?;
+        entities::fed_chats::ActiveModel {
+            chat_id: Set(message.chat.id),
+            fed_id: Set(fed.fed_id),
+        }
+        .insert(&*ctx.db)
+        .await?;
+        reply(
+            ctx,
+            message,
+            format!("This chat joined federation {}", fed.name),
+        )
+        .await
+    } else {
+        Err(anyhow!(BotError::new("Usage: /joinfed <federation id>")))
+    }
+}
+
+async fn leave_fed(ctx: &BotContext, message: &Message) -> Result<()> {
+    require_admin(ctx, message).await?;
+    let fed = require_chat_fed(ctx, message).await?;
+    entities::fed_chats::Entity::delete_many()
+        .filter(entities::fed_chats::Column::ChatId.eq(message.chat.id))
+        .exec(&*ctx.db)
+        .await?;
+    reply(
+        ctx,
+        message,
+        format!("This chat left federation {}", fed.name),
+    )
+    .await
+}
+
+async fn fed_promote(
+    ctx: &BotContext,
+    message: &Message,
+    args: &[Arg],
+    promote: bool,
+) -> Result<()> {
+    let fed = require_chat_fed(ctx, message).await?;
+    if fed.owner_id != sender_id(message)? {
+        return Err(anyhow!(BotError::new(
+            "Only the federation owner can change admins"
+        )));
+    }
+    let user = target_user(message, args).ok_or_else(|| BotError::new("No user specified"))?;
+    if promote {
+        if !is_fed_admin(ctx, &fed, user).await? {
+            entities::fed_admins::ActiveModel {
+                fed_id: Set(fed.fed_id),
+                user_id: Set(user),
+            }
+            .insert(&*ctx.db)
+            .await?;
+        }
+        reply(ctx, message, format!("{} is now a federation admin", user)).await
+    } else {
+        entities::fed_admins::Entity::delete_many()
+            .filter(entities::fed_admins::Column::FedId.eq(fed.fed_id))
+            .filter(entities::fed_admins::Column::UserId.eq(user))
+            .exec(&*ctx.db)
+            .await?;
+        reply(
+            ctx,
+            message,
+            format!("{} is no longer a federation admin", user),
+        )
+        .await
+    }
+}
+
+async fn fban(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    let fed = require_chat_fed(ctx, message).await?;
+    require_fed_admin(ctx, &fed, message).await?;
+    let user = target_user(message, args).ok_or_else(|| BotError::new("No user specified"))?;
+    if is_fed_admin(ctx, &fed, user).await? {
+        return Err(anyhow!(BotError::new("Federation admins can't be fbanned")));
+    }
+    // the user id is only in the args when not replying
+    let skip = if message.reply_to_message().is_some() {
+        1
+    } else {
+        2
+    };
+    let reason = rest_args(message.text().unwrap_or_default(), skip);
+    let ban = BanRecord {
+        user_id: user,
+        reason: if reason.is_empty() {
+            None
+        } else {
+            Some(reason)
+        },
+        banned_by: sender_id(message)?,
+        banned_at: Utc::now(),
+    };
+    upsert_ban(ctx, &fed, ban).await?;
+    enforce_fban(ctx, &fed, user).await?;
+    reply(
+        ctx,
+        message,
+        format!("Banned {} in federation {}", user, fed.name),
+    )
+    .await
+}
+
+async fn unfban(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    let fed = require_chat_fed(ctx, message).await?;
+    require_fed_admin(ctx, &fed, message).await?;
+    let user = target_user(message, args).ok_or_else(|| BotError::new("No user specified"))?;
+    entities::fed_bans::Entity::delete_many()
+        .filter(entities::fed_bans::Column::FedId.eq(fed.fed_id))
+        .filter(entities::fed_bans::Column::UserId.eq(user))
+        .exec(&*ctx.db)
+        .await?;
+    for chat in get_fed_chats(ctx, &fed).await? {
+        if let Err(err) = ctx.tg.unban_chat_member(chat, user).await {
+            log::warn!("failed to unfban {} in {}: {}", user, chat, err);
+        }
+    }
+    reply(
+        ctx,
+        message,
+        format!("Unbanned {} in federation {}", user, fed.name),
+    )
+    .await
+}
+
+async fn fed_info(ctx: &BotContext, message: &Message) -> Result<()> {
+    let fed = require_chat_fed(ctx, message).await?;
+    let chats = get_fed_chats(ctx, &fed).await?.len();
+    let bans = entities::fed_bans::Entity::find()
+        .filter(entities::fed_bans::Column::FedId.eq(fed.fed_id))
+        .count(&*ctx.db)
+        .await?;
+    let admins = entities::fed_admins::Entity::find()
+        .filter(entities::fed_admins::Column::FedId.eq(fed.fed_id))
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .fold(String::new(), |mut s, admin| {
+            s.push_str(format!("\n - {}", admin.user_id).as_str());
+            s
+        });
+    reply(
+        ctx,
+        message,
+        format!(
+            "Federation {}\nId: {}\nOwner: {}\nChats: {}\nBans: {}\nAdmins:{}",
+            fed.name, fed.fed_id, fed.owner_id, chats, bans, admins
+        ),
+    )
+    .await
+}
+
+async fn export_fbans(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    let fed = require_chat_fed(ctx, message).await?;
+    require_fed_admin(ctx, &fed, message).await?;
+    let bans: Vec<BanRecord> = entities::fed_bans::Entity::find()
+        .filter(entities::fed_bans::Column::FedId.eq(fed.fed_id))
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .map(|b| b.into())
+        .collect();
+    let (bytes, name) = match args.get(1) {
+        Some(Arg::Arg(format)) if format == "csv" => (bans_to_csv(&bans).into_bytes(), "fbans.csv"),
+        _ => (serde_json::to_vec_pretty(&bans)?, "fbans.json"),
+    };
+    ctx.tg
+        .send_document(message.chat.id, name.to_owned(), bytes, None)
+        .await?;
+    Ok(())
+}
+
+async fn import_fbans(ctx: &BotContext, message: &Message) -> Result<()> {
+    let fed = require_chat_fed(ctx, message).await?;
+    require_fed_admin(ctx, &fed, message).await?;
+    let document = message
+        .reply_to_message()
+        .and_then(|m| m.document())
+        .ok_or_else(|| BotError::new("Reply to a json or csv ban list"))?;
+    let bytes = ctx.tg.download_file(&document.file_id).await?;
+    let text = String::from_utf8(bytes)?;
+    let is_csv = document
+        .file_name
+        .as_ref()
+        .map(|n| n.ends_with(".csv"))
+        .unwrap_or_else(|| !text.trim_start().starts_with('['));
+    let bans = if is_csv {
+        bans_from_csv(&text)?
+    } else {
+        serde_json::from_str::<Vec<BanRecord>>(&text)?
+    };
+    // a user listed twice keeps their last entry
+    let bans: HashMap<i64, BanRecord> = bans.into_iter().map(|ban| (ban.user_id, ban)).collect();
+    // federation admins can't be fbanned, not even by an import
+    let admins = fed_admin_ids(ctx, &fed).await?;
+    let (skipped, bans): (Vec<BanRecord>, Vec<BanRecord>) = bans
+        .into_values()
+        .partition(|ban| admins.contains(&ban.user_id));
+    upsert_bans(ctx, &fed, &bans).await?;
+
+    let mut text = format!("Imported {} bans into federation {}", bans.len(), fed.name);
+    if !skipped.is_empty() {
+        text.push_str(&format!(", skipped {} federation admins", skipped.len()));
+    }
+    reply(ctx, message, text).await?;
+
+    // banning everyone in every chat can take far longer than an update may
+    // run, so it carries on after the reply
+    let users: Vec<i64> = bans.iter().map(|ban| ban.user_id).collect();
+    let ctx = ctx.clone();
+    tokio::spawn(async move {
+        if let Err(err) = enforce_fbans(&ctx, &fed, &users).await {
+            log::error!("failed to enforce imported fbans in {}: {}", fed.name, err);
+        }
+    });
+    Ok(())
+}
+
+// ban any fbanned user who speaks or joins in a federated chat
+async fn enforce_message(ctx: &BotContext, message: &Message) -> Result<()> {
+    if let Some(fed) = get_chat_fed(ctx, message.chat.id).await? {
+        let mut users: Vec<i64> = message
+            .new_chat_members()
+            .map(|m| m.iter().map(|u| u.id).collect())
+            .unwrap_or_else(Vec::new);
+        if let Some(user) = message.from() {
+            users.push(user.id);
+        }
+        for user in users {
+            let ban = entities::fed_bans::Entity::find_by_id((fed.fed_id, user))
+                .one(&*ctx.db)
+                .await?;
+            if ban.is_some() {
+                info!("enforcing fban for {} in {}", user, message.chat.id);
+                ctx.tg.ban_chat_member(message.chat.id, user).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn handle_command(ctx: &BotContext, message: &Message) -> Result<()> {
+    if let Some(text) = message.text() {
+        let command = parse_cmd(text)?;
+        if let Some(Arg::Arg(cmd)) = command.first() {
+            match cmd.as_str() {
+                "/newfed" => new_fed(ctx, message).await,
+                "/joinfed" => join_fed(ctx, message, &command).await,
+                "/leavefed" => leave_fed(ctx, message).await,
+                "/fedpromote" => fed_promote(ctx, message, &command, true).await,
+                "/feddemote" => fed_promote(ctx, message, &command, false).await,
+                "/fban" => fban(ctx, message, &command).await,
+                "/unfban" => unfban(ctx, message, &command).await,
+                "/fedinfo" => fed_info(ctx, message).await,
+                "/exportfbans" => export_fbans(ctx, message, &command).await,
+                "/importfbans" => import_fbans(ctx, message).await,
+                _ => Ok(()),
+            }?;
+        }
+    };
+    Ok(())
+}
+
+async fn handle_message(ctx: &BotContext, message: &Message) -> Result<()> {
+    enforce_message(ctx, message).await?;
+    handle_command(ctx, message).await?;
+    Ok(())
+}
+
+async fn handle_update(ctx: &BotContext, update: &Update) {
+    let res = match update.kind {
+        UpdateKind::Message(ref message) => handle_message(ctx, message).await,
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        info!("error {}", err);
+        if let Some(chat) = update.chat() {
+            if let Err(send_err) = ctx.tg.send(chat.id, err.to_string()).await {
+                log::error!("failed to send error message: {}", send_err);
+            }
+        }
+    }
+}
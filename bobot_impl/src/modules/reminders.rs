@@ -0,0 +1,388 @@
+use std::str::FromStr;
+
+use crate::config::{Config, ModuleConfig};
+use crate::context::BotContext;
+use crate::persist::drift::entity_table;
+use crate::persist::Result;
+use crate::tg::module::{BotModule, ModuleCommand, Propagation};
+use crate::util::error::BotError;
+use crate::util::time::parse_when;
+use crate::EXEC;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::Utc;
+use futures::task::SpawnExt;
+use log::info;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::TableCreateStatement;
+use sea_orm::{ActiveModelTrait, PaginatorTrait, QueryOrder, Set};
+use sea_schema::migration::{MigrationName, MigrationTrait};
+use serde::Deserialize;
+
+use teloxide::types::{Message, Update, UpdateKind};
+
+// redis keys
+const KEY_REMINDER_LOCK: &str = "remindlock";
+const KEY_REMINDER_ATTEMPTS: &str = "remindtries";
+
+// how often the worker checks for due reminders
+const POLL_INTERVAL_SECS: u64 = 5;
+
+// a reminder is only deleted after it is sent, so if an instance dies or the
+// send fails it is sent again once the lock expires
+const LOCK_TTL_MS: usize = 5 * 60 * 1000;
+
+// a reminder that still can't be sent after this many tries, about half an
+// hour with the lock above (blocked bot, deleted chat), is dropped instead of
+// being retried forever
+const MAX_SEND_ATTEMPTS: i64 = 6;
+
+const MAX_REMINDERS: usize = 50;
+
+// [modules.reminders] in the config file
+#[derive(Deserialize)]
+#[serde(default)]
+struct ReminderConfig {
+    poll_interval_secs: u64,
+    max_reminders: usize,
+}
+
+impl Default for ReminderConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: POLL_INTERVAL_SECS,
+            max_reminders: MAX_REMINDERS,
+        }
+    }
+}
+
+impl ModuleConfig for ReminderConfig {
+    const NAME: &'static str = "reminders";
+}
+
+struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220503_000001_create_reminders"
+    }
+}
+
+pub mod entities {
+    use crate::persist::migrate::ManagerHelper;
+    use sea_schema::migration::prelude::*;
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::Migration {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(reminders::Entity)
+                        .col(
+                            ColumnDef::new(reminders::Column::ReminderId)
+                                .uuid()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(reminders::Column::ChatId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(reminders::Column::UserId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(reminders::Column::MessageId)
+                                .integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(reminders::Column::Text).text().not_null())
+                        .col(
+                            ColumnDef::new(reminders::Column::RemindAt)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_index(
+                    Index::create()
+                        .name("reminders_remind_at")
+                        .table(reminders::Entity)
+                        .col(reminders::Column::RemindAt)
+                        .to_owned(),
+                )
+                .await?;
+
+            Ok(())
+        }
+
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager.drop_table_auto(reminders::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod reminders {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "reminders")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub reminder_id: Uuid,
+            pub chat_id: i64,
+            pub user_id: i64,
+            pub message_id: i32,
+            #[sea_orm(column_type = "Text")]
+            pub text: String,
+            pub remind_at: DateTimeWithTimeZone,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+const COMMANDS: &[ModuleCommand] = &[
+    ModuleCommand::new("/remindme", "Set a reminder"),
+    ModuleCommand::new("/reminders", "List pending reminders"),
+    ModuleCommand::new("/cancelreminder", "Cancel a reminder"),
+];
+
+pub struct Module;
+
+#[async_trait]
+impl BotModule for Module {
+    fn name(&self) -> &'static str {
+        "reminders"
+    }
+
+    fn description(&self) -> &'static str {
+        "Personal reminders sent at a later time"
+    }
+
+    fn commands(&self) -> &'static [ModuleCommand] {
+        COMMANDS
+    }
+
+    fn migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        vec![Box::new(Migration)]
+    }
+
+    fn entities(&self) -> Vec<TableCreateStatement> {
+        vec![entity_table(entities::reminders::Entity)]
+    }
+
+    fn validate_config(&self, config: &Config) -> Vec<String> {
+        match config.module::<ReminderConfig>() {
+            Ok(reminders) if reminders.poll_interval_secs == 0 => {
+                vec!["modules.reminders.poll_interval_secs must be positive".to_owned()]
+            }
+            Ok(_) => Vec::new(),
+            Err(_) => config.module_errors::<ReminderConfig>(),
+        }
+    }
+
+    async fn init(&self, ctx: &BotContext) -> Result<()> {
+        let config: ReminderConfig = ctx.config.module()?;
+        EXEC.spawn(reminder_worker(ctx.clone(), config.poll_interval_secs))
+            .map_err(|err| BotError::new(format!("failed to start reminder worker: {}", err)))?;
+        Ok(())
+    }
+
+    async fn handle_update(&self, ctx: &BotContext, update: &Update) -> Propagation {
+        handle_update(ctx, update).await;
+        Propagation::Continue
+    }
+}
+
+async fn reminder_worker(ctx: BotContext, poll_interval_secs: u64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(poll_interval_secs));
+    loop {
+        interval.tick().await;
+        if let Err(err) = send_due_reminders(&ctx).await {
+            log::error!("reminder worker error: {}", err);
+        }
+    }
+}
+
+async fn send_due_reminders(ctx: &BotContext) -> Result<()> {
+    let now: DateTimeWithTimeZone = Utc::now().into();
+    let reminders = entities::reminders::Entity::find()
+        .filter(entities::reminders::Column::RemindAt.lte(now))
+        .all(&*ctx.db)
+        .await?;
+    for reminder in reminders {
+        let lock = format!("{}:{}", KEY_REMINDER_LOCK, reminder.reminder_id);
+        if ctx.redis.try_lock(&lock, LOCK_TTL_MS).await? {
+            info!("sending reminder {}", reminder.reminder_id);
+            let attempts = format!("{}:{}", KEY_REMINDER_ATTEMPTS, reminder.reminder_id);
+            if let Err(err) = ctx
+                .tg
+                .send_message(
+                    reminder.chat_id,
+                    format!("Reminder: {}", reminder.text),
+                    Some(reminder.message_id),
+                )
+                .await
+            {
+                let (tries,): (i64,) = ctx.redis.pipe(|p| p.incr(&attempts, 1)).await?;
+                if tries < MAX_SEND_ATTEMPTS {
+                    // keep the lock so the send is retried once it expires
+                    log::error!("failed to send reminder {}: {}", reminder.reminder_id, err);
+                    continue;
+                }
+                log::error!(
+                    "giving up on reminder {} after {} tries: {}",
+                    reminder.reminder_id,
+                    tries,
+                    err
+                );
+            }
+            entities::reminders::Entity::delete_many()
+                .filter(entities::reminders::Column::ReminderId.eq(reminder.reminder_id))
+                .exec(&*ctx.db)
+                .await?;
+            let _: () = ctx.redis.pipe(|p| p.del(attempts).ignore()).await?;
+        }
+    }
+    Ok(())
+}
+
+fn sender_id(message: &Message) -> Result<i64> {
+    Ok(message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id)
+}
+
+async fn remind_me(ctx: &BotContext, message: &Message, args: &[&str]) -> Result<()> {
+    let user = sender_id(message)?;
+    let (when, consumed) = parse_when(args)?;
+    if when <= Utc::now() {
+        return Err(anyhow!(BotError::new("That time is in the past")));
+    }
+    let text = args[consumed..].join(" ");
+    if text.is_empty() {
+        return Err(anyhow!(BotError::new("Usage: /remindme <when> <text>")));
+    }
+    let pending = entities::reminders::Entity::find()
+        .filter(entities::reminders::Column::UserId.eq(user))
+        .count(&*ctx.db)
+        .await?;
+    let config: ReminderConfig = ctx.config.module()?;
+    if pending >= config.max_reminders {
+        return Err(anyhow!(BotError::new(
+            "You have too many pending reminders"
+        )));
+    }
+    let reminder = entities::reminders::ActiveModel {
+        reminder_id: Set(Uuid::new_v4()),
+        chat_id: Set(message.chat.id),
+        user_id: Set(user),
+        message_id: Set(message.id),
+        text: Set(text),
+        remind_at: Set(when.into()),
+    }
+    .insert(&*ctx.db)
+    .await?;
+    ctx.tg
+        .reply(
+            message,
+            format!(
+                "I will remind you at {} UTC",
+                reminder.remind_at.format("%Y-%m-%d %H:%M")
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn list_reminders(ctx: &BotContext, message: &Message) -> Result<()> {
+    let reminders = entities::reminders::Entity::find()
+        .filter(entities::reminders::Column::UserId.eq(sender_id(message)?))
+        .order_by_asc(entities::reminders::Column::RemindAt)
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .fold(String::from("My reminders:"), |mut s, reminder| {
+            s.push_str(
+                format!(
+                    "\n - {} {}: {}",
+                    reminder.reminder_id,
+                    reminder.remind_at.format("%Y-%m-%d %H:%M"),
+                    reminder.text
+                )
+                .as_str(),
+            );
+            s
+        });
+    ctx.tg.reply(message, reminders).await?;
+    Ok(())
+}
+
+async fn cancel_reminder(ctx: &BotContext, message: &Message, args: &[&str]) -> Result<()> {
+    if let Some(uuid) = args.first() {
+        let uuid = Uuid::from_str(uuid)?;
+        let res = entities::reminders::Entity::delete_many()
+            .filter(entities::reminders::Column::ReminderId.eq(uuid))
+            .filter(entities::reminders::Column::UserId.eq(sender_id(message)?))
+            .exec(&*ctx.db)
+            .await?;
+        let text = if res.rows_affected > 0 {
+            "Cancelled reminder"
+        } else {
+            "No such reminder"
+        };
+        ctx.tg.reply(message, text).await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("Usage: /cancelreminder <id>")))
+    }
+}
+
+// reminder text is free form, so this splits on whitespace instead of using
+// parse_cmd to keep quotes intact
+async fn handle_command(ctx: &BotContext, message: &Message) -> Result<()> {
+    if let Some(text) = message.text() {
+        let words: Vec<&str> = text.split_whitespace().collect();
+        if let Some((cmd, args)) = words.split_first() {
+            match *cmd {
+                "/remindme" => remind_me(ctx, message, args).await,
+                "/reminders" => list_reminders(ctx, message).await,
+                "/cancelreminder" => cancel_reminder(ctx, message, args).await,
+                _ => Ok(()),
+            }?;
+        }
+    };
+    Ok(())
+}
+
+async fn handle_update(ctx: &BotContext, update: &Update) {
+    let res = match update.kind {
+        UpdateKind::Message(ref message) => handle_command(ctx, message).await,
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        info!("error {}", err);
+        if let Some(chat) = update.chat() {
+            if let Err(send_err) = ctx.tg.send(chat.id, err.to_string()).await {
+                log::error!("failed to send error message: {}", send_err);
+            }
+        }
+    }
+}
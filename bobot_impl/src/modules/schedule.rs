@@ -0,0 +1,446 @@
+use std::str::FromStr;
+
+use crate::config::{Config, ModuleConfig};
+use crate::context::BotContext;
+use crate::persist::drift::entity_table;
+use crate::persist::Result;
+use crate::tg::admin::require_admin;
+use crate::tg::command::{parse_cmd, rest_args, Arg};
+use crate::tg::module::{BotModule, ModuleCommand, Propagation};
+use crate::util::error::BotError;
+use crate::util::time::{parse_datetime, parse_duration};
+use crate::EXEC;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use futures::task::SpawnExt;
+use log::info;
+use sea_orm::entity::prelude::*;
+use sea_orm::sea_query::TableCreateStatement;
+use sea_orm::{ActiveModelTrait, IntoActiveModel, QueryOrder, Set};
+use sea_schema::migration::{MigrationName, MigrationTrait};
+use serde::Deserialize;
+
+use teloxide::types::{Message, Update, UpdateKind};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tg::mock::{message_update, mock_context};
+
+    #[tokio::test]
+    async fn schedule_requires_admin() {
+        let (ctx, tg) = mock_context().await;
+        handle_update(
+            &ctx,
+            &message_update(-100, 42, "/schedule 2099-01-01T00:00 hi"),
+        )
+        .await;
+        let messages = tg.messages(-100);
+        assert!(messages.len() == 1);
+        assert!(messages[0].ends_with("You must be a chat admin to do this"));
+    }
+
+    #[tokio::test]
+    async fn schedule_usage() {
+        let (ctx, tg) = mock_context().await;
+        tg.set_admin(-100, 42);
+        handle_update(&ctx, &message_update(-100, 42, "/schedule")).await;
+        let messages = tg.messages(-100);
+        assert!(messages.len() == 1);
+        assert!(messages[0].ends_with("Usage: /schedule <YYYY-MM-DDTHH:MM> <text>"));
+    }
+}
+
+// redis keys
+const KEY_SCHEDULE_LOCK: &str = "schedlock";
+
+// how often the scheduler checks for due jobs
+const POLL_INTERVAL_SECS: u64 = 10;
+
+// Jobs are locked per run, so the lock only needs to outlive clock skew
+// between bot instances. A run is only recorded after it is sent, so a
+// failed send is retried once the lock expires
+const LOCK_TTL_MS: usize = 5 * 60 * 1000;
+
+const MIN_INTERVAL_SECS: i64 = 60;
+
+// [modules.schedule] in the config file
+#[derive(Deserialize)]
+#[serde(default)]
+struct ScheduleConfig {
+    poll_interval_secs: u64,
+    min_interval_secs: i64,
+}
+
+impl Default for ScheduleConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: POLL_INTERVAL_SECS,
+            min_interval_secs: MIN_INTERVAL_SECS,
+        }
+    }
+}
+
+impl ModuleConfig for ScheduleConfig {
+    const NAME: &'static str = "schedule";
+}
+
+struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220502_000001_create_scheduled_jobs"
+    }
+}
+
+pub mod entities {
+    use crate::persist::migrate::ManagerHelper;
+    use sea_schema::migration::prelude::*;
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::Migration {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(scheduled_jobs::Entity)
+                        .col(
+                            ColumnDef::new(scheduled_jobs::Column::JobId)
+                                .uuid()
+                                .primary_key(),
+                        )
+                        .col(
+                            ColumnDef::new(scheduled_jobs::Column::ChatId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(scheduled_jobs::Column::CreatorId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(scheduled_jobs::Column::Text)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(scheduled_jobs::Column::NextRun)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(scheduled_jobs::Column::IntervalSecs).big_integer())
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_index(
+                    Index::create()
+                        .name("scheduled_jobs_next_run")
+                        .table(scheduled_jobs::Entity)
+                        .col(scheduled_jobs::Column::NextRun)
+                        .to_owned(),
+                )
+                .await?;
+
+            Ok(())
+        }
+
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager.drop_table_auto(scheduled_jobs::Entity).await?;
+            Ok(())
+        }
+    }
+
+    pub mod scheduled_jobs {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "scheduled_jobs")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub job_id: Uuid,
+            pub chat_id: i64,
+            pub creator_id: i64,
+            #[sea_orm(column_type = "Text")]
+            pub text: String,
+            pub next_run: DateTimeWithTimeZone,
+            pub interval_secs: Option<i64>,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+const COMMANDS: &[ModuleCommand] = &[
+    ModuleCommand::new("/schedule", "Send a message at a time"),
+    ModuleCommand::new("/every", "Send a message on an interval"),
+    ModuleCommand::new("/jobs", "List scheduled jobs"),
+    ModuleCommand::new("/canceljob", "Cancel a scheduled job"),
+];
+
+pub struct Module;
+
+#[async_trait]
+impl BotModule for Module {
+    fn name(&self) -> &'static str {
+        "schedule"
+    }
+
+    fn description(&self) -> &'static str {
+        "Scheduled and recurring chat messages"
+    }
+
+    fn commands(&self) -> &'static [ModuleCommand] {
+        COMMANDS
+    }
+
+    fn migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        vec![Box::new(Migration)]
+    }
+
+    fn entities(&self) -> Vec<TableCreateStatement> {
+        vec![entity_table(entities::scheduled_jobs::Entity)]
+    }
+
+    fn validate_config(&self, config: &Config) -> Vec<String> {
+        match config.module::<ScheduleConfig>() {
+            Ok(schedule) if schedule.poll_interval_secs == 0 => {
+                vec!["modules.schedule.poll_interval_secs must be positive".to_owned()]
+            }
+            Ok(_) => Vec::new(),
+            Err(_) => config.module_errors::<ScheduleConfig>(),
+        }
+    }
+
+    async fn init(&self, ctx: &BotContext) -> Result<()> {
+        let config: ScheduleConfig = ctx.config.module()?;
+        EXEC.spawn(scheduler(ctx.clone(), config.poll_interval_secs))
+            .map_err(|err| BotError::new(format!("failed to start scheduler: {}", err)))?;
+        Ok(())
+    }
+
+    async fn handle_update(&self, ctx: &BotContext, update: &Update) -> Propagation {
+        handle_update(ctx, update).await;
+        Propagation::Continue
+    }
+}
+
+async fn scheduler(ctx: BotContext, poll_interval_secs: u64) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(poll_interval_secs));
+    loop {
+        interval.tick().await;
+        if let Err(err) = run_due_jobs(&ctx).await {
+            log::error!("scheduler error: {}", err);
+        }
+    }
+}
+
+async fn run_due_jobs(ctx: &BotContext) -> Result<()> {
+    let now: DateTimeWithTimeZone = Utc::now().into();
+    let jobs = entities::scheduled_jobs::Entity::find()
+        .filter(entities::scheduled_jobs::Column::NextRun.lte(now))
+        .all(&*ctx.db)
+        .await?;
+    for job in jobs {
+        // other bot instances see the same due jobs, only one of them gets
+        // to fire each run
+        let lock = format!(
+            "{}:{}:{}",
+            KEY_SCHEDULE_LOCK,
+            job.job_id,
+            job.next_run.timestamp()
+        );
+        if ctx.redis.try_lock(&lock, LOCK_TTL_MS).await? {
+            if let Err(err) = fire_job(ctx, job).await {
+                log::error!("failed to fire scheduled job: {}", err);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn fire_job(ctx: &BotContext, job: entities::scheduled_jobs::Model) -> Result<()> {
+    info!("firing scheduled job {}", job.job_id);
+    ctx.tg.send(job.chat_id, job.text.clone()).await?;
+    if let Some(interval) = job.interval_secs {
+        // runs missed while the bot was down are skipped rather than replayed
+        let now = Utc::now();
+        let mut next = job.next_run.with_timezone(&Utc);
+        while next <= now {
+            next = next + Duration::seconds(interval);
+        }
+        let mut job = job.into_active_model();
+        job.next_run = Set(next.into());
+        job.update(&*ctx.db).await?;
+    } else {
+        entities::scheduled_jobs::Entity::delete_many()
+            .filter(entities::scheduled_jobs::Column::JobId.eq(job.job_id))
+            .exec(&*ctx.db)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn insert_job(
+    ctx: &BotContext,
+    message: &Message,
+    text: String,
+    next_run: chrono::DateTime<Utc>,
+    interval: Option<Duration>,
+) -> Result<entities::scheduled_jobs::Model> {
+    if text.is_empty() {
+        return Err(anyhow!(BotError::new("Nothing to send")));
+    }
+    let creator = message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id;
+    let job = entities::scheduled_jobs::ActiveModel {
+        job_id: Set(Uuid::new_v4()),
+        chat_id: Set(message.chat.id),
+        creator_id: Set(creator),
+        text: Set(text),
+        next_run: Set(next_run.into()),
+        interval_secs: Set(interval.map(|i| i.num_seconds())),
+    }
+    .insert(&*ctx.db)
+    .await?;
+    Ok(job)
+}
+
+async fn schedule(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    require_admin(ctx, message).await?;
+    if let Some(Arg::Arg(time)) = args.get(1) {
+        let time = parse_datetime(time)?;
+        if time <= Utc::now() {
+            return Err(anyhow!(BotError::new("That time is in the past")));
+        }
+        let text = rest_args(message.text().unwrap_or_default(), 2);
+        let job = insert_job(ctx, message, text, time, None).await?;
+        ctx.tg
+            .reply(
+                message,
+                format!("Scheduled {} for {}", job.job_id, job.next_run),
+            )
+            .await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new(
+            "Usage: /schedule <YYYY-MM-DDTHH:MM> <text>"
+        )))
+    }
+}
+
+async fn every(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    require_admin(ctx, message).await?;
+    if let Some(Arg::Arg(interval)) = args.get(1) {
+        let interval = parse_duration(interval)?;
+        let config: ScheduleConfig = ctx.config.module()?;
+        if interval.num_seconds() < config.min_interval_secs {
+            return Err(anyhow!(BotError::new(format!(
+                "Interval must be at least {}s",
+                config.min_interval_secs
+            ))));
+        }
+        let text = rest_args(message.text().unwrap_or_default(), 2);
+        let job = insert_job(ctx, message, text, Utc::now() + interval, Some(interval)).await?;
+        ctx.tg
+            .reply(
+                message,
+                format!("Scheduled {}, first run at {}", job.job_id, job.next_run),
+            )
+            .await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("Usage: /every <interval> <text>")))
+    }
+}
+
+async fn list_jobs(ctx: &BotContext, message: &Message) -> Result<()> {
+    let jobs = entities::scheduled_jobs::Entity::find()
+        .filter(entities::scheduled_jobs::Column::ChatId.eq(message.chat.id))
+        .order_by_asc(entities::scheduled_jobs::Column::NextRun)
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .fold(String::from("Scheduled messages:"), |mut s, job| {
+            let every = job
+                .interval_secs
+                .map(|i| format!(" every {}s", i))
+                .unwrap_or_default();
+            s.push_str(
+                format!(
+                    "\n - {} next: {}{}: {}",
+                    job.job_id, job.next_run, every, job.text
+                )
+                .as_str(),
+            );
+            s
+        });
+    ctx.tg.reply(message, jobs).await?;
+    Ok(())
+}
+
+async fn cancel_job(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    require_admin(ctx, message).await?;
+    if let Some(Arg::Arg(uuid)) = args.get(1) {
+        let uuid = Uuid::from_str(uuid)?;
+        let res = entities::scheduled_jobs::Entity::delete_many()
+            .filter(entities::scheduled_jobs::Column::JobId.eq(uuid))
+            .filter(entities::scheduled_jobs::Column::ChatId.eq(message.chat.id))
+            .exec(&*ctx.db)
+            .await?;
+        let text = if res.rows_affected > 0 {
+            "Cancelled scheduled message"
+        } else {
+            "No such scheduled message"
+        };
+        ctx.tg.reply(message, text).await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("Usage: /canceljob <id>")))
+    }
+}
+
+async fn handle_command(ctx: &BotContext, message: &Message) -> Result<()> {
+    if let Some(text) = message.text() {
+        let command = parse_cmd(text)?;
+        if let Some(Arg::Arg(cmd)) = command.first() {
+            match cmd.as_str() {
+                "/schedule" => schedule(ctx, message, &command).await,
+                "/every" => every(ctx, message, &command).await,
+                "/jobs" => list_jobs(ctx, message).await,
+                "/canceljob" => cancel_job(ctx, message, &command).await,
+                _ => Ok(()),
+            }?;
+        }
+    };
+    Ok(())
+}
+
+async fn handle_update(ctx: &BotContext, update: &Update) {
+    let res = match update.kind {
+        UpdateKind::Message(ref message) => handle_command(ctx, message).await,
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        info!("error {}", err);
+        if let Some(chat) = update.chat() {
+            if let Err(send_err) = ctx.tg.send(chat.id, err.to_string()).await {
+                log::error!("failed to send error message: {}", send_err);
+            }
+        }
+    }
+}
@@ -1,34 +1,373 @@
+use std::collections::{HashMap, HashSet};
 use std::str::FromStr;
 
 use self::entities::tags::ModelRedis;
+use crate::context::BotContext;
+use crate::persist::core::chat_members;
+use crate::persist::drift::entity_table;
 use crate::persist::redis::{
-    default_cached_query_vec, scope_key_by_chatuser, CachedQuery, CachedQueryTrait, RedisPool,
-    RedisStr,
+    default_cached_query_vec, invalidate_tag, scope_key_by_chatuser, scope_key_by_user,
+    CachedQuery, CachedQueryTrait, RedisPool,
 };
 use crate::persist::Result;
-use crate::statics::{DB, REDIS, TG};
-use crate::tg::command::{parse_cmd, Arg};
-use crate::tg::dialog::{drop_converstaion, Conversation};
+use crate::tg::admin::{require_admin, target_user};
+use crate::tg::api::TgApi;
+use crate::tg::command::{join_args, parse_cmd, rest_args, Arg};
+use crate::tg::dialog::{drop_converstaion, forget_chat_member, record_chat_member, Conversation};
 use crate::tg::dialog::{get_conversation, replace_conversation};
+use crate::tg::module::{BotModule, ModuleCommand, Propagation, UpdateType};
 use crate::util::error::BotError;
 use anyhow::anyhow;
-use lazy_static::__Deref;
+use async_trait::async_trait;
+use chrono::Utc;
 use log::info;
 use sea_orm::entity::prelude::*;
-use sea_orm::{ActiveModelTrait, IntoActiveModel, QuerySelect, Set};
+use sea_orm::sea_query::{Expr, Query, TableCreateStatement};
+use sea_orm::{
+    ActiveModelTrait, Condition, ConnectionTrait, IntoActiveModel, QueryOrder, QuerySelect, Set,
+    Statement,
+};
 use sea_schema::migration::{MigrationName, MigrationTrait};
+use serde::{Deserialize, Serialize};
 
-use teloxide::payloads::SendMessageSetters;
-use teloxide::prelude::Requester;
 use teloxide::types::{
-    InlineQuery, InlineQueryResult, InlineQueryResultCachedSticker, MediaKind, Message,
-    MessageCommon, MessageKind, Update, UpdateKind,
+    ChosenInlineResult, InlineQuery, InlineQueryResult, InlineQueryResultCachedGif,
+    InlineQueryResultCachedPhoto, InlineQueryResultCachedSticker, InlineQueryResultCachedVoice,
+    MediaKind, Message, MessageCommon, MessageKind, Update, UpdateKind,
 };
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tg::mock::{
+        message_update, mock_context_with, reply_update, sticker_set, sticker_update,
+    };
+    use lazy_static::lazy_static;
+    use sea_orm::{DbBackend, MockDatabase, MockExecResult};
+
+    lazy_static! {
+        // invalidate_all changes every search key, so cache tests take turns
+        static ref SEARCH_CACHE: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+    }
+
+    fn sticker(file_id: &str, owner: i64, name: Option<&str>) -> entities::stickers::Model {
+        entities::stickers::Model {
+            unique_id: file_id.to_owned(),
+            owner_id: owner,
+            uuid: Uuid::new_v4(),
+            chosen_name: name.map(|name| name.to_owned()),
+            kind: KIND_STICKER.to_owned(),
+        }
+    }
+
+    fn tag(file_id: &str, owner: i64, tag: &str) -> entities::tags::Model {
+        entities::tags::Model {
+            id: 1,
+            sticker_id: file_id.to_owned(),
+            owner_id: owner,
+            tag: tag.to_owned(),
+        }
+    }
+
+    fn no_stickers() -> Vec<entities::stickers::Model> {
+        Vec::new()
+    }
+
+    fn no_tags() -> Vec<entities::tags::Model> {
+        Vec::new()
+    }
+
+    fn no_shares() -> Vec<entities::sticker_shares::Model> {
+        Vec::new()
+    }
+
+    fn share(owner: i64, kind: &str, grantee: i64) -> entities::sticker_shares::Model {
+        entities::sticker_shares::Model {
+            id: 1,
+            owner_id: owner,
+            kind: kind.to_owned(),
+            grantee_id: grantee,
+        }
+    }
+
+    // names of the stickers a search for "cat" finds. A name only shows up
+    // here once, so a repeated name means the search was served from cache
+    async fn search(ctx: &BotContext, user: i64) -> Vec<Option<String>> {
+        search_stickers(ctx, user, "cat".to_owned(), 0)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|sticker| sticker.chosen_name)
+            .collect()
+    }
+
+    fn found(name: &str) -> Vec<Option<String>> {
+        vec![Some(name.to_owned())]
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn search_owner_test() {
+        let _lock = SEARCH_CACHE.lock().await;
+        let (first, second) = (7101, 7102);
+        let db = MockDatabase::new(DbBackend::Postgres)
+            .append_query_results(vec![vec![sticker("first", first, Some("first"))]])
+            .append_query_results(vec![vec![sticker("second", second, Some("second"))]]);
+        let (ctx, _) = mock_context_with(db).await;
+        invalidate_user(&ctx, first).await.unwrap();
+        invalidate_user(&ctx, second).await.unwrap();
+
+        assert_eq!(search(&ctx, first).await, found("first"));
+        // same search text, but never the first owner's cached page
+        assert_eq!(search(&ctx, second).await, found("second"));
+        assert_eq!(search(&ctx, first).await, found("first"));
+        assert_eq!(search(&ctx, second).await, found("second"));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn search_write_test() {
+        let _lock = SEARCH_CACHE.lock().await;
+        let user = 7103;
+        let cat = sticker("cat", user, Some("cat"));
+        let db = MockDatabase::new(DbBackend::Postgres)
+            .append_query_results(vec![vec![sticker("cat", user, Some("before"))]])
+            // upload
+            .append_query_results(vec![no_stickers()])
+            .append_query_results(vec![vec![sticker("new", user, None)]])
+            .append_query_results(vec![no_tags()])
+            .append_query_results(vec![vec![tag("new", user, "cat")]])
+            .append_query_results(vec![no_shares()])
+            .append_query_results(vec![vec![sticker("cat", user, Some("uploaded"))]])
+            // tag
+            .append_query_results(vec![no_tags()])
+            .append_query_results(vec![vec![tag("cat", user, "kitten")]])
+            .append_query_results(vec![no_shares()])
+            .append_query_results(vec![vec![sticker("cat", user, Some("tagged"))]])
+            // untag
+            .append_exec_results(vec![MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }])
+            .append_query_results(vec![no_shares()])
+            .append_query_results(vec![vec![sticker("cat", user, Some("untagged"))]])
+            // delete
+            .append_query_results(vec![vec![cat.clone()]])
+            .append_exec_results(vec![MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }])
+            .append_query_results(vec![no_shares()])
+            .append_query_results(vec![vec![sticker("new", user, Some("deleted"))]]);
+        let (ctx, _) = mock_context_with(db).await;
+        invalidate_user(&ctx, user).await.unwrap();
+
+        assert_eq!(search(&ctx, user).await, found("before"));
+        assert_eq!(search(&ctx, user).await, found("before"));
+
+        let tags = vec!["cat".to_owned()];
+        assert!(save_sticker(&ctx, user, "new", None, &tags).await.unwrap());
+        assert_eq!(search(&ctx, user).await, found("uploaded"));
+        assert_eq!(search(&ctx, user).await, found("uploaded"));
+
+        let tags = vec!["kitten".to_owned()];
+        assert_eq!(add_tags(&ctx, user, "cat", &tags).await.unwrap(), 1);
+        assert_eq!(search(&ctx, user).await, found("tagged"));
+        assert_eq!(search(&ctx, user).await, found("tagged"));
+
+        assert_eq!(remove_tags(&ctx, user, "cat", &tags).await.unwrap(), 1);
+        assert_eq!(search(&ctx, user).await, found("untagged"));
+        assert_eq!(search(&ctx, user).await, found("untagged"));
+
+        let update = message_update(user, user, &format!("/delete {}", cat.uuid));
+        let message = match update.kind {
+            UpdateKind::Message(ref message) => message,
+            _ => unreachable!(),
+        };
+        let args = parse_cmd(message.text().unwrap()).unwrap();
+        delete_sticker(&ctx, message, args).await.unwrap();
+        assert_eq!(search(&ctx, user).await, found("deleted"));
+        assert_eq!(search(&ctx, user).await, found("deleted"));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn search_share_test() {
+        let _lock = SEARCH_CACHE.lock().await;
+        let (owner, viewer) = (7104, 7105);
+        let db = MockDatabase::new(DbBackend::Postgres)
+            .append_query_results(vec![vec![sticker("cat", owner, Some("before"))]])
+            .append_query_results(vec![vec![sticker("cat", owner, Some("shared"))]])
+            .append_query_results(vec![vec![share(owner, SHARE_PUBLIC, 0)]])
+            .append_query_results(vec![vec![sticker("cat", owner, Some("published"))]])
+            .append_query_results(vec![vec![sticker("cat", owner, Some("all"))]]);
+        let (ctx, _) = mock_context_with(db).await;
+        invalidate_user(&ctx, viewer).await.unwrap();
+
+        assert_eq!(search(&ctx, viewer).await, found("before"));
+        assert_eq!(search(&ctx, viewer).await, found("before"));
+
+        // a user share only refreshes its grantee
+        invalidate_share(&ctx, SHARE_USER, viewer).await.unwrap();
+        assert_eq!(search(&ctx, viewer).await, found("shared"));
+        assert_eq!(search(&ctx, viewer).await, found("shared"));
+
+        // an owner with a public share refreshes everyone
+        invalidate_owner(&ctx, owner).await.unwrap();
+        assert_eq!(search(&ctx, viewer).await, found("published"));
+        assert_eq!(search(&ctx, viewer).await, found("published"));
+
+        invalidate_all(&ctx).await.unwrap();
+        assert_eq!(search(&ctx, viewer).await, found("all"));
+    }
+
+    fn mock_db() -> MockDatabase {
+        MockDatabase::new(DbBackend::Postgres)
+    }
+
+    // Feed updates straight to the module. Conversations are kept in memory,
+    // so this runs without redis. Dropping cached inline results after a
+    // write still tries redis, but only logs when it is missing
+    async fn run(ctx: &BotContext, updates: Vec<Update>) {
+        for update in updates {
+            Module.handle_update(ctx, &update).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_test() {
+        let user = 7001;
+        let db = mock_db()
+            .append_query_results(vec![vec![sticker("file_a", user, Some("cat"))]])
+            .append_query_results(vec![vec![tag("file_a", user, "cute")]])
+            .append_query_results(vec![no_shares()]);
+        let (ctx, tg) = mock_context_with(db).await;
+        run(
+            &ctx,
+            vec![
+                message_update(user, user, "/upload"),
+                sticker_update(user, user, "file_a", None),
+                message_update(user, user, "cat"),
+                message_update(user, user, "cute"),
+                message_update(user, user, "/done"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            tg.messages(user),
+            vec![STATE_START, STATE_NAME, STATE_TAGS, STATE_TAGS, STATE_DONE]
+        );
+    }
+
+    #[tokio::test]
+    async fn upload_set_test() {
+        let user = 7002;
+        let mut db = mock_db();
+        // each sticker is looked up, inserted and tagged
+        for file_id in ["set_a", "set_b"] {
+            db = db
+                .append_query_results(vec![no_stickers()])
+                .append_query_results(vec![vec![sticker(file_id, user, None)]])
+                .append_query_results(vec![no_tags()])
+                .append_query_results(vec![vec![tag(file_id, user, "cute")]])
+                .append_query_results(vec![no_shares()]);
+        }
+        let (ctx, tg) = mock_context_with(db).await;
+        tg.add_sticker_set(sticker_set("testset", "Test set", &["set_a", "set_b"]));
+        run(
+            &ctx,
+            vec![
+                message_update(user, user, "/uploadset"),
+                sticker_update(user, user, "set_a", Some("testset")),
+                message_update(user, user, "/all"),
+                message_update(user, user, "cute"),
+                message_update(user, user, "/done"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            tg.messages(user),
+            vec![
+                STATE_SET_START.to_owned(),
+                format!("Found 2 stickers in Test set\n{}", STATE_SET_MODE),
+                STATE_SET_ALL.to_owned(),
+                STATE_SET_ALL.to_owned(),
+                format!("{}, saved 2 stickers", STATE_SET_DONE),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn edit_test() {
+        let user = 7003;
+        let db = mock_db()
+            // /edit checks ownership, then shows the sticker
+            .append_query_results(vec![vec![sticker("file_e", user, Some("cat"))]])
+            .append_query_results(vec![vec![sticker("file_e", user, Some("cat"))]])
+            .append_query_results(vec![vec![tag("file_e", user, "cute")]])
+            // +happy
+            .append_query_results(vec![vec![tag("file_e", user, "cute")]])
+            .append_query_results(vec![vec![tag("file_e", user, "happy")]])
+            .append_query_results(vec![no_shares()])
+            // -cute
+            .append_exec_results(vec![MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }])
+            .append_query_results(vec![no_shares()]);
+        let (ctx, tg) = mock_context_with(db).await;
+        run(
+            &ctx,
+            vec![
+                reply_update(user, user, "/edit", "file_e"),
+                message_update(user, user, "+happy"),
+                message_update(user, user, "-cute"),
+                message_update(user, user, "/done"),
+            ],
+        )
+        .await;
+        assert_eq!(
+            tg.messages(user),
+            vec![
+                format!("Name: cat\nTags: cute\n{}", STATE_EDIT),
+                "Added 1 tags".to_owned(),
+                "Removed 1 tags".to_owned(),
+                STATE_EDIT_DONE.to_owned(),
+            ]
+        );
+    }
+}
+
 // redis keys
-const KEY_TYPE_TAG: &str = "wc:tag";
-const KEY_TYPE_STICKER_ID: &str = "wc:stickerid";
-const KEY_TYPE_STICKER_NAME: &str = "wc:stickername";
+// hash holding the scratch data of a chat member's current conversation
+const KEY_TYPE_SCRATCH: &str = "wc:scratch";
+// abandoned conversations leave their scratch data behind this long
+const SCRATCH_TTL_SECS: usize = 24 * 60 * 60;
+
+// tag on every inline query cached for a user, used to drop stale results
+const KEY_QUERY_INDEX: &str = "stickerq:idx";
+// part of every cached query key, bumping it invalidates all of them
+const KEY_QUERY_GENERATION: &str = "stickerq:gen";
+
+// kinds of saved media
+const KIND_STICKER: &str = "sticker";
+const KIND_GIF: &str = "gif";
+const KIND_PHOTO: &str = "photo";
+const KIND_VOICE: &str = "voice";
+
+// sticker_shares kinds
+const SHARE_PUBLIC: &str = "public";
+const SHARE_USER: &str = "user";
+const SHARE_CHAT: &str = "chat";
+
+// maximum number of results telegram accepts in one inline answer
+const PAGE_SIZE: u64 = 50;
+
+// number of entries shown by /stickerstats
+const STATS_LIMIT: u64 = 10;
+
+// version of the /exportstickers document format
+const EXPORT_VERSION: u32 = 1;
 
 // conversation state machine globals
 const UPLOAD_CMD: &str = "/upload";
@@ -37,12 +376,40 @@ const TRANSITION_DONE: &str = "stickerdone";
 const TRANSITION_UPLOAD: &str = "upload";
 const TRANSITION_TAG: &str = "stickertag";
 const TRANSITION_MORETAG: &str = "stickermoretag";
-const STATE_START: &str = "Send a sticker to upload";
+const STATE_START: &str = "Send a sticker, GIF, photo or voice note to upload";
 const STATE_UPLOAD: &str = "sticker uploaded";
 const STATE_NAME: &str = "Send a name for this sticker";
 const STATE_TAGS: &str = "Send tags for this sticker, one at a time. Send /done to stop";
 const STATE_DONE: &str = "Successfully uploaded sticker";
 
+// sticker set upload state machine globals
+const UPLOAD_SET_CMD: &str = "/uploadset";
+const TRANSITION_SET_UPLOAD: &str = "setupload";
+const TRANSITION_SET_MODE: &str = "setmode";
+const TRANSITION_SET_ALL: &str = "setall";
+const TRANSITION_SET_MOREALL: &str = "setmoreall";
+const TRANSITION_SET_EACH: &str = "seteach";
+const TRANSITION_SET_MOREEACH: &str = "setmoreeach";
+const TRANSITION_SET_DONE: &str = "setdone";
+const STATE_SET_START: &str = "Send any sticker from the set to upload";
+const STATE_SET_UPLOAD: &str = "sticker set uploaded";
+const STATE_SET_MODE: &str =
+    "Send /all to give every sticker in the set the same tags or /each to tag them one at a time";
+const STATE_SET_ALL: &str =
+    "Send tags for every sticker in the set, one at a time. Send /done to stop";
+const STATE_SET_EACH: &str = "Send tags for this sticker, one at a time. Send /next to save it and move on, /skip to skip it or /done to stop";
+const STATE_SET_DONE: &str = "Successfully uploaded sticker set";
+
+// sticker edit state machine globals
+const EDIT_CMD: &str = "/edit";
+const TRANSITION_EDIT: &str = "stickeredit";
+const TRANSITION_MOREEDIT: &str = "stickermoreedit";
+const TRANSITION_EDIT_DONE: &str = "stickereditdone";
+const STATE_EDIT_START: &str = "Reply to one of your stickers or saved media with /edit";
+const STATE_EDIT: &str =
+    "Send a new name, +tag to add a tag or -tag to remove one. Send /done to stop";
+const STATE_EDIT_DONE: &str = "Finished editing sticker";
+
 fn upload_sticker_conversation(message: &Message) -> Result<Conversation> {
     let mut conversation = Conversation::new(
         UPLOAD_CMD.to_string(),
@@ -68,16 +435,100 @@ fn upload_sticker_conversation(message: &Message) -> Result<Conversation> {
     Ok(conversation)
 }
 
+fn upload_set_conversation(message: &Message) -> Result<Conversation> {
+    let mut conversation = Conversation::new(
+        UPLOAD_SET_CMD.to_string(),
+        STATE_SET_START.to_string(),
+        message.chat.id,
+        message
+            .from()
+            .ok_or_else(|| BotError::new("message has no sender"))?
+            .id,
+    )?;
+    let start_state = conversation.get_start()?.state_id;
+    let upload_state = conversation.add_state(STATE_SET_UPLOAD);
+    let mode_state = conversation.add_state(STATE_SET_MODE);
+    let all_state = conversation.add_state(STATE_SET_ALL);
+    let each_state = conversation.add_state(STATE_SET_EACH);
+    let done_state = conversation.add_state(STATE_SET_DONE);
+
+    conversation.add_transition(start_state, upload_state, TRANSITION_SET_UPLOAD);
+    conversation.add_transition(upload_state, mode_state, TRANSITION_SET_MODE);
+    conversation.add_transition(mode_state, all_state, TRANSITION_SET_ALL);
+    conversation.add_transition(all_state, all_state, TRANSITION_SET_MOREALL);
+    conversation.add_transition(mode_state, each_state, TRANSITION_SET_EACH);
+    conversation.add_transition(each_state, each_state, TRANSITION_SET_MOREEACH);
+    conversation.add_transition(all_state, done_state, TRANSITION_SET_DONE);
+    // transitions are keyed by name, so each -> done reuses the same trigger
+    conversation.add_transition(each_state, done_state, TRANSITION_SET_DONE);
+
+    Ok(conversation)
+}
+
+fn edit_sticker_conversation(message: &Message) -> Result<Conversation> {
+    let mut conversation = Conversation::new(
+        EDIT_CMD.to_string(),
+        STATE_EDIT_START.to_string(),
+        message.chat.id,
+        message
+            .from()
+            .ok_or_else(|| BotError::new("message has no sender"))?
+            .id,
+    )?;
+    let start_state = conversation.get_start()?.state_id;
+    let edit_state = conversation.add_state(STATE_EDIT);
+    let done_state = conversation.add_state(STATE_EDIT_DONE);
+
+    conversation.add_transition(start_state, edit_state, TRANSITION_EDIT);
+    conversation.add_transition(edit_state, edit_state, TRANSITION_MOREEDIT);
+    conversation.add_transition(edit_state, done_state, TRANSITION_EDIT_DONE);
+
+    Ok(conversation)
+}
+
 struct Migration;
 
+struct MigrationShares;
+
+struct MigrationSearch;
+
+struct MigrationStats;
+
+struct MigrationMediaKind;
+
 impl MigrationName for Migration {
     fn name(&self) -> &str {
         "m20220412_000001_create_stickertag"
     }
 }
 
+impl MigrationName for MigrationShares {
+    fn name(&self) -> &str {
+        "m20220504_000001_create_sticker_shares"
+    }
+}
+
+impl MigrationName for MigrationSearch {
+    fn name(&self) -> &str {
+        "m20220505_000001_sticker_search"
+    }
+}
+
+impl MigrationName for MigrationStats {
+    fn name(&self) -> &str {
+        "m20220506_000001_create_tag_usage"
+    }
+}
+
+impl MigrationName for MigrationMediaKind {
+    fn name(&self) -> &str {
+        "m20220507_000001_add_media_kind"
+    }
+}
+
 pub mod entities {
     use crate::persist::migrate::ManagerHelper;
+    use sea_orm::{ConnectionTrait, Statement};
     use sea_schema::migration::prelude::*;
     #[async_trait::async_trait]
     impl MigrationTrait for super::Migration {
@@ -148,157 +599,836 @@ pub mod entities {
             Ok(())
         }
     }
-    pub mod tags {
-        use sea_orm::entity::prelude::*;
-        use serde::{Deserialize, Serialize};
-
-        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
-        #[sea_orm(table_name = "tags")]
-        pub struct Model {
-            #[sea_orm(primary_key, auto_increment = true)]
-            pub id: i64,
-            pub sticker_id: String,
-            pub owner_id: i64,
-            #[sea_orm(column_type = "Text")]
-            pub tag: String,
-        }
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::MigrationShares {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(sticker_shares::Entity)
+                        .col(
+                            ColumnDef::new(sticker_shares::Column::Id)
+                                .big_integer()
+                                .primary_key()
+                                .auto_increment(),
+                        )
+                        .col(
+                            ColumnDef::new(sticker_shares::Column::OwnerId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(sticker_shares::Column::Kind)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(sticker_shares::Column::GranteeId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .to_owned(),
+                )
+                .await?;
 
-        #[derive(DeriveIntoActiveModel, Serialize, Deserialize)]
-        pub struct ModelRedis {
-            pub sticker_id: String,
-            pub owner_id: i64,
-            pub tag: String,
-        }
+            manager
+                .create_index(
+                    Index::create()
+                        .name("sticker_shares_grant")
+                        .table(sticker_shares::Entity)
+                        .col(sticker_shares::Column::OwnerId)
+                        .col(sticker_shares::Column::Kind)
+                        .col(sticker_shares::Column::GranteeId)
+                        .unique()
+                        .to_owned(),
+                )
+                .await?;
 
-        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-        pub enum Relation {
-            #[sea_orm(
-                belongs_to = "super::stickers::Entity",
-                from = "Column::StickerId",
-                to = "super::stickers::Column::UniqueId"
-            )]
-            Stickers,
+            Ok(())
         }
 
-        impl Related<super::stickers::Entity> for Entity {
-            fn to() -> RelationDef {
-                Relation::Stickers.def()
-            }
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager.drop_table_auto(sticker_shares::Entity).await?;
+            Ok(())
         }
-
-        impl ActiveModelBehavior for ActiveModel {}
     }
 
-    pub mod stickers {
-        use sea_orm::entity::prelude::*;
-        use serde::{Deserialize, Serialize};
-
-        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
-        #[sea_orm(table_name = "stickers")]
-        pub struct Model {
-            #[sea_orm(primary_key, auto_increment = false)]
-            pub unique_id: String,
-            pub owner_id: i64,
-            #[sea_orm(unique)]
-            pub uuid: Uuid,
-            #[sea_orm(column_type = "Text", nullable)]
-            pub chosen_name: Option<String>,
-        }
-
-        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
-        pub enum Relation {
-            #[sea_orm(has_many = "super::tags::Entity")]
-            Tags,
-        }
-
-        impl Related<super::tags::Entity> for Entity {
-            fn to() -> RelationDef {
-                Relation::Tags.def()
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::MigrationSearch {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            let conn = manager.get_connection();
+            for sql in [
+                "CREATE EXTENSION IF NOT EXISTS pg_trgm",
+                "CREATE INDEX IF NOT EXISTS tags_tag_trgm ON tags USING gin (tag gin_trgm_ops)",
+                "CREATE INDEX IF NOT EXISTS stickers_chosen_name_trgm ON stickers USING gin (chosen_name gin_trgm_ops)",
+            ] {
+                conn.execute(Statement::from_string(
+                    manager.get_database_backend(),
+                    sql.to_owned(),
+                ))
+                .await?;
             }
+            Ok(())
         }
 
-        impl ActiveModelBehavior for ActiveModel {}
-    }
-}
-
-pub fn get_migrations() -> Vec<Box<dyn MigrationTrait>> {
-    vec![Box::new(Migration)]
-}
-
-async fn handle_inline(query: &InlineQuery) -> Result<()> {
-    log::info!("query! owner: {} tag: {}", query.from.id, query.query);
-    let id = query.from.id;
-    let key = query.query.to_owned();
-    if let Some(stickers) = tokio::spawn(async move {
-        default_cached_query_vec(move |key, sql| async move {
-            let sql: &DatabaseConnection = sql;
-            let key = format!("%{}%", key);
-            let stickers = entities::stickers::Entity::find()
-                .join(
-                    sea_orm::JoinType::InnerJoin,
-                    entities::stickers::Relation::Tags.def(),
-                )
-                .group_by(entities::stickers::Column::UniqueId)
-                .filter(entities::stickers::Column::OwnerId.eq(id))
-                .filter(entities::tags::Column::Tag.like(&key))
-                .limit(10)
-                .all(sql)
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            let conn = manager.get_connection();
+            for sql in [
+                "DROP INDEX IF EXISTS tags_tag_trgm",
+                "DROP INDEX IF EXISTS stickers_chosen_name_trgm",
+            ] {
+                conn.execute(Statement::from_string(
+                    manager.get_database_backend(),
+                    sql.to_owned(),
+                ))
                 .await?;
-            Ok(Some(stickers))
-        })
-        .query(&DB.deref(), &REDIS, &key)
-        .await
-    })
-    .await??
-    {
-        let stickers = stickers.into_iter().map(|s| {
-            let r = InlineQueryResultCachedSticker {
-                id: Uuid::new_v4().to_string(),
-                sticker_file_id: s.unique_id,
-                reply_markup: None,
-                input_message_content: None,
-            };
-            InlineQueryResult::CachedSticker(r)
-        });
-
-        TG.client
-            .answer_inline_query(query.id.as_str(), stickers)
-            .await?;
-    }
-    Ok(())
-}
-
-async fn handle_message(message: &Message) -> Result<()> {
-    handle_command(message).await?;
-    handle_conversation(message).await?;
-    Ok(())
-}
-
-pub async fn handle_update(update: &Update) {
-    let res = match update.kind {
-        UpdateKind::Message(ref message) => handle_message(message).await,
-        UpdateKind::InlineQuery(ref query) => handle_inline(query).await,
-        _ => Ok(()),
-    };
-    if let Err(err) = res {
-        info!("error {}", err);
-        if let Some(chat) = update.chat() {
-            if let Err(send_err) = TG.client().send_message(chat.id, err.to_string()).await {
-                log::error!("failed to send error message: {}", send_err);
             }
+            Ok(())
         }
     }
-}
 
-async fn handle_command(message: &Message) -> Result<()> {
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::MigrationStats {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .create_table(
+                    Table::create()
+                        .table(tag_usage::Entity)
+                        .col(
+                            ColumnDef::new(tag_usage::Column::UserId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(ColumnDef::new(tag_usage::Column::Tag).text().not_null())
+                        .col(
+                            ColumnDef::new(tag_usage::Column::UseCount)
+                                .big_integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .col(
+                            ColumnDef::new(tag_usage::Column::LastUsed)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(tag_usage::Column::UserId)
+                                .col(tag_usage::Column::Tag),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_table(
+                    Table::create()
+                        .table(sticker_usage::Entity)
+                        .col(
+                            ColumnDef::new(sticker_usage::Column::UserId)
+                                .big_integer()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(sticker_usage::Column::StickerId)
+                                .text()
+                                .not_null(),
+                        )
+                        .col(
+                            ColumnDef::new(sticker_usage::Column::UseCount)
+                                .big_integer()
+                                .not_null()
+                                .default(0),
+                        )
+                        .col(
+                            ColumnDef::new(sticker_usage::Column::LastUsed)
+                                .timestamp_with_time_zone()
+                                .not_null(),
+                        )
+                        .primary_key(
+                            Index::create()
+                                .col(sticker_usage::Column::UserId)
+                                .col(sticker_usage::Column::StickerId),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_foreign_key(
+                    ForeignKey::create()
+                        .from(sticker_usage::Entity, sticker_usage::Column::StickerId)
+                        .to(stickers::Entity, stickers::Column::UniqueId)
+                        .on_delete(ForeignKeyAction::Cascade)
+                        .to_owned(),
+                )
+                .await?;
+
+            manager
+                .create_index(
+                    Index::create()
+                        .name("sticker_usage_recent")
+                        .table(sticker_usage::Entity)
+                        .col(sticker_usage::Column::UserId)
+                        .col(sticker_usage::Column::LastUsed)
+                        .to_owned(),
+                )
+                .await?;
+
+            Ok(())
+        }
+
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager.drop_table_auto(sticker_usage::Entity).await?;
+            manager.drop_table_auto(tag_usage::Entity).await?;
+            Ok(())
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for super::MigrationMediaKind {
+        async fn up(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(stickers::Entity)
+                        .add_column(
+                            ColumnDef::new(stickers::Column::Kind)
+                                .text()
+                                .not_null()
+                                .default(super::KIND_STICKER),
+                        )
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+
+        async fn down(
+            &self,
+            manager: &sea_schema::migration::SchemaManager,
+        ) -> std::result::Result<(), sea_orm::DbErr> {
+            manager
+                .alter_table(
+                    Table::alter()
+                        .table(stickers::Entity)
+                        .drop_column(stickers::Column::Kind)
+                        .to_owned(),
+                )
+                .await?;
+            Ok(())
+        }
+    }
+
+    pub mod tags {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "tags")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = true)]
+            pub id: i64,
+            pub sticker_id: String,
+            pub owner_id: i64,
+            #[sea_orm(column_type = "Text")]
+            pub tag: String,
+        }
+
+        #[derive(DeriveIntoActiveModel, Serialize, Deserialize)]
+        pub struct ModelRedis {
+            pub sticker_id: String,
+            pub owner_id: i64,
+            pub tag: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(
+                belongs_to = "super::stickers::Entity",
+                from = "Column::StickerId",
+                to = "super::stickers::Column::UniqueId"
+            )]
+            Stickers,
+        }
+
+        impl Related<super::stickers::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::Stickers.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    pub mod stickers {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "stickers")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub unique_id: String,
+            pub owner_id: i64,
+            #[sea_orm(unique)]
+            pub uuid: Uuid,
+            #[sea_orm(column_type = "Text", nullable)]
+            pub chosen_name: Option<String>,
+            #[sea_orm(column_type = "Text")]
+            pub kind: String,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(has_many = "super::tags::Entity")]
+            Tags,
+            #[sea_orm(has_many = "super::sticker_usage::Entity")]
+            Usage,
+        }
+
+        impl Related<super::tags::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::Tags.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    // How often and how recently a user sent a sticker from inline results
+    pub mod sticker_usage {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "sticker_usage")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub user_id: i64,
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub sticker_id: String,
+            pub use_count: i64,
+            pub last_used: DateTimeWithTimeZone,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {
+            #[sea_orm(
+                belongs_to = "super::stickers::Entity",
+                from = "Column::StickerId",
+                to = "super::stickers::Column::UniqueId"
+            )]
+            Stickers,
+        }
+
+        impl Related<super::stickers::Entity> for Entity {
+            fn to() -> RelationDef {
+                Relation::Stickers.def()
+            }
+        }
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    // How often a user sent stickers found through a tag. Tags are counted by
+    // text so usage survives retagging
+    pub mod tag_usage {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "tag_usage")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = false)]
+            pub user_id: i64,
+            #[sea_orm(primary_key, auto_increment = false, column_type = "Text")]
+            pub tag: String,
+            pub use_count: i64,
+            pub last_used: DateTimeWithTimeZone,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+
+    // Grants other users access to all stickers of an owner. Public shares
+    // have a grantee_id of 0, chat shares apply to every known member of the chat
+    pub mod sticker_shares {
+        use sea_orm::entity::prelude::*;
+        use serde::{Deserialize, Serialize};
+
+        #[derive(Clone, Debug, PartialEq, DeriveEntityModel, Serialize, Deserialize)]
+        #[sea_orm(table_name = "sticker_shares")]
+        pub struct Model {
+            #[sea_orm(primary_key, auto_increment = true)]
+            pub id: i64,
+            pub owner_id: i64,
+            #[sea_orm(column_type = "Text")]
+            pub kind: String,
+            pub grantee_id: i64,
+        }
+
+        #[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+        pub enum Relation {}
+
+        impl ActiveModelBehavior for ActiveModel {}
+    }
+}
+
+const COMMANDS: &[ModuleCommand] = &[
+    ModuleCommand::new("/upload", "Save new media"),
+    ModuleCommand::new("/uploadset", "Tag a whole sticker set"),
+    ModuleCommand::new("/list", "List your saved media"),
+    ModuleCommand::new("/delete", "Delete saved media"),
+    ModuleCommand::new("/rename", "Rename saved media"),
+    ModuleCommand::new("/addtag", "Add tags to saved media"),
+    ModuleCommand::new("/rmtag", "Remove tags from saved media"),
+    ModuleCommand::new("/edit", "Edit the replied media"),
+    ModuleCommand::new("/publish", "Share your collection with everyone"),
+    ModuleCommand::new("/unpublish", "Stop sharing with everyone"),
+    ModuleCommand::new("/share", "Share your collection with a user"),
+    ModuleCommand::new("/unshare", "Stop sharing with a user"),
+    ModuleCommand::new("/sharechat", "Share your collection with this chat"),
+    ModuleCommand::new("/unsharechat", "Stop sharing with this chat"),
+    ModuleCommand::new("/shares", "List who you share with"),
+    ModuleCommand::new("/exportstickers", "Export your collection as json"),
+    ModuleCommand::new("/importstickers", "Import a collection from json"),
+    ModuleCommand::new("/stickerstats", "Show your most used media and tags"),
+];
+
+pub struct Module;
+
+#[async_trait]
+impl BotModule for Module {
+    fn name(&self) -> &'static str {
+        "sticker"
+    }
+
+    fn description(&self) -> &'static str {
+        "Save, tag and search stickers and other media"
+    }
+
+    fn commands(&self) -> &'static [ModuleCommand] {
+        COMMANDS
+    }
+
+    fn update_kinds(&self) -> &'static [UpdateType] {
+        &[
+            UpdateType::Message,
+            UpdateType::InlineQuery,
+            UpdateType::ChosenInlineResult,
+            UpdateType::ChatMember,
+        ]
+    }
+
+    fn migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        vec![
+            Box::new(Migration),
+            Box::new(MigrationShares),
+            Box::new(MigrationSearch),
+            Box::new(MigrationStats),
+            Box::new(MigrationMediaKind),
+        ]
+    }
+
+    fn entities(&self) -> Vec<TableCreateStatement> {
+        vec![
+            entity_table(entities::stickers::Entity),
+            entity_table(entities::tags::Entity),
+            entity_table(entities::sticker_usage::Entity),
+            entity_table(entities::tag_usage::Entity),
+            entity_table(entities::sticker_shares::Entity),
+        ]
+    }
+
+    // chat members are tracked even when the message is a disabled command
+    async fn handle_hooks(&self, ctx: &BotContext, update: &Update) -> Propagation {
+        track_members(ctx, update).await;
+        Propagation::Continue
+    }
+
+    async fn handle_update(&self, ctx: &BotContext, update: &Update) -> Propagation {
+        handle_update(ctx, update).await;
+        Propagation::Continue
+    }
+}
+
+// condition matching stickers owned by or shared with a user
+fn visible_to(user: i64) -> Condition {
+    let chats = Query::select()
+        .column(chat_members::Column::ChatId)
+        .from(chat_members::Entity)
+        .and_where(Expr::col(chat_members::Column::UserId).eq(user))
+        .to_owned();
+    let owners = Query::select()
+        .column(entities::sticker_shares::Column::OwnerId)
+        .from(entities::sticker_shares::Entity)
+        .cond_where(
+            Condition::any()
+                .add(Expr::col(entities::sticker_shares::Column::Kind).eq(SHARE_PUBLIC))
+                .add(
+                    Condition::all()
+                        .add(Expr::col(entities::sticker_shares::Column::Kind).eq(SHARE_USER))
+                        .add(Expr::col(entities::sticker_shares::Column::GranteeId).eq(user)),
+                )
+                .add(
+                    Condition::all()
+                        .add(Expr::col(entities::sticker_shares::Column::Kind).eq(SHARE_CHAT))
+                        .add(
+                            Expr::col(entities::sticker_shares::Column::GranteeId)
+                                .in_subquery(chats),
+                        ),
+                ),
+        )
+        .to_owned();
+    Condition::any()
+        .add(entities::stickers::Column::OwnerId.eq(user))
+        .add(entities::stickers::Column::OwnerId.in_subquery(owners))
+}
+
+// escape LIKE wildcards in user supplied search text
+fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+// stickers matching a search, ranked by exact tag, then prefix, then substring
+// matches, then by how often the user sent them and trigram similarity
+async fn search_stickers(
+    ctx: &BotContext,
+    id: i64,
+    text: String,
+    offset: u64,
+) -> Result<Vec<entities::stickers::Model>> {
+    let (generation,): (Option<u64>,) = ctx.redis.pipe(|p| p.get(KEY_QUERY_GENERATION)).await?;
+    let page = format!("{}:{}:{}", generation.unwrap_or(0), offset, text);
+    let key = scope_key_by_user(&page, id);
+    let index = scope_key_by_user(KEY_QUERY_INDEX, id);
+    let ctx = ctx.clone();
+    let stickers = tokio::spawn(async move {
+        default_cached_query_vec(move |_, sql| async move {
+            let sql: &DatabaseConnection = sql;
+            let contains = format!("%{}%", escape_like(&text));
+            let prefix = format!("{}%", escape_like(&text));
+            let stickers = entities::stickers::Entity::find()
+                .join(
+                    sea_orm::JoinType::InnerJoin,
+                    entities::stickers::Relation::Tags.def(),
+                )
+                .group_by(entities::stickers::Column::UniqueId)
+                .filter(visible_to(id))
+                .filter(Expr::cust_with_values(
+                    r#"("tags"."tag" ILIKE ? OR "stickers"."chosen_name" ILIKE ? OR "tags"."tag" % ?)"#,
+                    vec![contains.clone(), contains.clone(), text.clone()],
+                ))
+                .order_by_desc(Expr::cust_with_values(
+                    r#"MAX(CASE WHEN lower("tags"."tag") = lower(?) THEN 3 WHEN "tags"."tag" ILIKE ? THEN 2 WHEN "tags"."tag" ILIKE ? THEN 1 ELSE 0 END)"#,
+                    vec![text.clone(), prefix, contains],
+                ))
+                .order_by_desc(
+                    Expr::tbl(
+                        entities::stickers::Entity,
+                        entities::stickers::Column::OwnerId,
+                    )
+                    .eq(id),
+                )
+                .order_by_desc(Expr::cust_with_values(
+                    r#"COALESCE((SELECT "use_count" FROM "sticker_usage" WHERE "sticker_usage"."sticker_id" = "stickers"."unique_id" AND "sticker_usage"."user_id" = ?), 0)"#,
+                    vec![id],
+                ))
+                .order_by_desc(Expr::cust_with_values(
+                    r#"MAX(COALESCE((SELECT "use_count" FROM "tag_usage" WHERE "tag_usage"."tag" = "tags"."tag" AND "tag_usage"."user_id" = ?), 0))"#,
+                    vec![id],
+                ))
+                .order_by_desc(Expr::cust_with_values(
+                    r#"MAX(GREATEST(similarity("tags"."tag", ?), similarity(COALESCE("stickers"."chosen_name", ''), ?)))"#,
+                    vec![text.clone(), text],
+                ))
+                .order_by_asc(entities::stickers::Column::UniqueId)
+                .offset(offset)
+                .limit(PAGE_SIZE)
+                .all(sql)
+                .await?;
+            // empty pages are cached as misses
+            Ok(Some(stickers).filter(|stickers| !stickers.is_empty()))
+        })
+        .tag(&index)
+        .query(&ctx.db, &ctx.redis, &key)
+        .await
+    })
+    .await??;
+    Ok(stickers.unwrap_or_default())
+}
+
+// stickers the user sent most recently, used for empty inline queries
+async fn recent_stickers(
+    ctx: &BotContext,
+    id: i64,
+    offset: u64,
+) -> Result<Vec<entities::stickers::Model>> {
+    let stickers = entities::stickers::Entity::find()
+        .join(
+            sea_orm::JoinType::InnerJoin,
+            entities::stickers::Relation::Usage.def(),
+        )
+        .filter(entities::sticker_usage::Column::UserId.eq(id))
+        .filter(visible_to(id))
+        .order_by_desc(entities::sticker_usage::Column::LastUsed)
+        .offset(offset)
+        .limit(PAGE_SIZE)
+        .all(&*ctx.db)
+        .await?;
+    Ok(stickers)
+}
+
+// result ids are sticker uuids so chosen results can be traced back
+fn inline_result(media: entities::stickers::Model) -> InlineQueryResult {
+    let id = media.uuid.to_string();
+    match media.kind.as_str() {
+        KIND_GIF => {
+            InlineQueryResult::CachedGif(InlineQueryResultCachedGif::new(id, media.unique_id))
+        }
+        KIND_PHOTO => {
+            InlineQueryResult::CachedPhoto(InlineQueryResultCachedPhoto::new(id, media.unique_id))
+        }
+        KIND_VOICE => InlineQueryResult::CachedVoice(InlineQueryResultCachedVoice::new(
+            id,
+            media.unique_id,
+            media.chosen_name.unwrap_or_else(|| "Voice".to_string()),
+        )),
+        _ => InlineQueryResult::CachedSticker(InlineQueryResultCachedSticker {
+            id,
+            sticker_file_id: media.unique_id,
+            reply_markup: None,
+            input_message_content: None,
+        }),
+    }
+}
+
+// kind and file id of any media that can be saved and tagged
+fn media_file(message: &Message) -> Option<(&'static str, String)> {
+    if let MessageKind::Common(MessageCommon { ref media_kind, .. }) = message.kind {
+        match media_kind {
+            MediaKind::Sticker(sticker) => Some((KIND_STICKER, sticker.sticker.file_id.clone())),
+            MediaKind::Animation(gif) => Some((KIND_GIF, gif.animation.file_id.clone())),
+            // the last size is the largest
+            MediaKind::Photo(photo) => photo.photo.last().map(|p| (KIND_PHOTO, p.file_id.clone())),
+            MediaKind::Voice(voice) => Some((KIND_VOICE, voice.voice.file_id.clone())),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
+async fn handle_inline(ctx: &BotContext, query: &InlineQuery) -> Result<()> {
+    log::info!("query! owner: {} tag: {}", query.from.id, query.query);
+    let id = query.from.id;
+    let offset: u64 = query.offset.parse().unwrap_or(0);
+    let stickers = if query.query.is_empty() {
+        recent_stickers(ctx, id, offset).await?
+    } else {
+        search_stickers(ctx, id, query.query.clone(), offset).await?
+    };
+    let next_offset = if stickers.len() as u64 == PAGE_SIZE {
+        (offset + PAGE_SIZE).to_string()
+    } else {
+        String::new()
+    };
+    let stickers = stickers.into_iter().map(inline_result);
+
+    ctx.tg
+        .answer_inline_query(query.id.clone(), stickers.collect(), next_offset)
+        .await?;
+    Ok(())
+}
+
+// count a sticker sent from inline results along with the tags the query
+// matched. Requires inline feedback to be enabled for the bot
+async fn handle_chosen(ctx: &BotContext, result: &ChosenInlineResult) -> Result<()> {
+    let db = &*ctx.db;
+    let uuid = Uuid::from_str(&result.result_id)?;
+    let sticker = entities::stickers::Entity::find()
+        .filter(entities::stickers::Column::Uuid.eq(uuid))
+        .one(db)
+        .await?;
+    if let Some(sticker) = sticker {
+        let user = result.from.id;
+        let now: DateTimeWithTimeZone = Utc::now().into();
+        db.execute(Statement::from_sql_and_values(
+            db.get_database_backend(),
+            r#"INSERT INTO "sticker_usage" ("user_id", "sticker_id", "use_count", "last_used")
+               VALUES ($1, $2, 1, $3)
+               ON CONFLICT ("user_id", "sticker_id")
+               DO UPDATE SET "use_count" = "sticker_usage"."use_count" + 1, "last_used" = EXCLUDED."last_used""#,
+            vec![user.into(), sticker.unique_id.clone().into(), now.into()],
+        ))
+        .await?;
+
+        let query = result.query.to_lowercase();
+        if !query.is_empty() {
+            let tags = sticker_tags(ctx, sticker.owner_id, &sticker.unique_id).await?;
+            for tag in tags.iter().filter(|t| t.to_lowercase().contains(&query)) {
+                db.execute(Statement::from_sql_and_values(
+                    db.get_database_backend(),
+                    r#"INSERT INTO "tag_usage" ("user_id", "tag", "use_count", "last_used")
+                       VALUES ($1, $2, 1, $3)
+                       ON CONFLICT ("user_id", "tag")
+                       DO UPDATE SET "use_count" = "tag_usage"."use_count" + 1, "last_used" = EXCLUDED."last_used""#,
+                    vec![user.into(), tag.clone().into(), now.into()],
+                ))
+                .await?;
+            }
+        }
+        // usage changes the ranking of this user's results
+        invalidate_user(ctx, user).await?;
+    }
+    Ok(())
+}
+
+async fn sticker_stats(ctx: &BotContext, message: &Message) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    let user = sender_id(message)?;
+    let db = &*ctx.db;
+    let stickers = entities::sticker_usage::Entity::find()
+        .filter(entities::sticker_usage::Column::UserId.eq(user))
+        .order_by_desc(entities::sticker_usage::Column::UseCount)
+        .limit(STATS_LIMIT)
+        .find_also_related(entities::stickers::Entity)
+        .all(db)
+        .await?;
+    let tags = entities::tag_usage::Entity::find()
+        .filter(entities::tag_usage::Column::UserId.eq(user))
+        .order_by_desc(entities::tag_usage::Column::UseCount)
+        .limit(STATS_LIMIT)
+        .all(db)
+        .await?;
+    let text = stickers.into_iter().fold(
+        String::from("My top stickers:"),
+        |mut s, (usage, sticker)| {
+            let name = sticker
+                .and_then(|s| s.chosen_name)
+                .unwrap_or_else(|| "Unnamed".to_string());
+            s.push_str(format!("\n - {}: {}", name, usage.use_count).as_str());
+            s
+        },
+    );
+    let text = tags
+        .into_iter()
+        .fold(text + "\nMy top tags:", |mut s, usage| {
+            s.push_str(format!("\n - {}: {}", usage.tag, usage.use_count).as_str());
+            s
+        });
+    ctx.tg.reply(message, text).await?;
+    Ok(())
+}
+
+// Members who leave or are removed lose access to the stickers shared with
+// the chat. Chat member updates are only sent when polling asks for them, so
+// the service message announcing a departure counts too. Membership only
+// gates /sharechat, so failing to track it must not stop the update from
+// being handled
+async fn track_members(ctx: &BotContext, update: &Update) {
+    let res = match update.kind {
+        UpdateKind::Message(ref message) if !message.chat.is_private() => {
+            track_message(ctx, message).await
+        }
+        UpdateKind::ChatMember(ref updated) if !updated.new_chat_member.kind.is_present() => {
+            forget_member(ctx, updated.chat.id, updated.new_chat_member.user.id).await
+        }
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        log::error!("failed to track chat members: {}", err);
+    }
+}
+
+async fn track_message(ctx: &BotContext, message: &Message) -> Result<()> {
+    record_chat_member(ctx, message).await?;
+    if let Some(user) = message.left_chat_member() {
+        forget_member(ctx, message.chat.id, user.id).await?;
+    }
+    Ok(())
+}
+
+async fn forget_member(ctx: &BotContext, chat: i64, user: i64) -> Result<()> {
+    forget_chat_member(ctx, chat, user).await?;
+    invalidate_user(ctx, user).await
+}
+
+async fn handle_message(ctx: &BotContext, message: &Message) -> Result<()> {
+    handle_command(ctx, message).await?;
+    handle_conversation(ctx, message).await?;
+    Ok(())
+}
+
+async fn handle_update(ctx: &BotContext, update: &Update) {
+    track_members(ctx, update).await;
+    let res = match update.kind {
+        UpdateKind::Message(ref message) => handle_message(ctx, message).await,
+        UpdateKind::InlineQuery(ref query) => handle_inline(ctx, query).await,
+        UpdateKind::ChosenInlineResult(ref result) => handle_chosen(ctx, result).await,
+        _ => Ok(()),
+    };
+    if let Err(err) = res {
+        info!("error {}", err);
+        if let Some(chat) = update.chat() {
+            if let Err(send_err) = ctx.tg.send(chat.id, err.to_string()).await {
+                log::error!("failed to send error message: {}", send_err);
+            }
+        }
+    }
+}
+
+async fn handle_command(ctx: &BotContext, message: &Message) -> Result<()> {
     if let Some(text) = message.text() {
         let command = parse_cmd(text)?;
         if let Some(Arg::Arg(cmd)) = command.first() {
             info!("command {}", cmd);
             match cmd.as_str() {
-                "/upload" => upload(message).await,
-                "/list" => list_stickers(message).await,
-                "/delete" => delete_sticker(message, command).await,
+                "/upload" => upload(ctx, message).await,
+                "/uploadset" => upload_set(ctx, message).await,
+                "/list" => list_stickers(ctx, message).await,
+                "/delete" => delete_sticker(ctx, message, command).await,
+                "/rename" => rename_sticker(ctx, message, &command).await,
+                "/addtag" => add_tag(ctx, message, &command).await,
+                "/rmtag" => remove_tag(ctx, message, &command).await,
+                "/edit" => edit(ctx, message).await,
+                "/publish" => share(ctx, message, SHARE_PUBLIC, 0, true).await,
+                "/unpublish" => share(ctx, message, SHARE_PUBLIC, 0, false).await,
+                "/share" => share_user(ctx, message, &command, true).await,
+                "/unshare" => share_user(ctx, message, &command, false).await,
+                "/sharechat" => share_chat(ctx, message, true).await,
+                "/unsharechat" => share_chat(ctx, message, false).await,
+                "/shares" => list_shares(ctx, message).await,
+                "/exportstickers" => export_stickers(ctx, message).await,
+                "/stickerstats" => sticker_stats(ctx, message).await,
+                "/importstickers" => import_stickers(ctx, message).await,
                 _ => Ok(()),
             }?;
         }
@@ -307,22 +1437,174 @@ async fn handle_command(message: &Message) -> Result<()> {
     Ok(())
 }
 
-async fn upload(message: &Message) -> Result<()> {
-    replace_conversation(message, |message| upload_sticker_conversation(message)).await?;
+async fn upload(ctx: &BotContext, message: &Message) -> Result<()> {
+    replace_conversation(ctx, message, |message| upload_sticker_conversation(message)).await?;
+    Ok(())
+}
+
+async fn upload_set(ctx: &BotContext, message: &Message) -> Result<()> {
+    replace_conversation(ctx, message, |message| upload_set_conversation(message)).await?;
+    Ok(())
+}
+
+// drop every inline result cached for a user
+async fn invalidate_user(ctx: &BotContext, user: i64) -> Result<()> {
+    invalidate_tag(&ctx.redis, &scope_key_by_user(KEY_QUERY_INDEX, user)).await?;
+    Ok(())
+}
+
+// public and chat shares reach too many users to track, so start a new cache
+// generation instead and let the old entries expire
+async fn invalidate_all(ctx: &BotContext) -> Result<()> {
+    let _: () = ctx.redis.pipe(|p| p.incr(KEY_QUERY_GENERATION, 1)).await?;
+    Ok(())
+}
+
+// a share only changes what its grantees can see
+async fn invalidate_share(ctx: &BotContext, kind: &str, grantee: i64) -> Result<()> {
+    if kind == SHARE_USER {
+        invalidate_user(ctx, grantee).await
+    } else {
+        invalidate_all(ctx).await
+    }
+}
+
+// drop cached inline results of everyone who can see this owner's stickers
+async fn invalidate_owner(ctx: &BotContext, owner: i64) -> Result<()> {
+    let shares = entities::sticker_shares::Entity::find()
+        .filter(entities::sticker_shares::Column::OwnerId.eq(owner))
+        .all(&*ctx.db)
+        .await?;
+    if shares.iter().any(|s| s.kind != SHARE_USER) {
+        return invalidate_all(ctx).await;
+    }
+    invalidate_user(ctx, owner).await?;
+    for share in shares {
+        invalidate_user(ctx, share.grantee_id).await?;
+    }
     Ok(())
 }
 
-async fn delete_sticker(message: &Message, args: Vec<Arg>) -> Result<()> {
-    drop_converstaion(message).await?;
+// invalidate_owner after a write. The write already happened, so a failure
+// is only logged and the stale results expire on their own
+async fn refresh_owner(ctx: &BotContext, owner: i64) {
+    if let Err(err) = invalidate_owner(ctx, owner).await {
+        log::error!("failed to drop inline results for {}: {}", owner, err);
+    }
+}
+
+async fn sticker_tags(ctx: &BotContext, owner: i64, sticker_id: &str) -> Result<Vec<String>> {
+    let tags = entities::tags::Entity::find()
+        .filter(entities::tags::Column::StickerId.eq(sticker_id))
+        .filter(entities::tags::Column::OwnerId.eq(owner))
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .map(|t| t.tag)
+        .collect();
+    Ok(tags)
+}
+
+// tag a sticker, skipping tags it already has. Returns the number of new tags
+async fn add_tags(
+    ctx: &BotContext,
+    owner: i64,
+    sticker_id: &str,
+    tags: &[String],
+) -> Result<usize> {
+    let known: HashSet<String> = sticker_tags(ctx, owner, sticker_id)
+        .await?
+        .into_iter()
+        .collect();
+    let new: Vec<String> = tags
+        .iter()
+        .filter(|t| !known.contains(*t))
+        .cloned()
+        .collect::<HashSet<String>>()
+        .into_iter()
+        .collect();
+    if !new.is_empty() {
+        let models = new.iter().map(|tag| {
+            ModelRedis {
+                sticker_id: sticker_id.to_owned(),
+                owner_id: owner,
+                tag: tag.to_owned(),
+            }
+            .into_active_model()
+        });
+        entities::tags::Entity::insert_many(models)
+            .exec(&*ctx.db)
+            .await?;
+        refresh_owner(ctx, owner).await;
+    }
+    Ok(new.len())
+}
+
+async fn remove_tags(
+    ctx: &BotContext,
+    owner: i64,
+    sticker_id: &str,
+    tags: &[String],
+) -> Result<u64> {
+    let res = entities::tags::Entity::delete_many()
+        .filter(entities::tags::Column::StickerId.eq(sticker_id))
+        .filter(entities::tags::Column::OwnerId.eq(owner))
+        .filter(entities::tags::Column::Tag.is_in(tags.to_vec()))
+        .exec(&*ctx.db)
+        .await?;
+    if res.rows_affected > 0 {
+        refresh_owner(ctx, owner).await;
+    }
+    Ok(res.rows_affected)
+}
+
+fn sender_id(message: &Message) -> Result<i64> {
+    Ok(message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id)
+}
+
+async fn owned_sticker(
+    ctx: &BotContext,
+    owner: i64,
+    uuid: &str,
+) -> Result<entities::stickers::Model> {
+    let uuid = Uuid::from_str(uuid)?;
+    entities::stickers::Entity::find()
+        .filter(entities::stickers::Column::Uuid.eq(uuid))
+        .filter(entities::stickers::Column::OwnerId.eq(owner))
+        .one(&*ctx.db)
+        .await?
+        .ok_or_else(|| anyhow!(BotError::new("You don't have a sticker with that id")))
+}
+
+async fn set_name(
+    ctx: &BotContext,
+    owner: i64,
+    sticker: entities::stickers::Model,
+    name: String,
+) -> Result<entities::stickers::Model> {
+    let mut sticker = sticker.into_active_model();
+    sticker.chosen_name = Set(Some(name));
+    let sticker = sticker.update(&*ctx.db).await?;
+    refresh_owner(ctx, owner).await;
+    Ok(sticker)
+}
+
+async fn delete_sticker(ctx: &BotContext, message: &Message, args: Vec<Arg>) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
     if let [Arg::Arg(_), Arg::Arg(uuid)] = args.as_slice() {
-        let uuid = Uuid::from_str(uuid.as_str())?;
+        let owner = sender_id(message)?;
+        let sticker = owned_sticker(ctx, owner, uuid).await?;
         entities::stickers::Entity::delete_many()
-            .filter(entities::stickers::Column::Uuid.eq(uuid))
-            .exec(DB.deref().deref())
+            .filter(entities::stickers::Column::UniqueId.eq(sticker.unique_id))
+            .filter(entities::stickers::Column::OwnerId.eq(owner))
+            .exec(&*ctx.db)
             .await?;
-        TG.client()
-            .send_message(message.chat.id, "Successfully deleted sticker")
-            .reply_to_message_id(message.id)
+        refresh_owner(ctx, owner).await;
+        ctx.tg
+            .reply(message, "Successfully deleted sticker")
             .await?;
         Ok(())
     } else {
@@ -330,12 +1612,87 @@ async fn delete_sticker(message: &Message, args: Vec<Arg>) -> Result<()> {
     }
 }
 
-async fn list_stickers(message: &Message) -> Result<()> {
-    drop_converstaion(message).await?;
+async fn rename_sticker(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    if let (Some(Arg::Arg(uuid)), Some(_)) = (args.get(1), args.get(2)) {
+        let owner = sender_id(message)?;
+        let sticker = owned_sticker(ctx, owner, uuid).await?;
+        let name = rest_args(message.text().unwrap_or_default(), 2);
+        set_name(ctx, owner, sticker, name).await?;
+        ctx.tg.reply(message, "Renamed sticker").await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("Usage: /rename <id> <name>")))
+    }
+}
+
+// every argument after the sticker id is a separate tag, quote tags with spaces
+fn tag_args(args: &[Arg]) -> Vec<String> {
+    args.iter()
+        .map(|a| join_args(std::slice::from_ref(a)))
+        .collect()
+}
+
+async fn add_tag(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    if let (Some(Arg::Arg(uuid)), Some(_)) = (args.get(1), args.get(2)) {
+        let owner = sender_id(message)?;
+        let sticker = owned_sticker(ctx, owner, uuid).await?;
+        let added = add_tags(ctx, owner, &sticker.unique_id, &tag_args(&args[2..])).await?;
+        ctx.tg
+            .reply(message, format!("Added {} tags", added))
+            .await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("Usage: /addtag <id> <tag>...")))
+    }
+}
+
+async fn remove_tag(ctx: &BotContext, message: &Message, args: &[Arg]) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    if let (Some(Arg::Arg(uuid)), Some(_)) = (args.get(1), args.get(2)) {
+        let owner = sender_id(message)?;
+        let sticker = owned_sticker(ctx, owner, uuid).await?;
+        let removed = remove_tags(ctx, owner, &sticker.unique_id, &tag_args(&args[2..])).await?;
+        ctx.tg
+            .reply(message, format!("Removed {} tags", removed))
+            .await?;
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new("Usage: /rmtag <id> <tag>...")))
+    }
+}
+
+async fn edit(ctx: &BotContext, message: &Message) -> Result<()> {
+    let owner = sender_id(message)?;
+    let (_, file_id) = message
+        .reply_to_message()
+        .and_then(media_file)
+        .ok_or_else(|| BotError::new(STATE_EDIT_START))?;
+    match entities::stickers::Entity::find_by_id(file_id.clone())
+        .one(&*ctx.db)
+        .await?
+    {
+        Some(sticker) if sticker.owner_id == owner => {
+            replace_conversation(ctx, message, |message| edit_sticker_conversation(message))
+                .await?;
+            let scratch = Scratch {
+                edit: Some(file_id),
+                ..Default::default()
+            };
+            set_scratch(ctx, message, &scratch).await?;
+            Ok(())
+        }
+        _ => Err(anyhow!(BotError::new("You haven't uploaded that sticker"))),
+    }
+}
+
+async fn list_stickers(ctx: &BotContext, message: &Message) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
     if let Some(sender) = message.from() {
         let stickers = entities::stickers::Entity::find()
             .filter(entities::stickers::Column::OwnerId.eq(sender.id))
-            .all(DB.deref().deref())
+            .all(&*ctx.db)
             .await?;
         let stickers = stickers
             .into_iter()
@@ -346,81 +1703,318 @@ async fn list_stickers(message: &Message) -> Result<()> {
                 s
             });
 
-        TG.client()
-            .send_message(message.chat.id, stickers)
-            .reply_to_message_id(message.id)
+        ctx.tg.reply(message, stickers).await?;
+    }
+    Ok(())
+}
+
+async fn share(
+    ctx: &BotContext,
+    message: &Message,
+    kind: &str,
+    grantee: i64,
+    enable: bool,
+) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    let owner = message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id;
+    let existing = entities::sticker_shares::Entity::find()
+        .filter(entities::sticker_shares::Column::OwnerId.eq(owner))
+        .filter(entities::sticker_shares::Column::Kind.eq(kind))
+        .filter(entities::sticker_shares::Column::GranteeId.eq(grantee))
+        .one(&*ctx.db)
+        .await?;
+    let text = match (existing, enable) {
+        (None, true) => {
+            entities::sticker_shares::ActiveModel {
+                owner_id: Set(owner),
+                kind: Set(kind.to_owned()),
+                grantee_id: Set(grantee),
+                ..Default::default()
+            }
+            .insert(&*ctx.db)
             .await?;
+            invalidate_share(ctx, kind, grantee).await?;
+            "Stickers shared"
+        }
+        (Some(existing), false) => {
+            entities::sticker_shares::Entity::delete_many()
+                .filter(entities::sticker_shares::Column::Id.eq(existing.id))
+                .exec(&*ctx.db)
+                .await?;
+            invalidate_share(ctx, kind, grantee).await?;
+            "Stickers no longer shared"
+        }
+        (Some(_), true) => "Stickers already shared",
+        (None, false) => "Stickers were not shared",
+    };
+    ctx.tg.reply(message, text).await?;
+    Ok(())
+}
+
+async fn share_user(ctx: &BotContext, message: &Message, args: &[Arg], enable: bool) -> Result<()> {
+    let user = target_user(message, args)
+        .ok_or_else(|| BotError::new("Reply to a user or pass their id"))?;
+    share(ctx, message, SHARE_USER, user, enable).await
+}
+
+async fn share_chat(ctx: &BotContext, message: &Message, enable: bool) -> Result<()> {
+    if message.chat.is_private() {
+        return Err(anyhow!(BotError::new("Use this in a group")));
+    }
+    require_admin(ctx, message).await?;
+    share(ctx, message, SHARE_CHAT, message.chat.id, enable).await
+}
+
+async fn list_shares(ctx: &BotContext, message: &Message) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    if let Some(sender) = message.from() {
+        let shares = entities::sticker_shares::Entity::find()
+            .filter(entities::sticker_shares::Column::OwnerId.eq(sender.id))
+            .all(&*ctx.db)
+            .await?
+            .into_iter()
+            .fold(
+                String::from("My stickers are shared with:"),
+                |mut s, share| {
+                    match share.kind.as_str() {
+                        SHARE_PUBLIC => s.push_str("\n - everyone"),
+                        kind => s.push_str(format!("\n - {} {}", kind, share.grantee_id).as_str()),
+                    }
+                    s
+                },
+            );
+        ctx.tg.reply(message, shares).await?;
     }
     Ok(())
 }
 
-async fn conv_start(conversation: Conversation, message: &Message) -> Result<()> {
-    TG.client()
-        .send_message(message.chat.id, "Send a sticker to upload")
-        .reply_to_message_id(message.id)
+// portable sticker library used by /exportstickers and /importstickers
+#[derive(Serialize, Deserialize)]
+struct StickerLibrary {
+    version: u32,
+    stickers: Vec<StickerExport>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StickerExport {
+    unique_id: String,
+    chosen_name: Option<String>,
+    #[serde(default = "default_kind")]
+    kind: String,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+fn default_kind() -> String {
+    KIND_STICKER.to_owned()
+}
+
+async fn export_stickers(ctx: &BotContext, message: &Message) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    let owner = message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id;
+    let stickers = entities::stickers::Entity::find()
+        .filter(entities::stickers::Column::OwnerId.eq(owner))
+        .find_with_related(entities::tags::Entity)
+        .all(&*ctx.db)
+        .await?
+        .into_iter()
+        .map(|(sticker, tags)| StickerExport {
+            unique_id: sticker.unique_id,
+            chosen_name: sticker.chosen_name,
+            kind: sticker.kind,
+            tags: tags
+                .into_iter()
+                .filter(|t| t.owner_id == owner)
+                .map(|t| t.tag)
+                .collect(),
+        })
+        .collect();
+    let library = StickerLibrary {
+        version: EXPORT_VERSION,
+        stickers,
+    };
+    let bytes = serde_json::to_vec_pretty(&library)?;
+    ctx.tg
+        .send_document(
+            message.chat.id,
+            "stickers.json".to_owned(),
+            bytes,
+            Some(message.id),
+        )
         .await?;
-    conversation.transition(TRANSITION_UPLOAD).await?;
     Ok(())
 }
 
-async fn conv_upload(conversation: Conversation, message: &Message) -> Result<()> {
-    if let MessageKind::Common(MessageCommon {
-        media_kind: MediaKind::Sticker(ref sticker),
-        ..
-    }) = message.kind
-    {
-        let key = scope_key_by_chatuser(&KEY_TYPE_STICKER_ID, &message)?;
-        let taglist = scope_key_by_chatuser(&KEY_TYPE_TAG, &message)?;
-        REDIS
-            .pipe(|p| {
-                p.set(&key, &sticker.sticker.file_id);
-                p.del(&taglist)
-            })
-            .await?;
-        let text = conversation.transition(TRANSITION_NAME).await?;
-        TG.client()
-            .send_message(message.chat.id, text)
-            .reply_to_message_id(message.id)
+async fn import_stickers(ctx: &BotContext, message: &Message) -> Result<()> {
+    drop_converstaion(ctx, message).await?;
+    let owner = message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?
+        .id;
+    let document = message
+        .reply_to_message()
+        .and_then(|m| m.document())
+        .ok_or_else(|| BotError::new("Reply to an exported sticker library"))?;
+    let bytes = ctx.tg.download_file(&document.file_id).await?;
+    let library: StickerLibrary = serde_json::from_slice(&bytes)?;
+    if library.version > EXPORT_VERSION {
+        return Err(anyhow!(BotError::new(
+            "Sticker library is from a newer bot"
+        )));
+    }
+
+    // the same sticker may appear more than once, merge their tags
+    let mut merged = HashMap::<String, StickerExport>::new();
+    for sticker in library.stickers {
+        if let Some(existing) = merged.get_mut(&sticker.unique_id) {
+            existing.tags.extend(sticker.tags);
+            if existing.chosen_name.is_none() {
+                existing.chosen_name = sticker.chosen_name;
+            }
+        } else {
+            merged.insert(sticker.unique_id.clone(), sticker);
+        }
+    }
+
+    let db = &*ctx.db;
+    let mut imported = 0;
+    let mut skipped = 0;
+    for (unique_id, sticker) in merged {
+        let existing = entities::stickers::Entity::find_by_id(unique_id.clone())
+            .one(db)
             .await?;
+        let known_tags: HashSet<String> = match existing {
+            // tags belong to the uploader, so stickers owned by someone else are left alone
+            Some(ref existing) if existing.owner_id != owner => {
+                skipped += 1;
+                continue;
+            }
+            Some(existing) => {
+                if sticker.chosen_name.is_some() {
+                    let mut existing = existing.into_active_model();
+                    existing.chosen_name = Set(sticker.chosen_name);
+                    existing.update(db).await?;
+                }
+                entities::tags::Entity::find()
+                    .filter(entities::tags::Column::StickerId.eq(unique_id.clone()))
+                    .filter(entities::tags::Column::OwnerId.eq(owner))
+                    .all(db)
+                    .await?
+                    .into_iter()
+                    .map(|t| t.tag)
+                    .collect()
+            }
+            None => {
+                entities::stickers::ActiveModel {
+                    unique_id: Set(unique_id.clone()),
+                    owner_id: Set(owner),
+                    uuid: Set(Uuid::new_v4()),
+                    chosen_name: Set(sticker.chosen_name),
+                    kind: Set(sticker.kind),
+                }
+                .insert(db)
+                .await?;
+                HashSet::new()
+            }
+        };
+
+        let tags: Vec<entities::tags::ActiveModel> = sticker
+            .tags
+            .into_iter()
+            .collect::<HashSet<String>>()
+            .difference(&known_tags)
+            .map(|tag| {
+                ModelRedis {
+                    sticker_id: unique_id.clone(),
+                    owner_id: owner,
+                    tag: tag.to_owned(),
+                }
+                .into_active_model()
+            })
+            .collect();
+        if !tags.is_empty() {
+            entities::tags::Entity::insert_many(tags).exec(db).await?;
+        }
+        imported += 1;
+    }
+    if imported > 0 {
+        refresh_owner(ctx, owner).await;
+    }
+
+    ctx.tg
+        .reply(
+            message,
+            format!(
+                "Imported {} stickers, skipped {} owned by someone else",
+                imported, skipped
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn conv_start(ctx: &BotContext, conversation: Conversation, message: &Message) -> Result<()> {
+    ctx.tg.reply(message, STATE_START).await?;
+    conversation.transition(ctx, TRANSITION_UPLOAD).await?;
+    Ok(())
+}
+
+async fn conv_upload(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    if let Some((kind, file_id)) = media_file(message) {
+        let scratch = Scratch {
+            sticker_id: Some(file_id),
+            kind: Some(kind.to_owned()),
+            ..Default::default()
+        };
+        set_scratch(ctx, message, &scratch).await?;
+        let text = conversation.transition(ctx, TRANSITION_NAME).await?;
+        ctx.tg.reply(message, text).await?;
         Ok(())
     } else {
-        Err(anyhow!(BotError::new("Send a sticker")))
+        Err(anyhow!(BotError::new(STATE_START)))
     }
 }
 
-async fn conv_name(conversation: Conversation, message: &Message) -> Result<()> {
-    let key = scope_key_by_chatuser(&KEY_TYPE_STICKER_NAME, &message)?;
-    REDIS.pipe(|p| p.set(&key, message.text())).await?;
-    let text = conversation.transition(TRANSITION_TAG).await?;
-    TG.client()
-        .send_message(message.chat.id, text)
-        .reply_to_message_id(message.id)
+async fn conv_name(ctx: &BotContext, conversation: Conversation, message: &Message) -> Result<()> {
+    let scratch = Scratch {
+        name: message.text().map(|name| name.to_owned()),
+        ..Default::default()
+    };
+    ctx.conversations
+        .update_hash(&scratch_key(message)?, &scratch)
         .await?;
+    let text = conversation.transition(ctx, TRANSITION_TAG).await?;
+    ctx.tg.reply(message, text).await?;
     Ok(())
 }
 
-async fn conv_moretags(conversation: Conversation, message: &Message) -> Result<()> {
-    let key = scope_key_by_chatuser(&KEY_TYPE_STICKER_ID, &message)?;
-    let namekey = scope_key_by_chatuser(&KEY_TYPE_STICKER_NAME, &message)?;
-    let taglist = scope_key_by_chatuser(&KEY_TYPE_TAG, &message)?;
-
-    let sticker_id: (String,) = REDIS.pipe(|p| p.get(&key)).await?;
-    let sticker_id = sticker_id.0;
+async fn conv_moretags(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    let scratch = get_scratch(ctx, message).await?;
+    let sticker_id = scratch
+        .sticker_id
+        .ok_or_else(|| BotError::new(SCRATCH_EXPIRED))?;
     let text = message.text().ok_or_else(|| BotError::new("no text"))?;
     info!("moretags stickerid: {}", sticker_id);
     if let Some(user) = message.from() {
         if text == "/done" {
-            let stickername: (String,) = REDIS.pipe(|p| p.get(&namekey)).await?;
-            let stickername = stickername.0;
-
-            let tags = REDIS
-                .drain_list::<ModelRedis>(&taglist)
-                .await?
-                .into_iter()
-                .map(|m| {
-                    info!("tag id {}", m.sticker_id);
-                    m.into_active_model()
-                });
+            let tags = scratch.tags.unwrap_or_default().into_iter().map(|m| {
+                info!("tag id {}", m.sticker_id);
+                m.into_active_model()
+            });
 
             info!("inserting sticker {}", sticker_id);
 
@@ -428,41 +2022,39 @@ async fn conv_moretags(conversation: Conversation, message: &Message) -> Result<
                 unique_id: Set(sticker_id),
                 owner_id: Set(user.id),
                 uuid: Set(Uuid::new_v4()),
-                chosen_name: Set(Some(stickername)),
+                chosen_name: Set(scratch.name),
+                kind: Set(scratch.kind.unwrap_or_else(|| KIND_STICKER.to_owned())),
             };
 
-            sticker.insert(DB.deref().deref()).await?;
+            sticker.insert(&*ctx.db).await?;
 
             info!("inserting tags {}", tags.len());
             entities::tags::Entity::insert_many(tags)
-                .exec(DB.deref().deref())
+                .exec(&*ctx.db)
                 .await?;
+            refresh_owner(ctx, user.id).await;
+            drop_scratch(ctx, message).await?;
 
-            let text = conversation.transition(TRANSITION_DONE).await?;
-            TG.client()
-                .send_message(message.chat.id, text)
-                .reply_to_message_id(message.id)
-                .await?;
+            let text = conversation.transition(ctx, TRANSITION_DONE).await?;
+            ctx.tg.reply(message, text).await?;
             Ok(())
         } else {
-            let tag = RedisStr::new(&ModelRedis {
+            let mut tags = scratch.tags.unwrap_or_default();
+            tags.push(ModelRedis {
                 sticker_id,
                 owner_id: user.id,
                 tag: text.to_owned(),
-            })?;
-
-            REDIS
-                .pipe(|p| {
-                    p.atomic();
-                    p.lpush(taglist, &tag)
-                })
+            });
+            let scratch = Scratch {
+                tags: Some(tags),
+                ..Default::default()
+            };
+            ctx.conversations
+                .update_hash(&scratch_key(message)?, &scratch)
                 .await?;
 
-            let text = conversation.transition(TRANSITION_MORETAG).await?;
-            TG.client()
-                .send_message(message.chat.id, text)
-                .reply_to_message_id(message.id)
-                .await?;
+            let text = conversation.transition(ctx, TRANSITION_MORETAG).await?;
+            ctx.tg.reply(message, text).await?;
             Ok(())
         }
     } else {
@@ -470,13 +2062,376 @@ async fn conv_moretags(conversation: Conversation, message: &Message) -> Result<
     }
 }
 
-async fn handle_conversation(message: &Message) -> Result<()> {
-    if let Some(conversation) = get_conversation(&message).await? {
-        match conversation.get_current_text().await?.as_str() {
-            STATE_START => conv_start(conversation, &message).await,
-            STATE_UPLOAD => conv_upload(conversation, &message).await,
-            STATE_NAME => conv_name(conversation, &message).await,
-            STATE_TAGS => conv_moretags(conversation, &message).await,
+// a single sticker from a sticker set waiting to be tagged
+#[derive(Serialize, Deserialize)]
+struct SetSticker {
+    file_id: String,
+    emoji: Option<String>,
+}
+
+const SCRATCH_EXPIRED: &str = "This conversation expired, please start over";
+
+// Everything the upload, set upload and edit conversations remember between
+// messages, stored as one hash. Only one conversation runs per chat member,
+// so they share it
+#[derive(Serialize, Deserialize, Default)]
+struct Scratch {
+    // upload
+    sticker_id: Option<String>,
+    name: Option<String>,
+    kind: Option<String>,
+    tags: Option<Vec<ModelRedis>>,
+    // set upload
+    set: Option<Vec<SetSticker>>,
+    set_index: Option<usize>,
+    set_tags: Option<Vec<String>>,
+    set_saved: Option<usize>,
+    // file id of the sticker being edited
+    edit: Option<String>,
+}
+
+impl Scratch {
+    fn current_set_sticker(&self) -> Option<&SetSticker> {
+        self.set
+            .as_ref()
+            .and_then(|set| set.get(self.set_index.unwrap_or(0)))
+    }
+}
+
+fn scratch_key(message: &Message) -> Result<String> {
+    scope_key_by_chatuser(KEY_TYPE_SCRATCH, message)
+}
+
+// start a conversation's scratch data over
+async fn set_scratch(ctx: &BotContext, message: &Message, scratch: &Scratch) -> Result<()> {
+    ctx.conversations
+        .set_hash(&scratch_key(message)?, scratch, SCRATCH_TTL_SECS)
+        .await
+}
+
+async fn get_scratch(ctx: &BotContext, message: &Message) -> Result<Scratch> {
+    Ok(ctx
+        .conversations
+        .get_hash(&scratch_key(message)?)
+        .await?
+        .unwrap_or_default())
+}
+
+async fn drop_scratch(ctx: &BotContext, message: &Message) -> Result<()> {
+    ctx.conversations.delete(&scratch_key(message)?).await
+}
+
+// remember a tag for the set stickers being tagged
+async fn add_set_tag(
+    ctx: &BotContext,
+    message: &Message,
+    mut tags: Vec<String>,
+    tag: &str,
+) -> Result<()> {
+    tags.push(tag.to_owned());
+    let scratch = Scratch {
+        set_tags: Some(tags),
+        ..Default::default()
+    };
+    ctx.conversations
+        .update_hash(&scratch_key(message)?, &scratch)
+        .await
+}
+
+// save a sticker along with any tags it doesn't already have. Returns false
+// if the sticker was already uploaded by someone else
+async fn save_sticker(
+    ctx: &BotContext,
+    owner: i64,
+    file_id: &str,
+    name: Option<String>,
+    tags: &[String],
+) -> Result<bool> {
+    let db = &*ctx.db;
+    match entities::stickers::Entity::find_by_id(file_id.to_owned())
+        .one(db)
+        .await?
+    {
+        Some(existing) if existing.owner_id != owner => return Ok(false),
+        Some(_) => (),
+        None => {
+            entities::stickers::ActiveModel {
+                unique_id: Set(file_id.to_owned()),
+                owner_id: Set(owner),
+                uuid: Set(Uuid::new_v4()),
+                chosen_name: Set(name),
+                kind: Set(KIND_STICKER.to_owned()),
+            }
+            .insert(db)
+            .await?;
+        }
+    };
+    add_tags(ctx, owner, file_id, tags).await?;
+    Ok(true)
+}
+
+async fn conv_set_start(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    ctx.tg.reply(message, STATE_SET_START).await?;
+    conversation.transition(ctx, TRANSITION_SET_UPLOAD).await?;
+    Ok(())
+}
+
+async fn conv_set_upload(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    let set_name = message
+        .sticker()
+        .and_then(|s| s.set_name.as_ref())
+        .ok_or_else(|| BotError::new("Send a sticker that is part of a sticker set"))?;
+    let set = ctx.tg.get_sticker_set(set_name.to_owned()).await?;
+    let count = set.stickers.len();
+    let stickers = set
+        .stickers
+        .iter()
+        .map(|s| SetSticker {
+            file_id: s.file_id.to_owned(),
+            emoji: s.emoji.to_owned(),
+        })
+        .collect();
+    let scratch = Scratch {
+        set: Some(stickers),
+        set_index: Some(0),
+        set_saved: Some(0),
+        ..Default::default()
+    };
+    set_scratch(ctx, message, &scratch).await?;
+    let text = conversation.transition(ctx, TRANSITION_SET_MODE).await?;
+    ctx.tg
+        .reply(
+            message,
+            format!("Found {} stickers in {}\n{}", count, set.title, text),
+        )
+        .await?;
+    Ok(())
+}
+
+// send the sticker currently being tagged, returns false when there are none left
+async fn send_set_sticker(ctx: &BotContext, message: &Message) -> Result<bool> {
+    let scratch = get_scratch(ctx, message).await?;
+    if let Some(sticker) = scratch.current_set_sticker() {
+        ctx.tg
+            .send_sticker(message.chat.id, sticker.file_id.to_owned())
+            .await?;
+        ctx.tg.send(message.chat.id, STATE_SET_EACH).await?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+async fn conv_set_mode(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    match message.text() {
+        Some("/all") => {
+            let text = conversation.transition(ctx, TRANSITION_SET_ALL).await?;
+            ctx.tg.reply(message, text).await?;
+            Ok(())
+        }
+        Some("/each") => {
+            conversation.transition(ctx, TRANSITION_SET_EACH).await?;
+            send_set_sticker(ctx, message).await?;
+            Ok(())
+        }
+        _ => Err(anyhow!(BotError::new("Send /all or /each"))),
+    }
+}
+
+async fn finish_set(ctx: &BotContext, conversation: Conversation, message: &Message) -> Result<()> {
+    let saved: Option<usize> = ctx
+        .conversations
+        .hash_field(&scratch_key(message)?, "set_saved")
+        .await?;
+    drop_scratch(ctx, message).await?;
+    let text = conversation.transition(ctx, TRANSITION_SET_DONE).await?;
+    ctx.tg
+        .reply(
+            message,
+            format!("{}, saved {} stickers", text, saved.unwrap_or(0)),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn conv_set_all(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    let user = message
+        .from()
+        .ok_or_else(|| BotError::new("not a user"))?
+        .id;
+    let text = message.text().ok_or_else(|| BotError::new("no text"))?;
+    let scratch = get_scratch(ctx, message).await?;
+    let tags = scratch.set_tags.unwrap_or_default();
+    if text == "/done" {
+        let stickers = scratch.set.ok_or_else(|| BotError::new(SCRATCH_EXPIRED))?;
+        let mut saved = 0;
+        for sticker in stickers {
+            if save_sticker(ctx, user, &sticker.file_id, sticker.emoji, &tags).await? {
+                saved += 1;
+            }
+        }
+        let scratch = Scratch {
+            set_saved: Some(saved),
+            ..Default::default()
+        };
+        ctx.conversations
+            .update_hash(&scratch_key(message)?, &scratch)
+            .await?;
+        finish_set(ctx, conversation, message).await
+    } else {
+        add_set_tag(ctx, message, tags, text).await?;
+        let text = conversation.transition(ctx, TRANSITION_SET_MOREALL).await?;
+        ctx.tg.reply(message, text).await?;
+        Ok(())
+    }
+}
+
+async fn conv_set_each(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    let user = message
+        .from()
+        .ok_or_else(|| BotError::new("not a user"))?
+        .id;
+    let text = message.text().ok_or_else(|| BotError::new("no text"))?;
+    let scratch = get_scratch(ctx, message).await?;
+    match text {
+        "/next" | "/skip" | "/done" => {
+            let tags = scratch.set_tags.clone().unwrap_or_default();
+            let mut saved = scratch.set_saved.unwrap_or(0);
+            if text != "/skip" && !tags.is_empty() {
+                if let Some(sticker) = scratch.current_set_sticker() {
+                    let emoji = sticker.emoji.clone();
+                    if save_sticker(ctx, user, &sticker.file_id, emoji, &tags).await? {
+                        saved += 1;
+                    }
+                }
+            }
+            let next = Scratch {
+                set_index: Some(scratch.set_index.unwrap_or(0) + 1),
+                set_tags: Some(Vec::new()),
+                set_saved: Some(saved),
+                ..Default::default()
+            };
+            ctx.conversations
+                .update_hash(&scratch_key(message)?, &next)
+                .await?;
+            if text == "/done" || !send_set_sticker(ctx, message).await? {
+                finish_set(ctx, conversation, message).await
+            } else {
+                conversation
+                    .transition(ctx, TRANSITION_SET_MOREEACH)
+                    .await?;
+                Ok(())
+            }
+        }
+        tag => {
+            add_set_tag(ctx, message, scratch.set_tags.unwrap_or_default(), tag).await?;
+            conversation
+                .transition(ctx, TRANSITION_SET_MOREEACH)
+                .await?;
+            Ok(())
+        }
+    }
+}
+
+async fn conv_edit_start(
+    ctx: &BotContext,
+    conversation: Conversation,
+    message: &Message,
+) -> Result<()> {
+    let owner = sender_id(message)?;
+    let file_id = get_scratch(ctx, message)
+        .await?
+        .edit
+        .ok_or_else(|| BotError::new(SCRATCH_EXPIRED))?;
+    let sticker = entities::stickers::Entity::find_by_id(file_id.clone())
+        .one(&*ctx.db)
+        .await?
+        .ok_or_else(|| BotError::new("sticker no longer exists"))?;
+    let tags = sticker_tags(ctx, owner, &file_id).await?;
+    let text = conversation.transition(ctx, TRANSITION_EDIT).await?;
+    ctx.tg
+        .reply(
+            message,
+            format!(
+                "Name: {}\nTags: {}\n{}",
+                sticker.chosen_name.unwrap_or_else(|| "Unnamed".to_string()),
+                tags.join(", "),
+                text
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+async fn conv_edit(ctx: &BotContext, conversation: Conversation, message: &Message) -> Result<()> {
+    let owner = sender_id(message)?;
+    let text = message.text().ok_or_else(|| BotError::new("no text"))?;
+    let file_id = get_scratch(ctx, message)
+        .await?
+        .edit
+        .ok_or_else(|| BotError::new(SCRATCH_EXPIRED))?;
+    let reply = if text == "/done" {
+        drop_scratch(ctx, message).await?;
+        conversation
+            .transition(ctx, TRANSITION_EDIT_DONE)
+            .await?
+            .to_owned()
+    } else {
+        let reply = if let Some(tag) = text.strip_prefix('+') {
+            let added = add_tags(ctx, owner, &file_id, &[tag.to_owned()]).await?;
+            format!("Added {} tags", added)
+        } else if let Some(tag) = text.strip_prefix('-') {
+            let removed = remove_tags(ctx, owner, &file_id, &[tag.to_owned()]).await?;
+            format!("Removed {} tags", removed)
+        } else {
+            let sticker = entities::stickers::Entity::find_by_id(file_id)
+                .one(&*ctx.db)
+                .await?
+                .ok_or_else(|| BotError::new("sticker no longer exists"))?;
+            set_name(ctx, owner, sticker, text.to_owned()).await?;
+            "Renamed sticker".to_owned()
+        };
+        conversation.transition(ctx, TRANSITION_MOREEDIT).await?;
+        reply
+    };
+    ctx.tg.reply(message, reply).await?;
+    Ok(())
+}
+
+async fn handle_conversation(ctx: &BotContext, message: &Message) -> Result<()> {
+    if let Some(conversation) = get_conversation(ctx, &message).await? {
+        match conversation.get_current_text(ctx).await?.as_str() {
+            STATE_START => conv_start(ctx, conversation, &message).await,
+            STATE_UPLOAD => conv_upload(ctx, conversation, &message).await,
+            STATE_NAME => conv_name(ctx, conversation, &message).await,
+            STATE_TAGS => conv_moretags(ctx, conversation, &message).await,
+            STATE_SET_START => conv_set_start(ctx, conversation, &message).await,
+            STATE_SET_UPLOAD => conv_set_upload(ctx, conversation, &message).await,
+            STATE_SET_MODE => conv_set_mode(ctx, conversation, &message).await,
+            STATE_SET_ALL => conv_set_all(ctx, conversation, &message).await,
+            STATE_SET_EACH => conv_set_each(ctx, conversation, &message).await,
+            STATE_EDIT_START => conv_edit_start(ctx, conversation, &message).await,
+            STATE_EDIT => conv_edit(ctx, conversation, &message).await,
             _ => return Ok(()),
         }?;
     } else {
@@ -0,0 +1,174 @@
+use std::borrow::Cow;
+use std::io::{Read, Write};
+
+use anyhow::anyhow;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::persist::Result;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Serialize, Deserialize, PartialEq, Debug)]
+    struct Value {
+        id: i64,
+        name: String,
+        tags: HashMap<String, Vec<u32>>,
+    }
+
+    fn value(len: usize) -> Value {
+        let mut tags = HashMap::new();
+        tags.insert("numbers".to_owned(), (0..len as u32).collect());
+        Value {
+            id: -42,
+            name: "sticker".to_owned(),
+            tags,
+        }
+    }
+
+    #[test]
+    fn round_trip_test() {
+        for format in [Format::MsgPack, Format::Json] {
+            for compress_min_bytes in [0, 1, 1 << 20] {
+                let codec = Codec {
+                    format,
+                    compress_min_bytes,
+                };
+                for len in [0, 10, 10_000] {
+                    let bytes = encode(&value(len), codec).unwrap();
+                    assert_eq!(bytes[0], MAGIC);
+                    assert_eq!(decode::<Value>(&bytes).unwrap(), value(len));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compress_test() {
+        let codec = Codec {
+            format: Format::Json,
+            compress_min_bytes: 1024,
+        };
+        let small = encode(&value(10), codec).unwrap();
+        assert_eq!(small[1], TAG_JSON);
+        assert!(serde_json::from_slice::<Value>(&small[2..]).is_ok());
+        let large = encode(&value(10_000), codec).unwrap();
+        assert_eq!(large[1], TAG_JSON | COMPRESSED);
+        assert!(large.len() < serde_json::to_vec(&value(10_000)).unwrap().len());
+    }
+
+    #[test]
+    fn legacy_test() {
+        let bytes = rmp_serde::to_vec(&value(10)).unwrap();
+        assert_eq!(decode::<Value>(&bytes).unwrap(), value(10));
+        let bytes = rmp_serde::to_vec(&"text".to_owned()).unwrap();
+        assert_eq!(decode::<String>(&bytes).unwrap(), "text");
+    }
+
+    #[test]
+    fn unknown_format_test() {
+        assert!(decode::<Value>(&[MAGIC, 0x7f, 0x90]).is_err());
+        assert!(decode::<Value>(&[MAGIC]).is_err());
+    }
+}
+
+/*
+ * Values stored in redis start with a two byte header: MAGIC followed by a
+ * tag naming the format, with the high bit set if the payload is deflated.
+ * MAGIC is a byte MessagePack never emits, so values written before the
+ * header existed are still read as plain MessagePack
+ */
+const MAGIC: u8 = 0xc1;
+const TAG_MSGPACK: u8 = 0x01;
+const TAG_JSON: u8 = 0x02;
+const COMPRESSED: u8 = 0x80;
+
+// values at least this large are compressed unless configured otherwise
+pub const DEFAULT_COMPRESS_MIN_BYTES: usize = 4096;
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    MsgPack,
+    // readable from redis-cli, at the cost of size
+    Json,
+}
+
+impl Default for Format {
+    fn default() -> Self {
+        Format::MsgPack
+    }
+}
+
+impl Format {
+    fn tag(self) -> u8 {
+        match self {
+            Format::MsgPack => TAG_MSGPACK,
+            Format::Json => TAG_JSON,
+        }
+    }
+}
+
+// how values are written. Reading never needs one since every value
+// carries its own header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Codec {
+    pub format: Format,
+    // payloads at least this large are deflated, 0 never compresses
+    pub compress_min_bytes: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self {
+            format: Format::default(),
+            compress_min_bytes: DEFAULT_COMPRESS_MIN_BYTES,
+        }
+    }
+}
+
+pub fn encode<T: Serialize + ?Sized>(val: &T, codec: Codec) -> Result<Vec<u8>> {
+    let payload = match codec.format {
+        Format::MsgPack => rmp_serde::to_vec(val)?,
+        Format::Json => serde_json::to_vec(val)?,
+    };
+    let mut tag = codec.format.tag();
+    let payload = if codec.compress_min_bytes > 0 && payload.len() >= codec.compress_min_bytes {
+        tag |= COMPRESSED;
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&payload)?;
+        encoder.finish()?
+    } else {
+        payload
+    };
+    let mut bytes = Vec::with_capacity(payload.len() + 2);
+    bytes.push(MAGIC);
+    bytes.push(tag);
+    bytes.extend_from_slice(&payload);
+    Ok(bytes)
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    let (tag, payload) = match bytes {
+        [MAGIC, tag, payload @ ..] => (*tag, payload),
+        [MAGIC] => return Err(anyhow!("redis value has a truncated header")),
+        _ => return Ok(rmp_serde::from_slice(bytes)?),
+    };
+    let payload = if tag & COMPRESSED != 0 {
+        let mut inflated = Vec::new();
+        DeflateDecoder::new(payload).read_to_end(&mut inflated)?;
+        Cow::Owned(inflated)
+    } else {
+        Cow::Borrowed(payload)
+    };
+    match tag & !COMPRESSED {
+        TAG_MSGPACK => Ok(rmp_serde::from_slice(&payload)?),
+        TAG_JSON => Ok(serde_json::from_slice(&payload)?),
+        other => Err(anyhow!("unknown redis value format {:#04x}", other)),
+    }
+}
@@ -6,4 +6,22 @@ pub mod conversation_states;
 pub mod conversation_transitions;
 pub mod conversations;
 pub mod dialogs;
+pub mod disabled_modules;
 pub mod module_schemas;
+
+use sea_orm::sea_query::TableCreateStatement;
+
+use crate::persist::drift::entity_table;
+
+// core tables as the entities expect them, referenced tables first
+pub fn entities() -> Vec<TableCreateStatement> {
+    vec![
+        entity_table(dialogs::Entity),
+        entity_table(chat_members::Entity),
+        entity_table(conversations::Entity),
+        entity_table(conversation_states::Entity),
+        entity_table(conversation_transitions::Entity),
+        entity_table(disabled_modules::Entity),
+        entity_table(module_schemas::Entity),
+    ]
+}
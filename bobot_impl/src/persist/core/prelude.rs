@@ -3,4 +3,5 @@ pub use super::conversation_states::Entity as ConversationStates;
 pub use super::conversation_transitions::Entity as ConversationTransitions;
 pub use super::conversations::Entity as Conversations;
 pub use super::dialogs::Entity as Dialogs;
+pub use super::disabled_modules::Entity as DisabledModules;
 pub use super::module_schemas::Entity as ModuleSchemas;
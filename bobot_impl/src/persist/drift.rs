@@ -0,0 +1,292 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use sea_orm::sea_query::{PostgresQueryBuilder, TableCreateStatement};
+use sea_orm::{DbBackend, EntityTrait, Schema};
+use sea_schema::postgres::def::TableDef;
+use sea_schema::postgres::discovery::SchemaDiscovery;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::persist::Result;
+use crate::tg::module::Registry;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn shape() -> TableShape {
+        let mut shape = TableShape::default();
+        shape
+            .columns
+            .insert("chat_id".to_owned(), ("BigInt".to_owned(), false));
+        shape
+            .columns
+            .insert("name".to_owned(), ("Text".to_owned(), false));
+        shape.primary_key = vec!["chat_id".to_owned()];
+        shape
+    }
+
+    #[test]
+    fn same_test() {
+        assert!(diff("t", &shape(), &shape()).is_empty());
+    }
+
+    #[test]
+    fn columns_test() {
+        let mut live = shape();
+        live.columns.remove("name");
+        live.columns
+            .insert("chat_id".to_owned(), ("Integer".to_owned(), true));
+        live.columns
+            .insert("extra".to_owned(), ("Text".to_owned(), true));
+        let diffs = diff("t", &shape(), &live);
+        assert!(diffs.len() == 4);
+        assert!(diffs.contains(&"t.name: missing column".to_owned()));
+        assert!(diffs.contains(&"t.extra: column not in entity".to_owned()));
+        assert!(diffs
+            .iter()
+            .any(|d| d.starts_with("t.chat_id: type is Integer")));
+        assert!(diffs.iter().any(|d| d.starts_with("t.chat_id: nullable")));
+    }
+
+    #[test]
+    fn keys_test() {
+        let mut live = shape();
+        live.primary_key = vec!["chat_id".to_owned(), "name".to_owned()];
+        live.foreign_keys.insert(ForeignKey {
+            columns: vec!["chat_id".to_owned()],
+            table: "dialogs".to_owned(),
+            foreign_columns: vec!["chat_id".to_owned()],
+            on_delete: "NoAction".to_owned(),
+        });
+        let diffs = diff("t", &shape(), &live);
+        assert!(diffs.len() == 2);
+        assert!(diffs.iter().any(|d| d.starts_with("t: primary key")));
+        assert!(diffs
+            .iter()
+            .any(|d| d.starts_with("t: unexpected foreign key (chat_id)")));
+    }
+}
+
+// Entity tables are created in a scratch schema and introspected the same way
+// as the live one, so both sides are compared as postgres reports them. Each
+// run gets its own schema with this prefix, inside a transaction that is
+// always rolled back, so nothing is left behind in the checked database
+const CHECK_SCHEMA: &str = "bobot_schema_check";
+
+// an entity's table as sea-orm would create it
+pub fn entity_table<E: EntityTrait>(entity: E) -> TableCreateStatement {
+    Schema::new(DbBackend::Postgres).create_table_from_entity(entity)
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ForeignKey {
+    columns: Vec<String>,
+    table: String,
+    foreign_columns: Vec<String>,
+    on_delete: String,
+}
+
+// the parts of a table an entity can disagree with, without constraint
+// names since migrations and sea-orm pick different ones
+#[derive(Debug, Default)]
+struct TableShape {
+    // type and nullability by column name
+    columns: BTreeMap<String, (String, bool)>,
+    primary_key: Vec<String>,
+    unique: BTreeSet<Vec<String>>,
+    foreign_keys: BTreeSet<ForeignKey>,
+}
+
+fn sorted(mut columns: Vec<String>) -> Vec<String> {
+    columns.sort();
+    columns
+}
+
+impl From<&TableDef> for TableShape {
+    fn from(table: &TableDef) -> Self {
+        Self {
+            columns: table
+                .columns
+                .iter()
+                .map(|column| {
+                    let ty = format!("{:?}", column.col_type);
+                    (column.name.clone(), (ty, column.not_null.is_none()))
+                })
+                .collect(),
+            primary_key: table
+                .primary_key_constraints
+                .iter()
+                .flat_map(|pk| pk.columns.iter().cloned())
+                .collect(),
+            unique: table
+                .unique_constraints
+                .iter()
+                .map(|unique| sorted(unique.columns.clone()))
+                .collect(),
+            foreign_keys: table
+                .reference_constraints
+                .iter()
+                .map(|fk| ForeignKey {
+                    columns: fk.columns.clone(),
+                    table: fk.table.clone(),
+                    foreign_columns: fk.foreign_columns.clone(),
+                    on_delete: fk
+                        .on_delete
+                        .as_ref()
+                        .map(|action| format!("{:?}", action))
+                        .unwrap_or_else(|| "NoAction".to_owned()),
+                })
+                .collect(),
+        }
+    }
+}
+
+fn describe_fk(fk: &ForeignKey) -> String {
+    format!(
+        "({}) -> {}({}) on delete {}",
+        fk.columns.join(", "),
+        fk.table,
+        fk.foreign_columns.join(", "),
+        fk.on_delete
+    )
+}
+
+// every difference between what an entity expects and the live table
+fn diff(table: &str, expected: &TableShape, live: &TableShape) -> Vec<String> {
+    let mut diffs = Vec::new();
+    for (name, (ty, nullable)) in expected.columns.iter() {
+        match live.columns.get(name) {
+            None => diffs.push(format!("{}.{}: missing column", table, name)),
+            Some((live_ty, live_nullable)) => {
+                if live_ty != ty {
+                    diffs.push(format!(
+                        "{}.{}: type is {} but entity expects {}",
+                        table, name, live_ty, ty
+                    ));
+                }
+                if live_nullable != nullable {
+                    let null = |nullable: bool| if nullable { "nullable" } else { "not null" };
+                    diffs.push(format!(
+                        "{}.{}: {} but entity expects {}",
+                        table,
+                        name,
+                        null(*live_nullable),
+                        null(*nullable)
+                    ));
+                }
+            }
+        }
+    }
+    for name in live.columns.keys() {
+        if !expected.columns.contains_key(name) {
+            diffs.push(format!("{}.{}: column not in entity", table, name));
+        }
+    }
+
+    if sorted(live.primary_key.clone()) != sorted(expected.primary_key.clone()) {
+        diffs.push(format!(
+            "{}: primary key is ({}) but entity expects ({})",
+            table,
+            live.primary_key.join(", "),
+            expected.primary_key.join(", ")
+        ));
+    }
+    for unique in expected.unique.difference(&live.unique) {
+        diffs.push(format!(
+            "{}: missing unique constraint on ({})",
+            table,
+            unique.join(", ")
+        ));
+    }
+    for unique in live.unique.difference(&expected.unique) {
+        diffs.push(format!(
+            "{}: unexpected unique constraint on ({})",
+            table,
+            unique.join(", ")
+        ));
+    }
+    for fk in expected.foreign_keys.difference(&live.foreign_keys) {
+        diffs.push(format!(
+            "{}: missing foreign key {}",
+            table,
+            describe_fk(fk)
+        ));
+    }
+    for fk in live.foreign_keys.difference(&expected.foreign_keys) {
+        diffs.push(format!(
+            "{}: unexpected foreign key {}",
+            table,
+            describe_fk(fk)
+        ));
+    }
+    diffs
+}
+
+async fn execute(pool: &PgPool, sql: &str) -> Result<()> {
+    sqlx::query(sql).execute(pool).await?;
+    Ok(())
+}
+
+async fn discover(pool: &PgPool, schema: &str) -> BTreeMap<String, TableShape> {
+    SchemaDiscovery::new(pool.clone(), schema)
+        .discover()
+        .await
+        .tables
+        .iter()
+        .map(|table| (table.info.name.clone(), TableShape::from(table)))
+        .collect()
+}
+
+async fn create_expected(
+    pool: &PgPool,
+    scratch: &str,
+    tables: &[TableCreateStatement],
+) -> Result<()> {
+    execute(pool, &format!("CREATE SCHEMA \"{}\"", scratch)).await?;
+    execute(pool, &format!("SET LOCAL search_path TO \"{}\"", scratch)).await?;
+    for table in tables {
+        execute(pool, &table.to_string(PostgresQueryBuilder)).await?;
+    }
+    Ok(())
+}
+
+// the entity tables as postgres reports them. pool must hold a single
+// connection so the transaction covers creating and discovering them
+async fn expected_shapes(
+    pool: &PgPool,
+    tables: &[TableCreateStatement],
+) -> Result<BTreeMap<String, TableShape>> {
+    let scratch = format!("{}_{}", CHECK_SCHEMA, Uuid::new_v4().to_simple());
+    execute(pool, "BEGIN").await?;
+    let expected = match create_expected(pool, &scratch, tables).await {
+        Ok(()) => Ok(discover(pool, &scratch).await),
+        Err(err) => Err(err),
+    };
+    execute(pool, "ROLLBACK").await?;
+    expected
+}
+
+// Compare every core and module entity against the tables in the live
+// schema. Returns one line per difference, empty when nothing drifted
+pub async fn check(url: &str, schema: &str, registry: &Registry) -> Result<Vec<String>> {
+    // a single connection, so the scratch transaction spans every statement
+    let pool = PgPoolOptions::new().max_connections(1).connect(url).await?;
+    let mut tables = crate::persist::core::entities();
+    for module in registry.schema_order()? {
+        tables.extend(module.entities());
+    }
+
+    let expected = expected_shapes(&pool, &tables).await?;
+
+    let live = discover(&pool, schema).await;
+    let mut diffs = Vec::new();
+    for (name, shape) in expected.iter() {
+        match live.get(name) {
+            Some(live) => diffs.append(&mut diff(name, shape, live)),
+            None => diffs.push(format!("{}: missing table", name)),
+        }
+    }
+    Ok(diffs)
+}
@@ -1,6 +1,10 @@
 pub(crate) type Result<T> = anyhow::Result<T>;
 
+pub mod codec;
 pub mod core;
+pub mod drift;
 pub mod migrate;
 #[allow(dead_code)]
 pub(crate) mod redis;
+pub mod schema;
+pub mod writecache;
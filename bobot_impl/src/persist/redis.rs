@@ -1,11 +1,13 @@
+use super::codec::{self, Codec};
 use super::Result;
 use crate::util::{
     callback::{CacheCallback, CacheMissCallback},
     error::BotError,
 };
 use anyhow::anyhow;
+use lazy_static::lazy_static;
 use sea_orm::DatabaseConnection;
-use std::{marker::PhantomData, ops::DerefMut};
+use std::{collections::HashMap, marker::PhantomData, ops::DerefMut, time::Duration};
 
 use bb8::{Pool, PooledConnection};
 use bb8_redis::RedisConnectionManager;
@@ -13,7 +15,7 @@ use bb8_redis::RedisConnectionManager;
 use async_trait::async_trait;
 use futures::Future;
 
-use redis::{AsyncCommands, ErrorKind, FromRedisValue, Pipeline, RedisError, ToRedisArgs};
+use redis::{AsyncCommands, ErrorKind, FromRedisValue, Pipeline, RedisError, Script, ToRedisArgs};
 use serde::{de::DeserializeOwned, Serialize};
 
 use teloxide::types::Message;
@@ -22,28 +24,378 @@ use uuid::Uuid;
 
 // write cache redis keys
 pub const KEY_WRITE_CACHE: &str = "writecache";
-pub const KEY_TYPE_PREFIX: &str = "wc:typeprefix";
-pub const KEY_WRAPPER: &str = "wc:wrapper";
-pub const KEY_TYPE_VAL: &str = "wc:typeval";
-pub const KEY_UUID: &str = "wc:uuid";
+
+// how long cached query results live before they are fetched again
+pub const CACHE_TTL_SECS: usize = 60 * 60;
+// how long a query that found nothing is remembered
+pub const NEGATIVE_TTL_SECS: usize = 60;
+
+// sets of cached keys sharing a tag
+const KEY_CACHE_TAG: &str = "cq:tag";
+// the only item of a cached empty list. Redis has no empty lists, so without
+// it an empty result would look uncached. Encoded values are never empty
+const EMPTY_LIST: &[u8] = b"";
+// a cached query held while one task runs it. Others wait at most this long
+// before running it themselves
+const CACHE_LOCK_TTL_MS: usize = 5000;
+const CACHE_LOCK_POLL_MS: u64 = 50;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // these need a redis server on localhost, run with --ignored
+    async fn local() -> RedisPool {
+        RedisPool::new("redis://127.0.0.1/").await.unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn list_test() {
+        let redis = local().await;
+        let key = random_key("test");
+        redis
+            .create_list(&key, vec![1, 2, 3].into_iter())
+            .await
+            .unwrap();
+        let first: Vec<i32> = redis.get_list(&key).await.unwrap();
+        let second: Vec<i32> = redis.get_list(&key).await.unwrap();
+        assert_eq!(first, vec![1, 2, 3]);
+        assert_eq!(first, second);
+        let drained: Vec<i32> = redis.drain_list(&key).await.unwrap();
+        assert_eq!(drained, vec![1, 2, 3]);
+        let empty: Vec<i32> = redis.get_list(&key).await.unwrap();
+        assert!(empty.is_empty());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn cached_query_test() {
+        let (redis, db) = (local().await, mock_db());
+        let key = random_key("test");
+        let res: Option<Vec<String>> =
+            default_cached_query_vec(|_: &str, _: &DatabaseConnection| {
+                found_rows(vec![String::from("a")])
+            })
+            .query(&db, &redis, &key)
+            .await
+            .unwrap();
+        assert_eq!(res, Some(vec![String::from("a")]));
+        let (ttl,): (i64,) = redis.pipe(|p| p.ttl(&key)).await.unwrap();
+        assert!(ttl > 0 && ttl <= CACHE_TTL_SECS as i64);
+        for _ in 0..2 {
+            let res: Option<Vec<String>> = redis_query_vec(&key, &redis).await.unwrap();
+            assert_eq!(res, Some(vec![String::from("a")]));
+        }
+        let _: () = redis.pipe(|p| p.del(&key)).await.unwrap();
+        let res: Option<Vec<String>> = redis_query_vec(&key, &redis).await.unwrap();
+        assert!(res.is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn cached_empty_vec_test() {
+        let (redis, db) = (local().await, mock_db());
+        let key = random_key("test");
+        let res: Option<Vec<String>> =
+            default_cached_query_vec(|_: &str, _: &DatabaseConnection| found_rows(Vec::new()))
+                .query(&db, &redis, &key)
+                .await
+                .unwrap();
+        assert_eq!(res, Some(Vec::new()));
+        // the empty result is cached, so the query does not run again
+        let res: Option<Vec<String>> =
+            default_cached_query_vec(|_: &str, _: &DatabaseConnection| {
+                found_rows(vec![String::from("a")])
+            })
+            .query(&db, &redis, &key)
+            .await
+            .unwrap();
+        assert_eq!(res, Some(Vec::new()));
+        let (ttl,): (i64,) = redis.pipe(|p| p.ttl(&key)).await.unwrap();
+        assert!(ttl > 0 && ttl <= CACHE_TTL_SECS as i64);
+    }
+
+    // a database query that finds val
+    async fn found(val: Option<i32>) -> Result<Option<i32>> {
+        Ok(val)
+    }
+
+    // a database query that finds the rows in val
+    async fn found_rows(val: Vec<String>) -> Result<Option<Vec<String>>> {
+        Ok(Some(val))
+    }
+
+    fn mock_db() -> DatabaseConnection {
+        sea_orm::MockDatabase::new(sea_orm::DbBackend::Postgres).into_connection()
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn ttl_test() {
+        let (redis, db) = (local().await, mock_db());
+        let key = random_key("test");
+        let res: Option<i32> =
+            default_cache_query(|_: &str, _: &DatabaseConnection| found(Some(1)))
+                .ttl(30)
+                .query(&db, &redis, &key)
+                .await
+                .unwrap();
+        assert_eq!(res, Some(1));
+        let (ttl,): (i64,) = redis.pipe(|p| p.ttl(&key)).await.unwrap();
+        assert!(ttl > 0 && ttl <= 30);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn negative_test() {
+        let (redis, db) = (local().await, mock_db());
+        let key = random_key("test");
+        let res: Option<i32> = default_cache_query(|_: &str, _: &DatabaseConnection| found(None))
+            .query(&db, &redis, &key)
+            .await
+            .unwrap();
+        assert!(res.is_none());
+        // the miss is remembered, so the row appearing goes unnoticed
+        let res: Option<i32> =
+            default_cache_query(|_: &str, _: &DatabaseConnection| found(Some(1)))
+                .query(&db, &redis, &key)
+                .await
+                .unwrap();
+        assert!(res.is_none());
+        invalidate(&redis, &key).await.unwrap();
+        let res: Option<i32> =
+            default_cache_query(|_: &str, _: &DatabaseConnection| found(Some(1)))
+                .query(&db, &redis, &key)
+                .await
+                .unwrap();
+        assert_eq!(res, Some(1));
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn tag_test() {
+        let (redis, db) = (local().await, mock_db());
+        let tag = random_key("tag");
+        let keys = [random_key("test"), random_key("test")];
+        for key in keys.iter() {
+            let res: Option<i32> =
+                default_cache_query(|_: &str, _: &DatabaseConnection| found(Some(1)))
+                    .tag(&tag)
+                    .query(&db, &redis, key)
+                    .await
+                    .unwrap();
+            assert_eq!(res, Some(1));
+        }
+        invalidate_tag(&redis, &tag).await.unwrap();
+        for key in keys.iter() {
+            let res: Option<i32> =
+                default_cache_query(|_: &str, _: &DatabaseConnection| found(Some(2)))
+                    .query(&db, &redis, key)
+                    .await
+                    .unwrap();
+            assert_eq!(res, Some(2));
+        }
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn stampede_test() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+
+        let (redis, db) = (local().await, mock_db());
+        let key = random_key("test");
+        let runs = Arc::new(AtomicUsize::new(0));
+        let query = || {
+            let runs = Arc::clone(&runs);
+            default_cache_query(move |_: &str, _: &DatabaseConnection| async move {
+                runs.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                found(Some(1)).await
+            })
+            .query(&db, &redis, &key)
+        };
+        let (first, second): (Result<Option<i32>>, Result<Option<i32>>) =
+            tokio::join!(query(), query());
+        assert_eq!(first.unwrap(), Some(1));
+        assert_eq!(second.unwrap(), Some(1));
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+    }
+
+    #[derive(Serialize, serde::Deserialize, Default, PartialEq, Debug)]
+    struct Scratch {
+        name: Option<String>,
+        count: Option<i64>,
+        tags: Option<Vec<String>>,
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn hash_test() {
+        let redis = local().await;
+        let key = random_key("test");
+        assert!(redis.get_hash::<Scratch>(&key).await.unwrap().is_none());
+        let scratch = Scratch {
+            name: Some("a".to_owned()),
+            count: Some(1),
+            tags: None,
+        };
+        redis.set_hash(&key, &scratch, 60).await.unwrap();
+        let update = Scratch {
+            count: Some(2),
+            tags: Some(vec!["b".to_owned()]),
+            ..Default::default()
+        };
+        redis.update_hash(&key, &update).await.unwrap();
+        let stored: Scratch = redis.get_hash(&key).await.unwrap().unwrap();
+        assert_eq!(stored.name.as_deref(), Some("a"));
+        assert_eq!(stored.count, Some(2));
+        assert_eq!(stored.tags, Some(vec!["b".to_owned()]));
+        let count: Option<i64> = redis.hash_field(&key, "count").await.unwrap();
+        assert_eq!(count, Some(2));
+        let (ttl,): (i64,) = redis.pipe(|p| p.ttl(&key)).await.unwrap();
+        assert!(ttl > 0 && ttl <= 60);
+        redis.set_hash(&key, &Scratch::default(), 60).await.unwrap();
+        assert!(redis.get_hash::<Scratch>(&key).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn set_test() {
+        let redis = local().await;
+        let key = random_key("test");
+        redis.add_set(&key, vec![1, 2, 3, 2]).await.unwrap();
+        redis.remove_set(&key, vec![3]).await.unwrap();
+        let mut members: Vec<i32> = redis.set_members(&key).await.unwrap();
+        members.sort_unstable();
+        assert_eq!(members, vec![1, 2]);
+        assert!(redis.is_set_member(&key, &1).await.unwrap());
+        assert!(!redis.is_set_member(&key, &3).await.unwrap());
+        let _: () = redis.pipe(|p| p.del(&key)).await.unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn zset_test() {
+        let redis = local().await;
+        let key = random_key("test");
+        redis
+            .add_zset(&key, vec![("b", 2.0), ("a", 1.0), ("c", 3.0)])
+            .await
+            .unwrap();
+        redis.remove_zset(&key, vec!["c"]).await.unwrap();
+        let all: Vec<(String, f64)> = redis.zset_range(&key, 0, -1).await.unwrap();
+        assert_eq!(all, vec![("a".to_owned(), 1.0), ("b".to_owned(), 2.0)]);
+        let high: Vec<(String, f64)> = redis.zset_range_by_score(&key, 1.5, 10.0).await.unwrap();
+        assert_eq!(high, vec![("b".to_owned(), 2.0)]);
+        let _: () = redis.pipe(|p| p.del(&key)).await.unwrap();
+    }
+}
+
+lazy_static! {
+    // Tag a cached key, keeping the tag set alive as long as its longest
+    // lived key
+    static ref TAG: Script = Script::new(
+        r"
+        redis.call('SADD', KEYS[1], ARGV[1])
+        if redis.call('TTL', KEYS[1]) < tonumber(ARGV[2]) then
+            redis.call('EXPIRE', KEYS[1], ARGV[2])
+        end
+        return 0
+        "
+    );
+
+    // Drop every key in a tag set, along with their missing markers
+    static ref INVALIDATE_TAG: Script = Script::new(
+        r"
+        local keys = redis.call('SMEMBERS', KEYS[1])
+        for _, key in ipairs(keys) do
+            redis.call('DEL', key, key .. ':none')
+        end
+        redis.call('DEL', KEYS[1])
+        return #keys
+        "
+    );
+}
+
+// marks a query that found nothing, so it is not rerun until this expires
+fn missing_key(key: &str) -> String {
+    format!("{}:none", key)
+}
+
+fn lock_key(key: &str) -> String {
+    format!("{}:lock", key)
+}
+
+fn tag_key(tag: &str) -> String {
+    format!("{}:{}", KEY_CACHE_TAG, tag)
+}
+
+// drop a cached value, or the record that it was missing, so the next
+// query reads the database again
+pub async fn invalidate(redis: &RedisPool, key: &str) -> Result<()> {
+    let _: () = redis
+        .pipe(|p| p.del(vec![key.to_owned(), missing_key(key)]))
+        .await?;
+    Ok(())
+}
+
+// drop every value cached under a tag
+pub async fn invalidate_tag(redis: &RedisPool, tag: &str) -> Result<()> {
+    let mut conn = redis.conn().await?;
+    let _: i64 = INVALIDATE_TAG
+        .key(tag_key(tag))
+        .invoke_async(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+// wait for another task's lock to be released. Returns false if it was
+// still held when we gave up
+async fn wait_unlock(redis: &RedisPool, lock: &str) -> Result<bool> {
+    for _ in 0..CACHE_LOCK_TTL_MS as u64 / CACHE_LOCK_POLL_MS {
+        let (held,): (bool,) = redis.pipe(|p| p.exists(lock)).await?;
+        if !held {
+            return Ok(true);
+        }
+        tokio::time::sleep(Duration::from_millis(CACHE_LOCK_POLL_MS)).await;
+    }
+    Ok(false)
+}
 
 async fn redis_query_vec<'a, R>(key: &'a str, redis: &'a RedisPool) -> Result<Option<Vec<R>>>
 where
-    R: DeserializeOwned + DeserializeOwned + Sync + Send + 'a,
+    R: DeserializeOwned + Sync + Send + 'a,
 {
-    let res: Vec<R> = redis.drain_list(key).await?;
-    if res.len() == 0 {
-        Ok(None)
-    } else {
-        Ok(Some(res))
+    let (items,): (Vec<Vec<u8>>,) = redis.pipe(|p| p.lrange(key, 0, -1)).await?;
+    match items.as_slice() {
+        [] => Ok(None),
+        [item] if item.as_slice() == EMPTY_LIST => Ok(Some(Vec::new())),
+        items => items
+            .iter()
+            .map(|item| codec::decode(item))
+            .collect::<Result<Vec<R>>>()
+            .map(Some),
     }
 }
 
+// expired by CachedQuery::fetch along with every other cached value
 async fn redis_miss_vec<'a, V>(key: &'a str, val: Vec<V>, redis: &'a RedisPool) -> Result<Vec<V>>
 where
     V: Serialize + DeserializeOwned + Send + Sync + 'a,
 {
-    redis.create_list(key, val.iter()).await?;
+    if val.is_empty() {
+        let _: () = redis
+            .pipe(|p| {
+                p.atomic();
+                p.del(key).ignore();
+                p.rpush(key, EMPTY_LIST).ignore()
+            })
+            .await?;
+    } else {
+        redis.create_list(key, val.iter()).await?;
+    }
     Ok(val)
 }
 
@@ -69,25 +421,32 @@ async fn redis_miss<'a, V>(key: &'a str, val: V, redis: &'a RedisPool) -> Result
 where
     V: Serialize + 'a,
 {
-    let valstr = RedisStr::new(&val)?;
-    redis.pipe(|p| p.set(key, valstr)).await?;
+    let valstr = redis.encode(&val)?;
+    let _: () = redis
+        .pipe(|p| p.set_ex(key, valstr, CACHE_TTL_SECS))
+        .await?;
     Ok(val)
 }
 
 /*
  * Helper type for caching a single value from the database
- * in redis.
+ * in redis. Values expire after a ttl and queries that found nothing are
+ * remembered for a shorter one. Concurrent misses on the same key run the
+ * sql query once while the others wait for its result
  */
 pub(crate) struct CachedQuery<'r, T, R, S, M>
 where
     T: Serialize + DeserializeOwned + Send + Sync,
-    R: CacheCallback<'r, RedisPool, T> + Send + Sync,
+    R: CacheCallback<'r, RedisPool, T> + Clone + Send + Sync,
     S: CacheCallback<'r, DatabaseConnection, T> + Send + Sync,
     M: CacheMissCallback<'r, RedisPool, T> + Send + Sync,
 {
     redis_query: R,
     sql_query: S,
     miss_query: M,
+    ttl: usize,
+    negative_ttl: Option<usize>,
+    tags: Vec<String>,
     phantom: PhantomData<&'r T>,
 }
 
@@ -96,6 +455,22 @@ pub(crate) trait CachedQueryTrait<'r, R>
 where
     R: DeserializeOwned,
 {
+    // seconds a cached value lives, CACHE_TTL_SECS by default
+    fn ttl(self, ttl: usize) -> Self
+    where
+        Self: Sized;
+
+    // seconds a query that found nothing is remembered, NEGATIVE_TTL_SECS by
+    // default. None runs the query again on every miss
+    fn negative_ttl(self, ttl: Option<usize>) -> Self
+    where
+        Self: Sized;
+
+    // cache the result under a tag so invalidate_tag can drop it
+    fn tag(self, tag: &str) -> Self
+    where
+        Self: Sized;
+
     async fn query(
         self,
         db: &'r DatabaseConnection,
@@ -128,7 +503,7 @@ where
 impl<'r, T, R, S, M> CachedQuery<'r, T, R, S, M>
 where
     T: Serialize + DeserializeOwned + Send + Sync,
-    R: CacheCallback<'r, RedisPool, T> + Send + Sync,
+    R: CacheCallback<'r, RedisPool, T> + Clone + Send + Sync,
     S: CacheCallback<'r, DatabaseConnection, T> + Send + Sync,
     M: CacheMissCallback<'r, RedisPool, T> + Send + Sync,
 {
@@ -137,35 +512,108 @@ where
             redis_query,
             sql_query,
             miss_query,
+            ttl: CACHE_TTL_SECS,
+            negative_ttl: Some(NEGATIVE_TTL_SECS),
+            tags: Vec::new(),
             phantom: PhantomData,
         }
     }
+
+    // the cached value if there is one, Some(None) if the query is known to
+    // find nothing and None if the database has to be asked
+    async fn cached(&self, redis: &'r RedisPool, key: &'r str) -> Result<Option<Option<T>>> {
+        if let Some(val) = self.redis_query.clone().cb(key, redis).await? {
+            return Ok(Some(Some(val)));
+        }
+        if self.negative_ttl.is_some() {
+            let (missing,): (bool,) = redis.pipe(|p| p.exists(missing_key(key))).await?;
+            if missing {
+                return Ok(Some(None));
+            }
+        }
+        Ok(None)
+    }
+
+    async fn fetch(
+        self,
+        db: &'r DatabaseConnection,
+        redis: &'r RedisPool,
+        key: &'r str,
+    ) -> Result<Option<T>> {
+        let val = match self.sql_query.cb(key, db).await? {
+            Some(val) => {
+                let val = self.miss_query.cb(key, val, redis).await?;
+                let _: () = redis.pipe(|p| p.expire(key, self.ttl)).await?;
+                Some(val)
+            }
+            None => {
+                if let Some(ttl) = self.negative_ttl {
+                    let _: () = redis.pipe(|p| p.set_ex(missing_key(key), 1, ttl)).await?;
+                }
+                None
+            }
+        };
+        let ttl = self.ttl.max(self.negative_ttl.unwrap_or(0));
+        for tag in self.tags.iter() {
+            let mut conn = redis.conn().await?;
+            let _: i64 = TAG
+                .key(tag_key(tag))
+                .arg(key)
+                .arg(ttl)
+                .invoke_async(&mut *conn)
+                .await?;
+        }
+        Ok(val)
+    }
 }
 
 #[async_trait]
 impl<'r, T, R, S, M> CachedQueryTrait<'r, T> for CachedQuery<'r, T, R, S, M>
 where
     T: Serialize + DeserializeOwned + Send + Sync,
-    R: CacheCallback<'r, RedisPool, T> + Send + Sync,
+    R: CacheCallback<'r, RedisPool, T> + Clone + Send + Sync,
     S: CacheCallback<'r, DatabaseConnection, T> + Send + Sync,
     M: CacheMissCallback<'r, RedisPool, T> + Send + Sync,
 {
+    fn ttl(mut self, ttl: usize) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn negative_ttl(mut self, ttl: Option<usize>) -> Self {
+        self.negative_ttl = ttl;
+        self
+    }
+
+    fn tag(mut self, tag: &str) -> Self {
+        self.tags.push(tag.to_owned());
+        self
+    }
+
     async fn query(
         self,
         db: &'r DatabaseConnection,
         redis: &'r RedisPool,
         key: &'r str,
     ) -> Result<Option<T>> {
-        if let Some(val) = self.redis_query.cb(key, redis).await? {
-            Ok(Some(val))
-        } else {
-            let val = self.sql_query.cb(key, db).await?;
-            if let Some(val) = val {
-                Ok(Some(self.miss_query.cb(key, val, redis).await?))
-            } else {
-                Ok(None)
+        if let Some(val) = self.cached(redis, key).await? {
+            return Ok(val);
+        }
+
+        let lock = lock_key(key);
+        let locked = redis.try_lock(&lock, CACHE_LOCK_TTL_MS).await?;
+        if !locked && wait_unlock(redis, &lock).await? {
+            if let Some(val) = self.cached(redis, key).await? {
+                return Ok(val);
             }
         }
+        let val = self.fetch(db, redis, key).await;
+        // if the query outlived the lock someone else may hold it by now,
+        // which at worst lets one more query through
+        if locked {
+            let _: () = redis.pipe(|p| p.del(&lock)).await?;
+        }
+        val
     }
 }
 
@@ -176,29 +624,31 @@ pub fn error_mapper(err: RedisError) -> BotError {
 }
 
 // Workaround for redis-rs's inability to support non-utf8 strings
-// as single args.
+// as single args. Values are encoded with a codec and can be read back
+// whichever codec wrote them
+#[derive(Clone)]
 pub struct RedisStr(Vec<u8>);
 
 impl RedisStr {
+    // encode with the default codec. Prefer RedisPool::encode, which uses
+    // the configured one
     pub fn new<T: Serialize>(val: &T) -> Result<Self> {
-        let bytes = rmp_serde::to_vec(val)?;
-        Ok(RedisStr(bytes))
+        Self::with_codec(val, Codec::default())
     }
 
-    pub async fn new_async<T: Serialize + Send + 'static>(val: T) -> Result<Self> {
-        tokio::spawn(async move {
-            let v: Result<Self> = Ok(RedisStr(rmp_serde::to_vec(&val)?));
-            v
-        })
-        .await?
+    pub fn with_codec<T: Serialize>(val: &T, codec: Codec) -> Result<Self> {
+        Ok(RedisStr(codec::encode(val, codec)?))
+    }
+
+    pub async fn new_async<T: Serialize + Send + 'static>(val: T, codec: Codec) -> Result<Self> {
+        tokio::spawn(async move { Self::with_codec(&val, codec) }).await?
     }
 
     pub fn get<T>(&self) -> Result<T>
     where
         T: DeserializeOwned,
     {
-        let res: T = rmp_serde::from_read(self.0.as_slice())?;
-        Ok(res)
+        codec::decode(self.0.as_slice())
     }
 }
 
@@ -257,30 +707,62 @@ pub fn scope_key_by_chatuser(key: &str, message: &Message) -> Result<String> {
 
 pub struct RedisPoolBuilder {
     connectionstr: String,
+    codec: Codec,
+    connection_timeout: Option<Duration>,
 }
 
 pub struct RedisPool {
     pool: Pool<RedisConnectionManager>,
+    codec: Codec,
 }
 
 impl RedisPoolBuilder {
     pub fn new<T: ToString>(connectonstr: T) -> Self {
         RedisPoolBuilder {
             connectionstr: connectonstr.to_string(),
+            codec: Codec::default(),
+            connection_timeout: None,
         }
     }
 
+    // codec used for values written through the pool
+    pub fn codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    // how long to wait for a connection before failing, 30 seconds by default
+    pub fn connection_timeout(mut self, timeout: Duration) -> Self {
+        self.connection_timeout = Some(timeout);
+        self
+    }
+
     pub async fn build(self) -> Result<RedisPool> {
-        RedisPool::new(self.connectionstr).await
+        let client = RedisConnectionManager::new(self.connectionstr.as_str())?;
+        let mut pool = Pool::builder().max_size(15);
+        if let Some(timeout) = self.connection_timeout {
+            pool = pool.connection_timeout(timeout);
+        }
+        Ok(RedisPool {
+            pool: pool.build(client).await?,
+            codec: self.codec,
+        })
     }
 }
 
 impl RedisPool {
     pub async fn new<T: AsRef<str>>(connectionstr: T) -> Result<Self> {
-        let client = RedisConnectionManager::new(connectionstr.as_ref())?;
+        RedisPoolBuilder::new(connectionstr.as_ref()).build().await
+    }
+
+    // the codec values written through this pool are encoded with
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
 
-        let pool = Pool::builder().max_size(15).build(client).await?;
-        Ok(RedisPool { pool })
+    // encode a value with this pool's codec
+    pub fn encode<T: Serialize>(&self, val: &T) -> Result<RedisStr> {
+        RedisStr::with_codec(val, self.codec)
     }
 
     // atomically create a list out of multipole Serialize types
@@ -294,8 +776,8 @@ impl RedisPool {
             p.atomic();
             p.del(key);
             obj.try_for_each(|v| {
-                let v = RedisStr::new(&v)?;
-                p.lpush(key, v);
+                let v = self.encode(&v)?;
+                p.rpush(key, v);
                 Ok::<(), anyhow::Error>(())
             })?;
             Ok(p)
@@ -304,8 +786,8 @@ impl RedisPool {
         Ok(())
     }
 
-    // remove and deserialize all items in a list.
-    pub async fn drain_list<R>(&self, key: &str) -> Result<Vec<R>>
+    // deserialize all items in a list without removing them
+    pub async fn get_list<R>(&self, key: &str) -> Result<Vec<R>>
     where
         R: DeserializeOwned + Send + Sync,
     {
@@ -313,13 +795,211 @@ impl RedisPool {
         conn.lrange::<&str, Vec<Vec<u8>>>(key, 0, -1)
             .await?
             .into_iter()
-            .map(|v| {
-                let res: Result<R> = rmp_serde::from_slice(&v.as_slice()).map_err(|e| anyhow!(e));
-                res
+            .map(|v| codec::decode(v.as_slice()))
+            .collect()
+    }
+
+    // remove and deserialize all items in a list.
+    pub async fn drain_list<R>(&self, key: &str) -> Result<Vec<R>>
+    where
+        R: DeserializeOwned + Send + Sync,
+    {
+        let (items, _): (Vec<Vec<u8>>, ()) = self
+            .pipe(|p| {
+                p.atomic();
+                p.lrange(key, 0, -1);
+                p.del(key)
             })
+            .await?;
+        items
+            .into_iter()
+            .map(|v| codec::decode(v.as_slice()))
             .collect()
     }
 
+    // replace the hash at key with a struct's fields, expiring the whole
+    // hash after ttl seconds
+    pub async fn set_hash<T: Serialize>(&self, key: &str, val: &T, ttl: usize) -> Result<()> {
+        self.set_fields(key, hash_fields(val, self.codec)?, ttl)
+            .await
+    }
+
+    // write the fields of a struct that are not None, keeping the others
+    // and the hash's ttl
+    pub async fn update_hash<T: Serialize>(&self, key: &str, val: &T) -> Result<()> {
+        self.update_fields(key, hash_fields(val, self.codec)?).await
+    }
+
+    // read a struct written by set_hash, None if the hash does not exist
+    pub async fn get_hash<R: DeserializeOwned>(&self, key: &str) -> Result<Option<R>> {
+        from_hash_fields(self.get_fields(key).await?)
+    }
+
+    // set_hash with the fields already encoded
+    pub async fn set_fields(
+        &self,
+        key: &str,
+        fields: Vec<(String, RedisStr)>,
+        ttl: usize,
+    ) -> Result<()> {
+        let _: () = self
+            .pipe(|p| {
+                p.atomic();
+                p.del(key).ignore();
+                if !fields.is_empty() {
+                    p.hset_multiple(key, &fields).ignore();
+                    p.expire(key, ttl).ignore();
+                }
+                p
+            })
+            .await?;
+        Ok(())
+    }
+
+    // update_hash with the fields already encoded
+    pub async fn update_fields(&self, key: &str, fields: Vec<(String, RedisStr)>) -> Result<()> {
+        if !fields.is_empty() {
+            let _: () = self.pipe(|p| p.hset_multiple(key, &fields)).await?;
+        }
+        Ok(())
+    }
+
+    // every field of a hash, still encoded
+    pub async fn get_fields(&self, key: &str) -> Result<HashMap<String, RedisStr>> {
+        let (fields,): (HashMap<String, RedisStr>,) = self.pipe(|p| p.hgetall(key)).await?;
+        Ok(fields)
+    }
+
+    // read a single field of a hash
+    pub async fn hash_field<R: DeserializeOwned>(
+        &self,
+        key: &str,
+        field: &str,
+    ) -> Result<Option<R>> {
+        let (val,): (Option<RedisStr>,) = self.pipe(|p| p.hget(key, field)).await?;
+        val.map(|val| val.get()).transpose()
+    }
+
+    // Set members are compared by their encoded bytes, so members written
+    // before a codec change will not match ones written after it
+    pub async fn add_set<U, V>(&self, key: &str, members: U) -> Result<()>
+    where
+        U: IntoIterator<Item = V>,
+        V: Serialize,
+    {
+        let members = members
+            .into_iter()
+            .map(|member| self.encode(&member))
+            .collect::<Result<Vec<RedisStr>>>()?;
+        if !members.is_empty() {
+            let _: () = self.pipe(|p| p.sadd(key, members)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_set<U, V>(&self, key: &str, members: U) -> Result<()>
+    where
+        U: IntoIterator<Item = V>,
+        V: Serialize,
+    {
+        let members = members
+            .into_iter()
+            .map(|member| self.encode(&member))
+            .collect::<Result<Vec<RedisStr>>>()?;
+        if !members.is_empty() {
+            let _: () = self.pipe(|p| p.srem(key, members)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn set_members<R: DeserializeOwned>(&self, key: &str) -> Result<Vec<R>> {
+        let (members,): (Vec<Vec<u8>>,) = self.pipe(|p| p.smembers(key)).await?;
+        members
+            .into_iter()
+            .map(|member| codec::decode(member.as_slice()))
+            .collect()
+    }
+
+    pub async fn is_set_member<V: Serialize>(&self, key: &str, member: &V) -> Result<bool> {
+        let member = self.encode(member)?;
+        let (res,): (bool,) = self.pipe(|p| p.sismember(key, member)).await?;
+        Ok(res)
+    }
+
+    // add members with their scores to a sorted set. Members are compared
+    // the same way as in add_set
+    pub async fn add_zset<U, V>(&self, key: &str, members: U) -> Result<()>
+    where
+        U: IntoIterator<Item = (V, f64)>,
+        V: Serialize,
+    {
+        let members = members
+            .into_iter()
+            .map(|(member, score)| Ok((score, self.encode(&member)?)))
+            .collect::<Result<Vec<(f64, RedisStr)>>>()?;
+        if !members.is_empty() {
+            let _: () = self.pipe(|p| p.zadd_multiple(key, &members)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn remove_zset<U, V>(&self, key: &str, members: U) -> Result<()>
+    where
+        U: IntoIterator<Item = V>,
+        V: Serialize,
+    {
+        let members = members
+            .into_iter()
+            .map(|member| self.encode(&member))
+            .collect::<Result<Vec<RedisStr>>>()?;
+        if !members.is_empty() {
+            let _: () = self.pipe(|p| p.zrem(key, members)).await?;
+        }
+        Ok(())
+    }
+
+    // members ranked start to stop by ascending score, with their scores
+    pub async fn zset_range<R: DeserializeOwned>(
+        &self,
+        key: &str,
+        start: isize,
+        stop: isize,
+    ) -> Result<Vec<(R, f64)>> {
+        let (members,): (Vec<(Vec<u8>, f64)>,) =
+            self.pipe(|p| p.zrange_withscores(key, start, stop)).await?;
+        decode_scored(members)
+    }
+
+    // members scored between min and max inclusive, lowest first
+    pub async fn zset_range_by_score<R: DeserializeOwned>(
+        &self,
+        key: &str,
+        min: f64,
+        max: f64,
+    ) -> Result<Vec<(R, f64)>> {
+        let (members,): (Vec<(Vec<u8>, f64)>,) = self
+            .pipe(|p| p.zrangebyscore_withscores(key, min, max))
+            .await?;
+        decode_scored(members)
+    }
+
+    // take a lock that expires on its own after ttl milliseconds. Returns false if
+    // the lock is already held by anyone, including ourselves
+    pub async fn try_lock(&self, key: &str, ttl: usize) -> Result<bool> {
+        let owner = Uuid::new_v4().to_string();
+        let res: (Option<String>,) = self
+            .pipe(|p| {
+                p.cmd("SET")
+                    .arg(key)
+                    .arg(owner)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl)
+            })
+            .await?;
+        Ok(res.0.is_some())
+    }
+
     // construct and run a redis pipeline using the provided closure
     pub async fn pipe<T, R>(&self, func: T) -> Result<R>
     where
@@ -381,10 +1061,48 @@ impl RedisPool {
     }
 }
 
+// Serialize a struct field by field, leaving out fields that are None so
+// partial updates only touch what they set
+pub fn hash_fields<T: Serialize>(val: &T, codec: Codec) -> Result<Vec<(String, RedisStr)>> {
+    match serde_json::to_value(val)? {
+        serde_json::Value::Object(fields) => fields
+            .into_iter()
+            .filter(|(_, field)| !field.is_null())
+            .map(|(name, field)| Ok((name, RedisStr::with_codec(&field, codec)?)))
+            .collect(),
+        _ => Err(anyhow!("only structs can be stored as a redis hash")),
+    }
+}
+
+// rebuild a struct from the fields written by hash_fields, None if there
+// are none
+pub fn from_hash_fields<R: DeserializeOwned>(
+    fields: HashMap<String, RedisStr>,
+) -> Result<Option<R>> {
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    let fields = fields
+        .into_iter()
+        .map(|(name, field)| Ok((name, field.get()?)))
+        .collect::<Result<serde_json::Map<String, serde_json::Value>>>()?;
+    Ok(Some(serde_json::from_value(serde_json::Value::Object(
+        fields,
+    ))?))
+}
+
+fn decode_scored<R: DeserializeOwned>(members: Vec<(Vec<u8>, f64)>) -> Result<Vec<(R, f64)>> {
+    members
+        .into_iter()
+        .map(|(member, score)| Ok((codec::decode(member.as_slice())?, score)))
+        .collect()
+}
+
 impl Clone for RedisPool {
     fn clone(&self) -> Self {
         RedisPool {
             pool: self.pool.clone(),
+            codec: self.codec,
         }
     }
 }
@@ -0,0 +1,600 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use sea_orm::entity::prelude::*;
+use sea_orm::{
+    ActiveModelTrait, ConnectOptions, ConnectionTrait, Database, DbBackend, ExecResult,
+    MockDatabase, MockDatabaseConnection, MockDatabaseTrait, MockExecResult, QueryResult, Set,
+    Statement, Transaction,
+};
+use sea_schema::migration::{MigrationTrait, MigratorTrait, SchemaManager};
+use sea_schema::sea_query::{ColumnDef, Table};
+
+use crate::persist::core::module_schemas;
+use crate::persist::Result;
+use crate::tg::module::{BotModule, Registry};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct DropSchemas;
+
+    impl sea_schema::migration::MigrationName for DropSchemas {
+        fn name(&self) -> &str {
+            "m00000000_000001_drop_schemas"
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for DropSchemas {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            manager
+                .drop_table(Table::drop().table(module_schemas::Entity).to_owned())
+                .await
+        }
+
+        async fn down(&self, _: &SchemaManager) -> std::result::Result<(), DbErr> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pending_test() {
+        assert!(pending("test", 0, 3).unwrap() == (0..3));
+        assert!(pending("test", 2, 3).unwrap() == (2..3));
+        assert!(pending("test", 3, 3).unwrap().is_empty());
+        assert!(pending("test", 4, 3).is_err());
+    }
+
+    #[test]
+    fn missing_table_test() {
+        let missing = r#"error returned from database: relation "seaql_migrations" does not exist"#;
+        assert!(missing_table(&DbErr::Query(missing.to_owned())));
+        assert!(!missing_table(&DbErr::Query(
+            "error communicating with the server: Connection refused".to_owned()
+        )));
+        assert!(!missing_table(&DbErr::Conn(missing.to_owned())));
+    }
+
+    #[tokio::test]
+    async fn dry_run_test() {
+        let up = dry_run_sql(&DropSchemas, Direction::Up).await.unwrap();
+        assert_eq!(up, vec![r#"DROP TABLE "module_schemas";"#.to_owned()]);
+        let down = dry_run_sql(&DropSchemas, Direction::Down).await.unwrap();
+        assert!(down.is_empty());
+    }
+
+    struct ReadSchemas;
+
+    impl sea_schema::migration::MigrationName for ReadSchemas {
+        fn name(&self) -> &str {
+            "m00000000_000002_read_schemas"
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl MigrationTrait for ReadSchemas {
+        async fn up(&self, manager: &SchemaManager) -> std::result::Result<(), DbErr> {
+            module_schemas::Entity::find()
+                .all(manager.get_connection())
+                .await?;
+            Ok(())
+        }
+
+        async fn down(&self, _: &SchemaManager) -> std::result::Result<(), DbErr> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_run_read_test() {
+        let err = dry_run_sql(&ReadSchemas, Direction::Up).await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("can't dry run m00000000_000002_read_schemas"));
+    }
+}
+
+// A module's schema version is the number of its migrations that have been
+// applied, so migrations must only ever be appended to a module's list.
+// Versions are stored per module in module_schemas
+
+// statements a dry run can record per migration before the mock runs dry
+const DRY_RUN_STATEMENTS: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Up,
+    Down,
+}
+
+// where a module's schema stands against the migrations this build knows
+struct ModuleState<'a> {
+    module: &'a dyn BotModule,
+    migrations: Vec<Box<dyn MigrationTrait>>,
+    version: i32,
+    // false for modules picked up from the old flat migrator
+    stored: bool,
+}
+
+impl<'a> ModuleState<'a> {
+    fn pending(&self) -> Result<std::ops::Range<usize>> {
+        pending(self.module.name(), self.version, self.migrations.len())
+    }
+}
+
+// the migrations still to run for a module, refusing to touch a schema
+// written by a newer build
+fn pending(name: &str, version: i32, count: usize) -> Result<std::ops::Range<usize>> {
+    let version = version as usize;
+    if version > count {
+        Err(anyhow!(
+            "schema for module {} is at version {} but this build only knows {}, refusing to start",
+            name,
+            version,
+            count
+        ))
+    } else {
+        Ok(version..count)
+    }
+}
+
+async fn create_schema_table(db: &DatabaseConnection) -> Result<()> {
+    SchemaManager::new(db)
+        .create_table(
+            Table::create()
+                .table(module_schemas::Entity)
+                .if_not_exists()
+                .col(
+                    ColumnDef::new(module_schemas::Column::ModuleName)
+                        .text()
+                        .primary_key(),
+                )
+                .col(
+                    ColumnDef::new(module_schemas::Column::SchemaVersion)
+                        .integer()
+                        .not_null(),
+                )
+                .to_owned(),
+        )
+        .await?;
+    Ok(())
+}
+
+// sea-orm only keeps the database's message, so match postgres' wording for
+// undefined_table. Anything else (lost connection, permissions) is a real error
+fn missing_table(err: &DbErr) -> bool {
+    match err {
+        DbErr::Query(msg) | DbErr::Exec(msg) => {
+            msg.contains("relation") && msg.contains("does not exist")
+        }
+        _ => false,
+    }
+}
+
+// names of migrations applied through the sea-schema migrator, empty on a
+// new database where seaql_migrations does not exist
+async fn recorded_migrations(db: &DatabaseConnection) -> Result<HashSet<String>> {
+    let stmt = Statement::from_string(
+        db.get_database_backend(),
+        "SELECT version FROM seaql_migrations".to_owned(),
+    );
+    match db.query_all(stmt).await {
+        Ok(rows) => Ok(rows
+            .into_iter()
+            .filter_map(|row| row.try_get::<String>("", "version").ok())
+            .collect()),
+        Err(err) if missing_table(&err) => Ok(HashSet::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// stored module versions, empty before module_schemas is created
+async fn stored_versions(db: &DatabaseConnection) -> Result<HashMap<String, i32>> {
+    match module_schemas::Entity::find().all(db).await {
+        Ok(schemas) => Ok(schemas
+            .into_iter()
+            .map(|schema| (schema.module_name, schema.schema_version))
+            .collect()),
+        Err(err) if missing_table(&err) => Ok(HashMap::new()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Every module in dependency order with its current version. Modules without
+// a row yet count the migrations the old flat migrator already applied, so
+// existing databases are picked up where they left off
+async fn module_states<'a>(
+    db: &DatabaseConnection,
+    registry: &'a Registry,
+) -> Result<Vec<ModuleState<'a>>> {
+    let recorded = recorded_migrations(db).await?;
+    let stored = stored_versions(db).await?;
+    let states = registry
+        .schema_order()?
+        .into_iter()
+        .map(|module| {
+            let migrations = module.migrations();
+            let (version, stored) = match stored.get(module.name()) {
+                Some(version) => (*version, true),
+                None => {
+                    let applied = migrations
+                        .iter()
+                        .take_while(|migration| recorded.contains(migration.name()))
+                        .count();
+                    (applied as i32, false)
+                }
+            };
+            ModuleState {
+                module,
+                migrations,
+                version,
+                stored,
+            }
+        })
+        .collect();
+    Ok(states)
+}
+
+async fn set_version(db: &DatabaseConnection, name: &str, version: i32) -> Result<()> {
+    match module_schemas::Entity::find_by_id(name.to_owned())
+        .one(db)
+        .await?
+    {
+        Some(schema) => {
+            let mut schema: module_schemas::ActiveModel = schema.into();
+            schema.schema_version = Set(version);
+            schema.update(db).await?;
+        }
+        None => {
+            module_schemas::ActiveModel {
+                module_name: Set(name.to_owned()),
+                schema_version: Set(version),
+            }
+            .insert(db)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+// A connection for module migrations. It holds a single connection so the
+// BEGIN and COMMIT around each step reach the same session as the statements
+pub async fn connect(url: &str) -> Result<DatabaseConnection> {
+    let mut options = ConnectOptions::new(url.to_owned());
+    options.max_connections(1);
+    Ok(Database::connect(options).await?)
+}
+
+async fn exec_raw(db: &DatabaseConnection, sql: &str) -> Result<()> {
+    db.execute(Statement::from_string(
+        db.get_database_backend(),
+        sql.to_owned(),
+    ))
+    .await?;
+    Ok(())
+}
+
+// Run one module migration and record the version it leaves behind in a
+// single transaction, so a failed step never leaves its tables half applied
+// or out of sync with module_schemas. db must come from connect
+async fn migrate_step(
+    db: &DatabaseConnection,
+    manager: &SchemaManager<'_>,
+    migration: &dyn MigrationTrait,
+    direction: Direction,
+    name: &str,
+    version: i32,
+) -> Result<()> {
+    exec_raw(db, "BEGIN").await?;
+    let res = async {
+        match direction {
+            Direction::Up => migration.up(manager).await?,
+            Direction::Down => migration.down(manager).await?,
+        }
+        set_version(db, name, version).await
+    }
+    .await;
+    match res {
+        Ok(()) => exec_raw(db, "COMMIT").await,
+        Err(err) => {
+            exec_raw(db, "ROLLBACK").await?;
+            Err(err)
+        }
+    }
+}
+
+// A mock database that keeps every statement run against it, since the
+// transaction log can't be read back statement by statement. It has no rows
+// to give back, so migrations that read from the database can't be dry run
+#[derive(Debug)]
+struct Recorder {
+    db: MockDatabase,
+    statements: Arc<Mutex<Vec<Statement>>>,
+}
+
+impl Recorder {
+    fn record(&self, stmt: &Statement) {
+        self.statements
+            .lock()
+            .expect("dry run recorder poisoned")
+            .push(stmt.clone());
+    }
+}
+
+impl MockDatabaseTrait for Recorder {
+    fn execute(
+        &mut self,
+        counter: usize,
+        stmt: Statement,
+    ) -> std::result::Result<ExecResult, DbErr> {
+        self.record(&stmt);
+        self.db.execute(counter, stmt)
+    }
+
+    fn query(
+        &mut self,
+        counter: usize,
+        stmt: Statement,
+    ) -> std::result::Result<Vec<QueryResult>, DbErr> {
+        Err(DbErr::Query(format!(
+            "query #{} needs data a dry run doesn't have: {}",
+            counter, stmt
+        )))
+    }
+
+    fn begin(&mut self) {
+        self.db.begin()
+    }
+
+    fn commit(&mut self) {
+        self.db.commit()
+    }
+
+    fn rollback(&mut self) {
+        self.db.rollback()
+    }
+
+    fn drain_transaction_log(&mut self) -> Vec<Transaction> {
+        self.db.drain_transaction_log()
+    }
+
+    fn get_database_backend(&self) -> DbBackend {
+        self.db.get_database_backend()
+    }
+}
+
+// the statements a migration would run with their values inlined, recorded
+// against a mock connection
+async fn dry_run_sql(migration: &dyn MigrationTrait, direction: Direction) -> Result<Vec<String>> {
+    let results: Vec<MockExecResult> = (0..DRY_RUN_STATEMENTS)
+        .map(|_| MockExecResult {
+            last_insert_id: 0,
+            rows_affected: 0,
+        })
+        .collect();
+    let statements = Arc::new(Mutex::new(Vec::new()));
+    let recorder = Recorder {
+        db: MockDatabase::new(DbBackend::Postgres).append_exec_results(results),
+        statements: statements.clone(),
+    };
+    let db =
+        DatabaseConnection::MockDatabaseConnection(Arc::new(MockDatabaseConnection::new(recorder)));
+    let manager = SchemaManager::new(&db);
+    let res = match direction {
+        Direction::Up => migration.up(&manager).await,
+        Direction::Down => migration.down(&manager).await,
+    };
+    res.map_err(|err| {
+        anyhow!(
+            "can't dry run {}, it reads from the database or runs more than {} statements: {}",
+            migration.name(),
+            DRY_RUN_STATEMENTS,
+            err
+        )
+    })?;
+    let statements = statements.lock().expect("dry run recorder poisoned");
+    Ok(statements.iter().map(|stmt| format!("{};", stmt)).collect())
+}
+
+async fn print_dry_run(
+    owner: &str,
+    migration: &dyn MigrationTrait,
+    direction: Direction,
+) -> Result<()> {
+    let verb = match direction {
+        Direction::Up => "apply",
+        Direction::Down => "roll back",
+    };
+    println!("-- {} {} ({})", verb, migration.name(), owner);
+    for sql in dry_run_sql(migration, direction).await? {
+        println!("{}", sql);
+    }
+    Ok(())
+}
+
+// core migrations not yet recorded in seaql_migrations
+async fn pending_core<M: MigratorTrait>(
+    db: &DatabaseConnection,
+) -> Result<Vec<Box<dyn MigrationTrait>>> {
+    let recorded = recorded_migrations(db).await?;
+    Ok(M::migrations()
+        .into_iter()
+        .filter(|migration| !recorded.contains(migration.name()))
+        .collect())
+}
+
+// Bring every module's tables up to date, dependencies first. All versions
+// are checked before anything runs so a newer schema leaves the database
+// untouched. db must come from connect
+pub async fn migrate_modules(
+    db: &DatabaseConnection,
+    registry: &Registry,
+    dry_run: bool,
+) -> Result<()> {
+    let states = module_states(db, registry).await?;
+    let plan = states
+        .iter()
+        .map(|state| Ok((state, state.pending()?)))
+        .collect::<Result<Vec<_>>>()?;
+
+    if dry_run {
+        for (state, pending) in plan {
+            for idx in pending {
+                let migration = state.migrations[idx].as_ref();
+                print_dry_run(state.module.name(), migration, Direction::Up).await?;
+            }
+        }
+        return Ok(());
+    }
+
+    create_schema_table(db).await?;
+    let manager = SchemaManager::new(db);
+    for (state, pending) in plan {
+        let name = state.module.name();
+        if !state.stored {
+            set_version(db, name, state.version).await?;
+        }
+        for idx in pending {
+            let migration = &state.migrations[idx];
+            log::info!(
+                "applying {} for module {} (version {})",
+                migration.name(),
+                name,
+                idx + 1
+            );
+            migrate_step(
+                db,
+                &manager,
+                migration.as_ref(),
+                Direction::Up,
+                name,
+                idx as i32 + 1,
+            )
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+// number of core migrations not applied yet, checked at startup
+pub async fn pending_core_count<M: MigratorTrait>(db: &DatabaseConnection) -> Result<usize> {
+    Ok(pending_core::<M>(db).await?.len())
+}
+
+// apply pending core migrations, then every module's
+pub async fn up<M: MigratorTrait>(
+    db: &DatabaseConnection,
+    registry: &Registry,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        for migration in pending_core::<M>(db).await? {
+            print_dry_run("core", migration.as_ref(), Direction::Up).await?;
+        }
+    } else {
+        M::up(db, None).await?;
+    }
+    migrate_modules(db, registry, dry_run).await
+}
+
+// Roll back the last steps migrations of one module, or of the core schema
+// when no module is given
+pub async fn down<M: MigratorTrait>(
+    db: &DatabaseConnection,
+    registry: &Registry,
+    module: Option<&str>,
+    steps: u32,
+    dry_run: bool,
+) -> Result<()> {
+    let name = match module {
+        Some(name) => name,
+        None if dry_run => {
+            let recorded = recorded_migrations(db).await?;
+            let applied = M::migrations()
+                .into_iter()
+                .filter(|migration| recorded.contains(migration.name()))
+                .rev()
+                .take(steps as usize);
+            for migration in applied {
+                print_dry_run("core", migration.as_ref(), Direction::Down).await?;
+            }
+            return Ok(());
+        }
+        None => return Ok(M::down(db, Some(steps)).await?),
+    };
+
+    let states = module_states(db, registry).await?;
+    let state = states
+        .iter()
+        .find(|state| state.module.name() == name)
+        .ok_or_else(|| anyhow!("no module named {}", name))?;
+    // never roll back a schema written by a newer build
+    state.pending()?;
+    let version = state.version as usize;
+    let manager = SchemaManager::new(db);
+    for idx in (version.saturating_sub(steps as usize)..version).rev() {
+        let migration = state.migrations[idx].as_ref();
+        if dry_run {
+            print_dry_run(name, migration, Direction::Down).await?;
+        } else {
+            log::info!("rolling back {} for module {}", migration.name(), name);
+            migrate_step(db, &manager, migration, Direction::Down, name, idx as i32).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn status<M: MigratorTrait>(db: &DatabaseConnection, registry: &Registry) -> Result<()> {
+    let recorded = recorded_migrations(db).await?;
+    println!("core:");
+    for migration in M::migrations() {
+        let state = if recorded.contains(migration.name()) {
+            "applied"
+        } else {
+            "pending"
+        };
+        println!("  {} ({})", migration.name(), state);
+    }
+    println!("modules:");
+    for state in module_states(db, registry).await? {
+        let note = match state.pending() {
+            Ok(pending) if pending.is_empty() => "up to date".to_owned(),
+            Ok(pending) => format!("{} pending", pending.len()),
+            Err(_) => "newer than this build".to_owned(),
+        };
+        println!(
+            "  {} version {}/{} ({})",
+            state.module.name(),
+            state.version,
+            state.migrations.len(),
+            note
+        );
+    }
+    Ok(())
+}
+
+// drop every table and migrate from scratch
+pub async fn fresh<M: MigratorTrait>(
+    db: &DatabaseConnection,
+    registry: &Registry,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        println!("-- drop all tables");
+        for migration in M::migrations() {
+            print_dry_run("core", migration.as_ref(), Direction::Up).await?;
+        }
+        for module in registry.schema_order()? {
+            for migration in module.migrations() {
+                print_dry_run(module.name(), migration.as_ref(), Direction::Up).await?;
+            }
+        }
+        Ok(())
+    } else {
+        M::fresh(db).await?;
+        migrate_modules(db, registry, false).await
+    }
+}
@@ -0,0 +1,500 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use lazy_static::lazy_static;
+use redis::Script;
+use sea_orm::sea_query::{OnConflict, Query};
+use sea_orm::{
+    ConnectionTrait, DatabaseConnection, EntityTrait, IdenStatic, Iterable, ModelTrait,
+    PrimaryKeyToColumn,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::config::WriteCacheConfig;
+use crate::context::BotContext;
+use crate::persist::codec;
+use crate::persist::redis::{RedisPool, RedisStr, KEY_WRITE_CACHE};
+use crate::persist::Result;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::persist::core::dialogs;
+
+    #[test]
+    fn latest_test() {
+        let model = |chat_id| dialogs::Model {
+            chat_id,
+            last_activity: chrono::Utc::now().into(),
+        };
+        let entry = |key: &str, chat_id| Entry {
+            id: Uuid::new_v4(),
+            key: key.to_owned(),
+            model: model(chat_id),
+        };
+        let entries = vec![entry("1", 1), entry("2", 2), entry("1", 3)];
+        let rows = latest(&entries);
+        assert!(rows.len() == 2);
+        assert!(rows.iter().any(|m| m.chat_id == 3));
+        assert!(!rows.iter().any(|m| m.chat_id == 1));
+    }
+
+    #[test]
+    fn backoff_test() {
+        assert_eq!(backoff_ms(200, 1), 200);
+        assert_eq!(backoff_ms(200, 3), 800);
+        assert_eq!(backoff_ms(200, 20), MAX_BACKOFF_MS);
+        assert_eq!(backoff_ms(200, 65), MAX_BACKOFF_MS);
+        assert_eq!(backoff_ms(u64::MAX, 2), MAX_BACKOFF_MS);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn dead_letter_test() {
+        static CACHE: WriteCache<dialogs::Entity> = WriteCache::new("dead_letter_test");
+        let redis = RedisPool::new("redis://127.0.0.1/").await.unwrap();
+        let keys = [
+            CACHE.queue_key(),
+            CACHE.inflight_key(),
+            CACHE.rows_key(),
+            CACHE.dead_key(),
+        ];
+        let _: () = redis.pipe(|p| p.del(&keys[..])).await.unwrap();
+
+        let model = dialogs::Model {
+            chat_id: 1,
+            last_activity: chrono::Utc::now().into(),
+        };
+        CACHE.enqueue(&redis, "1", model).await.unwrap();
+        // a mock with no exec results fails every write
+        let db = sea_orm::MockDatabase::new(sea_orm::DbBackend::Postgres).into_connection();
+        let config = WriteCacheConfig {
+            max_retries: 1,
+            retry_backoff_ms: 1,
+            ..Default::default()
+        };
+        assert!(CACHE.flush(&db, &redis, &config).await.is_err());
+
+        let (queued, inflight, dead): (usize, usize, Vec<Vec<u8>>) = redis
+            .pipe(|p| {
+                p.llen(CACHE.queue_key())
+                    .llen(CACHE.inflight_key())
+                    .lrange(CACHE.dead_key(), 0, -1)
+            })
+            .await
+            .unwrap();
+        assert_eq!(queued, 0);
+        assert_eq!(inflight, 0);
+        assert_eq!(dead.len(), 1);
+        let entry: Entry<dialogs::Model> = codec::decode(&dead[0]).unwrap();
+        assert_eq!(entry.model.chat_id, 1);
+        assert!(CACHE.get(&redis, "1").await.unwrap().is_none());
+        // the next flush starts clean instead of replaying the dead batch
+        assert_eq!(CACHE.flush(&db, &redis, &config).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    #[ignore]
+    async fn dead_letter_undecodable_test() {
+        static CACHE: WriteCache<dialogs::Entity> = WriteCache::new("dead_letter_undecodable_test");
+        let redis = RedisPool::new("redis://127.0.0.1/").await.unwrap();
+        let keys = [
+            CACHE.queue_key(),
+            CACHE.inflight_key(),
+            CACHE.rows_key(),
+            CACHE.dead_key(),
+        ];
+        let _: () = redis.pipe(|p| p.del(&keys[..])).await.unwrap();
+
+        let garbage = b"not an entry".to_vec();
+        let _: () = redis
+            .pipe(|p| {
+                p.hset(CACHE.rows_key(), "2", &garbage)
+                    .ignore()
+                    .rpush(CACHE.queue_key(), &garbage)
+                    .ignore()
+            })
+            .await
+            .unwrap();
+        let model = dialogs::Model {
+            chat_id: 1,
+            last_activity: chrono::Utc::now().into(),
+        };
+        CACHE.enqueue(&redis, "1", model).await.unwrap();
+        let db = sea_orm::MockDatabase::new(sea_orm::DbBackend::Postgres)
+            .append_exec_results(vec![sea_orm::MockExecResult {
+                last_insert_id: 0,
+                rows_affected: 1,
+            }])
+            .into_connection();
+        let config = WriteCacheConfig::default();
+        assert_eq!(CACHE.flush(&db, &redis, &config).await.unwrap(), 1);
+
+        let (queued, inflight, dead): (usize, usize, Vec<Vec<u8>>) = redis
+            .pipe(|p| {
+                p.llen(CACHE.queue_key())
+                    .llen(CACHE.inflight_key())
+                    .lrange(CACHE.dead_key(), 0, -1)
+            })
+            .await
+            .unwrap();
+        assert_eq!(queued, 0);
+        assert_eq!(inflight, 0);
+        assert_eq!(dead, vec![garbage]);
+        assert!(CACHE.get(&redis, "1").await.unwrap().is_none());
+        assert!(CACHE.get(&redis, "2").await.unwrap().is_none());
+    }
+}
+
+/*
+ * Write-behind cache for rows written too often to hit postgres every time.
+ * Writes are queued in redis and upserted in batches by a background
+ * flusher. A batch is moved to an in-flight list before it is written and
+ * only removed once postgres accepted it, so a crash or failed write replays
+ * the batch instead of losing it. Writes are upserts, so replays are harmless.
+ * A batch that still fails after every retry is moved to a dead-letter list
+ * so one bad row can't wedge the queue. Entries that can't be decoded go
+ * there straight away.
+ *
+ * Until a write is flushed it can be read back with WriteCache::get
+ */
+
+lazy_static! {
+    // Claim the next batch. Entries still in flight from a failed flush are
+    // retried before anything new is taken from the queue
+    static ref CLAIM: Script = Script::new(
+        r"
+        local pending = redis.call('LRANGE', KEYS[1], 0, -1)
+        if #pending > 0 then
+            return pending
+        end
+        local items = redis.call('LRANGE', KEYS[2], 0, ARGV[1] - 1)
+        if #items > 0 then
+            redis.call('LTRIM', KEYS[2], #items, -1)
+            redis.call('RPUSH', KEYS[1], unpack(items))
+        end
+        return items
+        "
+    );
+
+    // Drop a written batch. Pending reads are cleared unless a newer write
+    // replaced them in the meantime
+    static ref RELEASE: Script = Script::new(
+        r"
+        redis.call('DEL', KEYS[1])
+        for i = 1, #ARGV, 2 do
+            if redis.call('HGET', KEYS[2], ARGV[i]) == ARGV[i + 1] then
+                redis.call('HDEL', KEYS[2], ARGV[i])
+            end
+        end
+        return 0
+        "
+    );
+
+    // Move a batch that keeps failing to the dead-letter list, clearing its
+    // pending reads the same way RELEASE does
+    static ref DEAD_LETTER: Script = Script::new(
+        r"
+        local items = redis.call('LRANGE', KEYS[1], 0, -1)
+        if #items > 0 then
+            redis.call('RPUSH', KEYS[3], unpack(items))
+        end
+        redis.call('DEL', KEYS[1])
+        for i = 1, #ARGV, 2 do
+            if redis.call('HGET', KEYS[2], ARGV[i]) == ARGV[i + 1] then
+                redis.call('HDEL', KEYS[2], ARGV[i])
+            end
+        end
+        return #items
+        "
+    );
+
+    // Move entries that can't be decoded to the dead-letter list. Their row
+    // key is unknown, so pending reads are cleared by comparing the bytes
+    static ref DEAD_LETTER_UNDECODABLE: Script = Script::new(
+        r"
+        local rows = redis.call('HGETALL', KEYS[2])
+        for _, item in ipairs(ARGV) do
+            redis.call('LREM', KEYS[1], 1, item)
+            redis.call('RPUSH', KEYS[3], item)
+            for i = 1, #rows, 2 do
+                if rows[i + 1] == item then
+                    redis.call('HDEL', KEYS[2], rows[i])
+                end
+            end
+        end
+        return #ARGV
+        "
+    );
+}
+
+// retries never wait longer than this, however often they doubled
+const MAX_BACKOFF_MS: u64 = 60_000;
+
+// the wait before a retry, doubling with every attempt starting at 1
+fn backoff_ms(base: u64, attempt: u32) -> u64 {
+    let factor = 1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX);
+    base.saturating_mul(factor).min(MAX_BACKOFF_MS)
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<M> {
+    // tells apart identical writes to the same row
+    id: Uuid,
+    key: String,
+    model: M,
+}
+
+// the last write to each row in a batch. Postgres refuses to upsert the same
+// row twice in one statement
+fn latest<M>(entries: &[Entry<M>]) -> Vec<&M> {
+    let mut rows = HashMap::new();
+    for entry in entries {
+        rows.insert(entry.key.as_str(), &entry.model);
+    }
+    rows.into_values().collect()
+}
+
+// a write cache that can be flushed without knowing its entity
+#[async_trait]
+pub trait Flush: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    async fn flush(
+        &self,
+        db: &DatabaseConnection,
+        redis: &RedisPool,
+        config: &WriteCacheConfig,
+    ) -> Result<usize>;
+}
+
+// Queued writes for one entity. Rows are identified by a key chosen by the
+// caller, usually the primary key
+pub struct WriteCache<E> {
+    name: &'static str,
+    phantom: PhantomData<fn() -> E>,
+}
+
+impl<E> WriteCache<E>
+where
+    E: EntityTrait,
+    E::Model: Serialize + DeserializeOwned + Send + Sync,
+{
+    pub const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            phantom: PhantomData,
+        }
+    }
+
+    // writes waiting for the next flush
+    fn queue_key(&self) -> String {
+        format!("{}:queue:{}", KEY_WRITE_CACHE, self.name)
+    }
+
+    // the batch being written right now
+    fn inflight_key(&self) -> String {
+        format!("{}:inflight:{}", KEY_WRITE_CACHE, self.name)
+    }
+
+    // the latest unflushed write to each row, read back by get
+    fn rows_key(&self) -> String {
+        format!("{}:rows:{}", KEY_WRITE_CACHE, self.name)
+    }
+
+    // batches given up on, kept for inspection or a manual replay
+    fn dead_key(&self) -> String {
+        format!("{}:dead:{}", KEY_WRITE_CACHE, self.name)
+    }
+
+    // queue a row to be inserted or updated on the next flush
+    pub async fn enqueue(&self, redis: &RedisPool, key: &str, model: E::Model) -> Result<()> {
+        let entry = redis.encode(&Entry {
+            id: Uuid::new_v4(),
+            key: key.to_owned(),
+            model,
+        })?;
+        let queue = self.queue_key();
+        let rows = self.rows_key();
+        let _: () = redis
+            .pipe(|p| {
+                p.atomic();
+                p.hset(&rows, key, &entry).ignore();
+                p.rpush(&queue, &entry).ignore()
+            })
+            .await?;
+        Ok(())
+    }
+
+    // the latest unflushed write to a row. Callers fall back to the
+    // database when this is None
+    pub async fn get(&self, redis: &RedisPool, key: &str) -> Result<Option<E::Model>> {
+        let rows = self.rows_key();
+        let (entry,): (Option<RedisStr>,) = redis.pipe(|p| p.hget(&rows, key)).await?;
+        entry
+            .map(|entry| Ok(entry.get::<Entry<E::Model>>()?.model))
+            .transpose()
+    }
+
+    async fn write(&self, db: &DatabaseConnection, entries: &[Entry<E::Model>]) -> Result<()> {
+        let columns: Vec<E::Column> = E::Column::iter().collect();
+        let keys: Vec<E::Column> = E::PrimaryKey::iter().map(|key| key.into_column()).collect();
+        let mut update: Vec<E::Column> = columns
+            .iter()
+            .filter(|col| !keys.iter().any(|key| key.as_str() == col.as_str()))
+            .copied()
+            .collect();
+        // tables made only of key columns still need something to update
+        if update.is_empty() {
+            update = keys.clone();
+        }
+
+        let mut insert = Query::insert();
+        insert.into_table(E::default()).columns(columns.clone());
+        for model in latest(entries) {
+            insert.values_panic(columns.iter().map(|col| model.get(*col)));
+        }
+        insert.on_conflict(OnConflict::columns(keys).update_columns(update).to_owned());
+        db.execute(db.get_database_backend().build(&insert)).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<E> Flush for WriteCache<E>
+where
+    E: EntityTrait,
+    E::Model: Serialize + DeserializeOwned + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // write queued rows until the queue is empty, returning how many were
+    // written. A batch that fails max_retries times is dead-lettered, as is
+    // any entry that can't be decoded
+    async fn flush(
+        &self,
+        db: &DatabaseConnection,
+        redis: &RedisPool,
+        config: &WriteCacheConfig,
+    ) -> Result<usize> {
+        let queue = self.queue_key();
+        let inflight = self.inflight_key();
+        let rows = self.rows_key();
+        let mut flushed = 0;
+        loop {
+            let batch: Vec<Vec<u8>> = {
+                let mut conn = redis.conn().await?;
+                CLAIM
+                    .key(&inflight)
+                    .key(&queue)
+                    .arg(config.batch_size)
+                    .invoke_async(&mut *conn)
+                    .await?
+            };
+            if batch.is_empty() {
+                return Ok(flushed);
+            }
+            // an entry that can't be decoded will never write, so it is
+            // dead-lettered right away instead of blocking the queue
+            let mut entries: Vec<Entry<E::Model>> = Vec::with_capacity(batch.len());
+            let mut decoded = Vec::with_capacity(batch.len());
+            let mut undecodable = Vec::new();
+            for bytes in batch {
+                match codec::decode(&bytes) {
+                    Ok(entry) => {
+                        entries.push(entry);
+                        decoded.push(bytes);
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "write cache {} can't decode an entry, moving it to {}: {}",
+                            self.name,
+                            self.dead_key(),
+                            err
+                        );
+                        undecodable.push(bytes);
+                    }
+                }
+            }
+            let batch = decoded;
+            if !undecodable.is_empty() {
+                let mut dead = DEAD_LETTER_UNDECODABLE.key(&inflight);
+                dead.key(&rows).key(self.dead_key());
+                for bytes in undecodable.iter() {
+                    dead.arg(bytes.as_slice());
+                }
+                let mut conn = redis.conn().await?;
+                let _: i64 = dead.invoke_async(&mut *conn).await?;
+            }
+            if entries.is_empty() {
+                continue;
+            }
+
+            let mut attempt = 0;
+            while let Err(err) = self.write(db, &entries).await {
+                if attempt >= config.max_retries {
+                    let keys: Vec<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+                    log::error!(
+                        "write cache {} gave up after {} retries, moving {} rows to {}: {:?}: {}",
+                        self.name,
+                        attempt,
+                        entries.len(),
+                        self.dead_key(),
+                        keys,
+                        err
+                    );
+                    let mut dead = DEAD_LETTER.key(&inflight);
+                    dead.key(&rows).key(self.dead_key());
+                    for (entry, bytes) in entries.iter().zip(batch.iter()) {
+                        dead.arg(&entry.key).arg(bytes.as_slice());
+                    }
+                    let mut conn = redis.conn().await?;
+                    let _: i64 = dead.invoke_async(&mut *conn).await?;
+                    return Err(err);
+                }
+                attempt += 1;
+                log::warn!(
+                    "write cache {} failed to flush, retry {}: {}",
+                    self.name,
+                    attempt,
+                    err
+                );
+                let backoff = backoff_ms(config.retry_backoff_ms, attempt);
+                tokio::time::sleep(Duration::from_millis(backoff)).await;
+            }
+
+            let mut release = RELEASE.key(&inflight);
+            release.key(&rows);
+            for (entry, bytes) in entries.iter().zip(batch.iter()) {
+                release.arg(&entry.key).arg(bytes.as_slice());
+            }
+            let mut conn = redis.conn().await?;
+            let _: i64 = release.invoke_async(&mut *conn).await?;
+            flushed += entries.len();
+        }
+    }
+}
+
+// flush every cache once, logging failures so one cache can't block the rest
+pub async fn flush_all(ctx: &BotContext, caches: &[&'static dyn Flush]) {
+    let config = &ctx.config.write_cache;
+    for cache in caches {
+        if let Err(err) = cache.flush(&ctx.db, &ctx.redis, config).await {
+            log::error!("failed to flush write cache {}: {}", cache.name(), err);
+        }
+    }
+}
+
+// flush on an interval for as long as the bot runs
+pub async fn flusher(ctx: BotContext, caches: Vec<&'static dyn Flush>) {
+    let interval = Duration::from_millis(ctx.config.write_cache.flush_interval_ms);
+    loop {
+        tokio::time::sleep(interval).await;
+        flush_all(&ctx, &caches).await;
+    }
+}
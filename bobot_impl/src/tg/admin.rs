@@ -0,0 +1,42 @@
+use anyhow::anyhow;
+use teloxide::types::{Chat, Message, User};
+
+use crate::context::BotContext;
+use crate::persist::Result;
+use crate::tg::api::TgApi;
+use crate::tg::command::Arg;
+use crate::util::error::BotError;
+
+// private chats have no admins, so the only user present is treated as one
+pub(crate) async fn is_admin(ctx: &BotContext, chat: &Chat, user: &User) -> Result<bool> {
+    if chat.is_private() {
+        Ok(true)
+    } else {
+        Ok(ctx.tg.is_chat_admin(chat.id, user.id).await?)
+    }
+}
+
+pub(crate) async fn require_admin(ctx: &BotContext, message: &Message) -> Result<()> {
+    let user = message
+        .from()
+        .ok_or_else(|| BotError::new("message has no sender"))?;
+    if is_admin(ctx, &message.chat, user).await? {
+        Ok(())
+    } else {
+        Err(anyhow!(BotError::new(
+            "You must be a chat admin to do this"
+        )))
+    }
+}
+
+// get the user a command is targeting, either from the replied-to message
+// or the first argument after the command
+pub(crate) fn target_user(message: &Message, args: &[Arg]) -> Option<i64> {
+    if let Some(user) = message.reply_to_message().and_then(|m| m.from()) {
+        Some(user.id)
+    } else if let Some(Arg::Arg(id)) = args.get(1) {
+        id.parse().ok()
+    } else {
+        None
+    }
+}
@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use teloxide::payloads::{
+    AnswerInlineQuerySetters, SendDocumentSetters, SendMessageSetters, UnbanChatMemberSetters,
+};
+use teloxide::prelude::Requester;
+use teloxide::types::{InlineQueryResult, InputFile, Message, StickerSet};
+
+use super::client::TgClient;
+use super::Result;
+
+// The subset of the telegram bot api used by modules. Handlers only talk to
+// telegram through this so they can be run against a mock in tests
+#[async_trait]
+pub trait TgApi: Send + Sync {
+    async fn send_message(&self, chat: i64, text: String, reply_to: Option<i32>) -> Result<()>;
+
+    async fn send_sticker(&self, chat: i64, file_id: String) -> Result<()>;
+
+    async fn send_document(
+        &self,
+        chat: i64,
+        file_name: String,
+        data: Vec<u8>,
+        reply_to: Option<i32>,
+    ) -> Result<()>;
+
+    async fn answer_inline_query(
+        &self,
+        query_id: String,
+        results: Vec<InlineQueryResult>,
+        next_offset: String,
+    ) -> Result<()>;
+
+    async fn is_chat_admin(&self, chat: i64, user: i64) -> Result<bool>;
+
+    async fn ban_chat_member(&self, chat: i64, user: i64) -> Result<()>;
+
+    // only lifts existing bans, never kicks members
+    async fn unban_chat_member(&self, chat: i64, user: i64) -> Result<()>;
+
+    async fn get_sticker_set(&self, name: String) -> Result<StickerSet>;
+
+    // fetch the contents of a file uploaded to telegram into memory
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>>;
+}
+
+impl<'a> dyn TgApi + 'a {
+    pub async fn send(&self, chat: i64, text: impl Into<String>) -> Result<()> {
+        self.send_message(chat, text.into(), None).await
+    }
+
+    // send a message to the chat of another message, as a reply to it
+    pub async fn reply(&self, message: &Message, text: impl Into<String>) -> Result<()> {
+        self.send_message(message.chat.id, text.into(), Some(message.id))
+            .await
+    }
+}
+
+#[async_trait]
+impl TgApi for TgClient {
+    async fn send_message(&self, chat: i64, text: String, reply_to: Option<i32>) -> Result<()> {
+        let req = self.client().send_message(chat, text);
+        if let Some(reply_to) = reply_to {
+            req.reply_to_message_id(reply_to)
+                .allow_sending_without_reply(true)
+                .await?;
+        } else {
+            req.await?;
+        }
+        Ok(())
+    }
+
+    async fn send_sticker(&self, chat: i64, file_id: String) -> Result<()> {
+        self.client()
+            .send_sticker(chat, InputFile::file_id(file_id))
+            .await?;
+        Ok(())
+    }
+
+    async fn send_document(
+        &self,
+        chat: i64,
+        file_name: String,
+        data: Vec<u8>,
+        reply_to: Option<i32>,
+    ) -> Result<()> {
+        let req = self
+            .client()
+            .send_document(chat, InputFile::memory(data).file_name(file_name));
+        if let Some(reply_to) = reply_to {
+            req.reply_to_message_id(reply_to).await?;
+        } else {
+            req.await?;
+        }
+        Ok(())
+    }
+
+    async fn answer_inline_query(
+        &self,
+        query_id: String,
+        results: Vec<InlineQueryResult>,
+        next_offset: String,
+    ) -> Result<()> {
+        self.client()
+            .answer_inline_query(query_id, results)
+            .next_offset(next_offset)
+            .await?;
+        Ok(())
+    }
+
+    async fn is_chat_admin(&self, chat: i64, user: i64) -> Result<bool> {
+        let member = self.client().get_chat_member(chat, user).await?;
+        Ok(member.kind.is_privileged())
+    }
+
+    async fn ban_chat_member(&self, chat: i64, user: i64) -> Result<()> {
+        self.client().ban_chat_member(chat, user).await?;
+        Ok(())
+    }
+
+    async fn unban_chat_member(&self, chat: i64, user: i64) -> Result<()> {
+        self.client()
+            .unban_chat_member(chat, user)
+            .only_if_banned(true)
+            .await?;
+        Ok(())
+    }
+
+    async fn get_sticker_set(&self, name: String) -> Result<StickerSet> {
+        let set = self.client().get_sticker_set(name).await?;
+        Ok(set)
+    }
+
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        TgClient::download_file(self, file_id).await
+    }
+}
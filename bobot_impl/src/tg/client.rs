@@ -1,12 +1,17 @@
 use teloxide::{
     adaptors::AutoSend,
     dispatching::update_listeners::{polling_default, AsUpdateStream},
+    net::Download,
+    prelude::Requester,
     Bot,
 };
 
 use futures::StreamExt;
 
+use super::module::Registry;
 use super::Result;
+use crate::context::BotContext;
+use std::sync::Arc;
 
 pub struct TgClient {
     pub client: AutoSend<Bot>,
@@ -22,23 +27,35 @@ impl TgClient {
         }
     }
 
-    pub async fn run(&self) -> Result<()> {
+    pub async fn run(&self, ctx: BotContext, registry: Arc<Registry>) -> Result<()> {
         polling_default(self.client.clone())
             .await
             .as_stream()
-            .for_each_concurrent(None, |update| async move {
-                tokio::spawn(async move {
-                    if let Ok(update) = update {
-                        crate::modules::process_updates(update).await;
-                    } else {
-                        log::debug!("failed to process update");
-                    }
-                });
+            .for_each_concurrent(None, |update| {
+                let ctx = ctx.clone();
+                let registry = registry.clone();
+                async move {
+                    tokio::spawn(async move {
+                        if let Ok(update) = update {
+                            registry.process_update(&ctx, &update).await;
+                        } else {
+                            log::debug!("failed to process update");
+                        }
+                    });
+                }
             })
             .await;
         Ok(())
     }
 
+    // fetch the contents of a file uploaded to telegram into memory
+    pub async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        let file = self.client.get_file(file_id).await?;
+        let mut buf = Vec::<u8>::new();
+        self.client.download_file(&file.file_path, &mut buf).await?;
+        Ok(buf)
+    }
+
     pub fn client<'a>(&'a self) -> &'a AutoSend<Bot> {
         &self.client
     }
@@ -26,6 +26,33 @@ mod test {
         assert!(quotes == 1);
         assert!(words == 3);
     }
+
+    #[test]
+    fn quote_order_test() {
+        let parsed = parse_cmd(r#"/rename id "funny little cat""#).unwrap();
+        match parsed.get(2) {
+            Some(Arg::Quote(words)) => assert_eq!(words, &["funny", "little", "cat"]),
+            _ => panic!("expected a quoted argument"),
+        }
+        assert_eq!(join_args(&parsed[2..]), "funny little cat");
+    }
+
+    #[test]
+    fn rest_args_test() {
+        let text = "/schedule 2099-01-01T00:00 first line\n  second   line ";
+        assert_eq!(rest_args(text, 2), "first line\n  second   line");
+        assert_eq!(rest_args(r#"/fban 123 "spam bot""#, 2), "spam bot");
+        assert_eq!(
+            rest_args(r#"/fban "a b" 123 spam "bot" here"#, 3),
+            r#"spam "bot" here"#
+        );
+        assert_eq!(
+            rest_args(r#"/newfed "quoted" "twice""#, 1),
+            r#""quoted" "twice""#
+        );
+        assert_eq!(rest_args("/fban 123", 2), "");
+        assert_eq!(rest_args("/fban", 2), "");
+    }
 }
 
 #[derive(Debug, Error)]
@@ -58,7 +85,7 @@ pomelo! {
     words ::= words(mut L)  quote(Q) { L.push(crate::tg::command::Arg::Quote(Q)); L }
     words ::= quote(Q) { vec![crate::tg::command::Arg::Quote(Q)] }
     quoteinner ::= Word(W) { vec![W] }
-    quoteinner ::= Word(W) quoteinner(mut L) { L.push(W); L }
+    quoteinner ::= quoteinner(mut L) Word(W) { L.push(W); L }
     quote ::= QuoteMark quoteinner(Q) QuoteMark { Q }
 
 }
@@ -104,6 +131,46 @@ pub(crate) fn parse_cmd<R: ToString>(cmd: R) -> Result<Vec<Arg>> {
     Ok(res)
 }
 
+// join parsed arguments back into a single space separated string
+pub(crate) fn join_args(args: &[Arg]) -> String {
+    args.iter()
+        .map(|a| match a {
+            Arg::Arg(s) => s.to_owned(),
+            Arg::Quote(q) => q.join(" "),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// The original text after the first skip arguments, for free-form text like
+// names, reasons or announcements. Spacing and newlines are kept as sent, and
+// quotes are only dropped when they wrap all of it
+pub(crate) fn rest_args(text: &str, skip: usize) -> String {
+    let mut args = 0;
+    let mut quoted = false;
+    let mut start = text.len();
+    for token in TOKENS.find_iter(text) {
+        if args == skip {
+            start = token.start();
+            break;
+        }
+        if token.as_str() == "\"" {
+            quoted = !quoted;
+            if !quoted {
+                args += 1;
+            }
+        } else if !quoted {
+            args += 1;
+        }
+    }
+    let rest = text[start..].trim();
+    rest.strip_prefix('"')
+        .and_then(|inner| inner.strip_suffix('"'))
+        .filter(|inner| !inner.contains('"'))
+        .unwrap_or(rest)
+        .to_owned()
+}
+
 #[allow(dead_code)]
 pub(crate) fn parse_cmd_iter<R: ToString>(cmd: R) -> Result<impl Iterator<Item = Arg>> {
     let iter = parse_cmd(cmd)?.into_iter();
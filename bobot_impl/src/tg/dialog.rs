@@ -1,14 +1,19 @@
 use anyhow::anyhow;
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
-use redis::AsyncCommands;
-use serde::{Deserialize, Serialize};
+use sea_orm::entity::prelude::*;
+use sea_orm::{ConnectionTrait, Statement};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::collections::HashMap;
 use std::str::FromStr;
 use teloxide::types::{Chat, Message};
 use uuid::Uuid;
 
-use crate::persist::redis::RedisStr;
-use crate::statics::REDIS;
+use crate::context::BotContext;
+use crate::persist::codec::{self, Codec};
+use crate::persist::core::{chat_members, dialogs};
+use crate::persist::redis::{from_hash_fields, hash_fields, RedisPool, RedisStr};
+use crate::persist::writecache::WriteCache;
 use crate::util::error::BotError;
 use log::info;
 
@@ -16,11 +21,170 @@ use crate::persist::Result;
 #[cfg(test)]
 mod tests {
 
-    use super::Conversation;
+    use super::*;
+    use crate::tg::mock::{message_update, mock_context};
+
+    fn conversation(message: &Message) -> Result<Conversation> {
+        let user = message.from().unwrap().id;
+        let mut conversation = Conversation::new("/test".to_owned(), "start".to_owned(), 1, user)?;
+        let start = conversation.get_start()?.state_id;
+        let next = conversation.add_state("next");
+        conversation.add_transition(start, next, "go");
+        Ok(conversation)
+    }
+
+    #[tokio::test]
+    async fn transition_test() {
+        let (ctx, _) = mock_context().await;
+        let update = message_update(1, 2, "hi");
+        let message = match update.kind {
+            teloxide::types::UpdateKind::Message(ref message) => message,
+            _ => unreachable!(),
+        };
+        assert!(get_conversation(&ctx, message).await.unwrap().is_none());
+
+        let created = replace_conversation(&ctx, message, conversation)
+            .await
+            .unwrap();
+        assert_eq!(created.get_current_text(&ctx).await.unwrap(), "start");
+        assert_eq!(created.transition(&ctx, "go").await.unwrap(), "next");
+        assert!(created.transition(&ctx, "stop").await.is_err());
+
+        // the state outlives the conversation value it was read through
+        let stored = get_conversation(&ctx, message).await.unwrap().unwrap();
+        assert_eq!(stored.get_current_text(&ctx).await.unwrap(), "next");
+        stored.reset(&ctx).await.unwrap();
+        let stored = get_conversation(&ctx, message).await.unwrap().unwrap();
+        assert_eq!(stored.get_current_text(&ctx).await.unwrap(), "start");
+
+        drop_converstaion(&ctx, message).await.unwrap();
+        assert!(get_conversation(&ctx, message).await.unwrap().is_none());
+    }
 }
 
 pub const TYPE_DIALOG: &str = "DialogDb";
 
+// Where conversations keep their state between messages. Backed by redis,
+// tests swap in an in-memory store so they run without a server
+#[async_trait]
+pub trait ConversationStore: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    // all values are written at once, so a conversation never lacks its state
+    async fn set(&self, vals: Vec<(String, Vec<u8>)>) -> Result<()>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    // scratch data is kept as a hash of encoded fields, see RedisPool::set_hash
+    async fn set_fields(
+        &self,
+        key: &str,
+        fields: Vec<(String, RedisStr)>,
+        ttl: usize,
+    ) -> Result<()>;
+
+    async fn update_fields(&self, key: &str, fields: Vec<(String, RedisStr)>) -> Result<()>;
+
+    async fn get_fields(&self, key: &str) -> Result<HashMap<String, RedisStr>>;
+
+    // codec stored values are encoded with
+    fn codec(&self) -> Codec;
+}
+
+// typed access to scratch data, mirroring the RedisPool hash helpers
+impl dyn ConversationStore {
+    pub async fn set_hash<T: Serialize>(&self, key: &str, val: &T, ttl: usize) -> Result<()> {
+        self.set_fields(key, hash_fields(val, self.codec())?, ttl)
+            .await
+    }
+
+    pub async fn update_hash<T: Serialize>(&self, key: &str, val: &T) -> Result<()> {
+        self.update_fields(key, hash_fields(val, self.codec())?)
+            .await
+    }
+
+    pub async fn get_hash<R: DeserializeOwned>(&self, key: &str) -> Result<Option<R>> {
+        from_hash_fields(self.get_fields(key).await?)
+    }
+
+    pub async fn hash_field<R: DeserializeOwned>(
+        &self,
+        key: &str,
+        field: &str,
+    ) -> Result<Option<R>> {
+        self.get_fields(key)
+            .await?
+            .get(field)
+            .map(|val| val.get())
+            .transpose()
+    }
+}
+
+pub struct RedisConversations(RedisPool);
+
+impl RedisConversations {
+    pub fn new(redis: RedisPool) -> Self {
+        Self(redis)
+    }
+}
+
+#[async_trait]
+impl ConversationStore for RedisConversations {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let (val,): (Option<Vec<u8>>,) = self.0.pipe(|p| p.get(key)).await?;
+        Ok(val)
+    }
+
+    async fn set(&self, vals: Vec<(String, Vec<u8>)>) -> Result<()> {
+        let _: () = self
+            .0
+            .pipe(|p| {
+                p.atomic();
+                for (key, val) in vals.iter() {
+                    p.set(key, val.as_slice()).ignore();
+                }
+                p
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        let _: () = self.0.pipe(|p| p.del(key)).await?;
+        Ok(())
+    }
+
+    async fn set_fields(
+        &self,
+        key: &str,
+        fields: Vec<(String, RedisStr)>,
+        ttl: usize,
+    ) -> Result<()> {
+        self.0.set_fields(key, fields, ttl).await
+    }
+
+    async fn update_fields(&self, key: &str, fields: Vec<(String, RedisStr)>) -> Result<()> {
+        self.0.update_fields(key, fields).await
+    }
+
+    async fn get_fields(&self, key: &str) -> Result<HashMap<String, RedisStr>> {
+        self.0.get_fields(key).await
+    }
+
+    fn codec(&self) -> Codec {
+        self.0.codec()
+    }
+}
+
+// redis keys
+const KEY_SEEN_MEMBER: &str = "seenmember";
+
+// how long to wait before writing the same chat member to the database again
+const SEEN_MEMBER_TTL_SECS: usize = 60 * 60;
+
+// last activity updates for known chats, flushed in the background
+pub(crate) static DIALOG_WRITES: WriteCache<dialogs::Entity> = WriteCache::new("dialogs");
+
 #[inline(always)]
 fn get_conversation_key_prefix(chat: i64, user: i64, prefix: &str) -> String {
     format!("{}:{}:{}", prefix, chat, user)
@@ -166,7 +330,7 @@ impl Conversation {
         }
     }
 
-    pub async fn transition<'a, S>(&'a self, next: S) -> Result<&'a str>
+    pub async fn transition<'a, S>(&'a self, ctx: &BotContext, next: S) -> Result<&'a str>
     where
         S: Into<String>,
     {
@@ -179,26 +343,27 @@ impl Conversation {
         } else {
             Err(BotError::new("invalid choice"))
         }?;
-        self.write_key(current.state_id).await?;
+        self.write_key(ctx, current.state_id).await?;
         Ok(&current.content)
     }
 
-    pub async fn write_key(&self, new: Uuid) -> Result<()> {
-        REDIS
-            .pipe(|p| p.set(&self.rediskey.to_string(), new.to_string()))
-            .await?;
-        Ok(())
+    pub async fn write_key(&self, ctx: &BotContext, new: Uuid) -> Result<()> {
+        ctx.conversations
+            .set(vec![(self.rediskey.clone(), new.to_string().into_bytes())])
+            .await
     }
 
-    pub async fn write_self(&self) -> Result<()> {
-        REDIS
-            .pipe(|p| p.set(&self.rediskey.to_string(), self.start.to_string()))
-            .await
+    pub async fn write_self(&self, ctx: &BotContext) -> Result<()> {
+        self.write_key(ctx, self.start).await
     }
 
-    pub async fn get_current<'a>(&'a self) -> Result<&'a FSMState> {
-        let current: (String,) = REDIS.pipe(|p| p.get(&self.rediskey.to_string())).await?;
-        let current = Uuid::from_str(&current.0)?;
+    pub async fn get_current<'a>(&'a self, ctx: &BotContext) -> Result<&'a FSMState> {
+        let current = ctx
+            .conversations
+            .get(&self.rediskey)
+            .await?
+            .ok_or_else(|| BotError::new("conversation has no state"))?;
+        let current = Uuid::from_str(std::str::from_utf8(&current)?)?;
         if let Some(current) = self.states.get(&current) {
             Ok(current)
         } else {
@@ -206,81 +371,79 @@ impl Conversation {
         }
     }
 
-    pub async fn get_current_text(&self) -> Result<String> {
-        let c = self.get_current().await?.content.to_string();
+    pub async fn get_current_text(&self, ctx: &BotContext) -> Result<String> {
+        let c = self.get_current(ctx).await?.content.to_string();
         Ok(c)
     }
 
-    pub async fn reset(self) -> Result<()> {
-        self.write_key(self.start).await
+    pub async fn reset(self, ctx: &BotContext) -> Result<()> {
+        self.write_key(ctx, self.start).await
     }
 }
 
-pub(crate) async fn get_conversation(message: &Message) -> Result<Option<Conversation>> {
+pub(crate) async fn get_conversation(
+    ctx: &BotContext,
+    message: &Message,
+) -> Result<Option<Conversation>> {
     let key = get_conversation_key_message(&message)?;
-    let rstr = REDIS
-        .query(|mut c| async move {
-            if c.exists(&key).await? {
-                let conv: RedisStr = c.get(&key).await?;
-                Ok(Some(conv))
-            } else {
-                Ok(None)
-            }
-        })
-        .await?;
-
-    let res = if let Some(rstr) = rstr {
-        Some(rstr.get::<Conversation>()?)
-    } else {
-        None
-    };
-
-    Ok(res)
+    ctx.conversations
+        .get(&key)
+        .await?
+        .map(|conversation| codec::decode(&conversation))
+        .transpose()
 }
 
-pub(crate) async fn drop_converstaion(message: &Message) -> Result<()> {
+pub(crate) async fn drop_converstaion(ctx: &BotContext, message: &Message) -> Result<()> {
     let key = get_conversation_key_message(message)?;
-    REDIS.pipe(|p| p.del(&key)).await?;
-    Ok(())
+    ctx.conversations.delete(&key).await
+}
+
+// store a conversation along with its start state
+async fn write_conversation(
+    ctx: &BotContext,
+    key: String,
+    conversation: &Conversation,
+) -> Result<()> {
+    let encoded = codec::encode(conversation, ctx.conversations.codec())?;
+    ctx.conversations
+        .set(vec![
+            (key, encoded),
+            (
+                conversation.rediskey.clone(),
+                conversation.start.to_string().into_bytes(),
+            ),
+        ])
+        .await
 }
 
-pub(crate) async fn replace_conversation<F>(message: &Message, create: F) -> Result<Conversation>
+pub(crate) async fn replace_conversation<F>(
+    ctx: &BotContext,
+    message: &Message,
+    create: F,
+) -> Result<Conversation>
 where
     F: FnOnce(&Message) -> Result<Conversation>,
 {
     let key = get_conversation_key_message(message)?;
     let conversation = create(message)?;
-    let conversationstr = RedisStr::new(&conversation)?;
-    let _: () = REDIS
-        .pipe(|p| {
-            p.atomic();
-            p.set(&key, conversationstr);
-            p.set(&conversation.rediskey, conversation.start.to_string())
-        })
-        .await?;
+    write_conversation(ctx, key, &conversation).await?;
     Ok(conversation)
 }
 
 pub(crate) async fn get_or_create_conversation<F>(
+    ctx: &BotContext,
     message: &Message,
     create: F,
 ) -> Result<Conversation>
 where
     F: FnOnce(&Message) -> Result<Conversation>,
 {
-    if let Some(conversation) = get_conversation(message).await? {
+    if let Some(conversation) = get_conversation(ctx, message).await? {
         Ok(conversation)
     } else {
         let res = create(message)?;
-        let s = RedisStr::new(&res)?;
         let key = get_conversation_key_message(&message)?;
-        REDIS
-            .pipe(|p| {
-                p.atomic();
-                p.set(&key, s);
-                p.set(&res.rediskey, res.start.to_string())
-            })
-            .await?;
+        write_conversation(ctx, key, &res).await?;
         Ok(res)
     }
 }
@@ -293,3 +456,69 @@ impl Dialog {
         }
     }
 }
+
+// record that the sender of a message is a member of its chat. Writes are
+// throttled per chat member since this is called for every message
+pub(crate) async fn record_chat_member(ctx: &BotContext, message: &Message) -> Result<()> {
+    let user = if let Some(user) = message.from() {
+        user.id
+    } else {
+        return Ok(());
+    };
+    let chat = message.chat.id;
+    let key = format!("{}:{}:{}", KEY_SEEN_MEMBER, chat, user);
+    let (seen,): (bool,) = ctx.redis.pipe(|p| p.exists(&key)).await?;
+    if seen {
+        return Ok(());
+    }
+
+    let db = &*ctx.db;
+    let dialog = Dialog::new(&message.chat);
+    let dialog_key = chat.to_string();
+    let exists = DIALOG_WRITES.get(&ctx.redis, &dialog_key).await?.is_some()
+        || dialogs::Entity::find_by_id(chat).one(db).await?.is_some();
+    if exists {
+        let dialog = dialogs::Model {
+            chat_id: dialog.chat_id,
+            last_activity: dialog.last_activity.into(),
+        };
+        DIALOG_WRITES
+            .enqueue(&ctx.redis, &dialog_key, dialog)
+            .await?;
+    } else {
+        // written right away since chat_members references it. Another
+        // message from the same chat may have inserted it meanwhile
+        let last_activity: DateTimeWithTimeZone = dialog.last_activity.into();
+        db.execute(Statement::from_sql_and_values(
+            db.get_database_backend(),
+            r#"INSERT INTO "dialogs" ("chat_id", "last_activity") VALUES ($1, $2)
+               ON CONFLICT ("chat_id") DO NOTHING"#,
+            vec![chat.into(), last_activity.into()],
+        ))
+        .await?;
+    }
+
+    db.execute(Statement::from_sql_and_values(
+        db.get_database_backend(),
+        r#"INSERT INTO "chat_members" ("chat_id", "user_id") VALUES ($1, $2)
+           ON CONFLICT ("chat_id", "user_id") DO NOTHING"#,
+        vec![chat.into(), user.into()],
+    ))
+    .await?;
+
+    let _: () = ctx
+        .redis
+        .pipe(|p| p.set_ex(&key, true, SEEN_MEMBER_TTL_SECS))
+        .await?;
+    Ok(())
+}
+
+// forget that a user is a member of a chat after they left or were removed
+pub(crate) async fn forget_chat_member(ctx: &BotContext, chat: i64, user: i64) -> Result<()> {
+    let key = format!("{}:{}:{}", KEY_SEEN_MEMBER, chat, user);
+    chat_members::Entity::delete_by_id((chat, user))
+        .exec(&*ctx.db)
+        .await?;
+    let _: () = ctx.redis.pipe(|p| p.del(&key)).await?;
+    Ok(())
+}
@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use log::info;
+use sea_orm::entity::prelude::*;
+use sea_orm::{ActiveModelTrait, Set};
+use teloxide::types::{Message, Update, UpdateKind};
+
+use crate::context::BotContext;
+use crate::persist::core::disabled_modules;
+use crate::persist::redis::{default_cache_query, invalidate, CachedQueryTrait};
+use crate::persist::Result;
+use crate::tg::admin::require_admin;
+use crate::tg::command::{parse_cmd, Arg};
+use crate::tg::module::Registry;
+use crate::util::error::BotError;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODULES: &[&str] = &["sticker", "schedule"];
+    const COMMANDS: &[&str] = &["/list", "/schedule", "/remindme"];
+
+    #[test]
+    fn target_test() {
+        let target = |arg| target_name(arg, MODULES, COMMANDS);
+        assert!(target("Sticker").unwrap() == "sticker");
+        assert!(target("schedule").unwrap() == "schedule");
+        assert!(target("/schedule").unwrap() == "/schedule");
+        assert!(target("remindme").unwrap() == "/remindme");
+        assert!(target("/enable").is_err());
+        assert!(target("/nothing").is_err());
+        assert!(target("/").is_err());
+    }
+
+    #[test]
+    fn command_test() {
+        assert!(command_name("/List@some_bot foo").unwrap() == "/list");
+        assert!(command_name("/list").unwrap() == "/list");
+        assert!(command_name("list /list").is_none());
+        assert!(command_name("").is_none());
+    }
+}
+
+// redis keys
+const KEY_DISABLED: &str = "disabled";
+
+// commands for managing modules, these can never be disabled
+const CORE_COMMANDS: [&str; 3] = ["/disable", "/enable", "/modules"];
+
+// modules and commands disabled in the chat an update came from
+#[derive(Default)]
+pub struct Disabled(HashSet<String>);
+
+impl Disabled {
+    pub fn new(names: HashSet<String>) -> Self {
+        Self(names)
+    }
+
+    pub fn module(&self, name: &str) -> bool {
+        self.0.contains(name)
+    }
+
+    // the command the update starts with, if it is disabled in its chat
+    pub fn command(&self, update: &Update) -> Option<String> {
+        match update.kind {
+            UpdateKind::Message(ref message) => message
+                .text()
+                .and_then(command_name)
+                .filter(|cmd| self.0.contains(cmd)),
+            _ => None,
+        }
+    }
+}
+
+// the command a message starts with, without any @botname suffix
+fn command_name(text: &str) -> Option<String> {
+    let first = text.split_whitespace().next()?;
+    if first.starts_with('/') {
+        let cmd = first.split('@').next().unwrap_or(first);
+        Some(cmd.to_lowercase())
+    } else {
+        None
+    }
+}
+
+// the name stored for /disable and /enable arguments. Anything that is not a
+// module name is treated as a command
+fn target_name(arg: &str, modules: &[&str], commands: &[&str]) -> Result<String> {
+    let name = arg.to_lowercase();
+    if modules.contains(&name.as_str()) {
+        return Ok(name);
+    }
+    let cmd = if name.starts_with('/') {
+        name
+    } else {
+        format!("/{}", name)
+    };
+    if cmd.len() < 2 {
+        Err(anyhow!(BotError::new("Missing module or command name")))
+    } else if CORE_COMMANDS.contains(&cmd.as_str()) {
+        Err(anyhow!(BotError::new(format!("{} can't be disabled", cmd))))
+    } else if !commands.contains(&cmd.as_str()) {
+        Err(anyhow!(BotError::new(format!(
+            "No module or command named {}",
+            arg
+        ))))
+    } else {
+        Ok(cmd)
+    }
+}
+
+pub(crate) fn disabled_key(chat: i64) -> String {
+    format!("{}:{}", KEY_DISABLED, chat)
+}
+
+async fn get_disabled(ctx: &BotContext, chat: i64) -> Result<HashSet<String>> {
+    let ctx = ctx.clone();
+    let key = disabled_key(chat);
+    let disabled = tokio::spawn(async move {
+        default_cache_query(move |_, sql| async move {
+            let sql: &DatabaseConnection = sql;
+            let names: Vec<String> = disabled_modules::Entity::find()
+                .filter(disabled_modules::Column::ChatId.eq(chat))
+                .all(sql)
+                .await?
+                .into_iter()
+                .map(|disabled| disabled.name)
+                .collect();
+            Ok(Some(names))
+        })
+        .query(&ctx.db, &ctx.redis, &key)
+        .await
+    })
+    .await??;
+    Ok(disabled.unwrap_or_default().into_iter().collect())
+}
+
+// updates without a chat, like inline queries, are never filtered
+pub async fn disabled_for(ctx: &BotContext, update: &Update) -> Result<Disabled> {
+    if let Some(chat) = update.chat() {
+        Ok(Disabled(get_disabled(ctx, chat.id).await?))
+    } else {
+        Ok(Disabled::default())
+    }
+}
+
+async fn set_disabled(
+    ctx: &BotContext,
+    message: &Message,
+    args: &[Arg],
+    registry: &Registry,
+    disable: bool,
+) -> Result<()> {
+    require_admin(ctx, message).await?;
+    let chat = message.chat.id;
+    let modules = registry.names();
+    let commands: Vec<&str> = registry
+        .modules()
+        .flat_map(|module| module.commands().iter().map(|c| c.name))
+        .collect();
+    let names = args[1..]
+        .iter()
+        .filter_map(|arg| match arg {
+            Arg::Arg(name) => Some(target_name(name, &modules, &commands)),
+            Arg::Quote(_) => None,
+        })
+        .collect::<Result<Vec<String>>>()?;
+    if names.is_empty() {
+        let usage = if disable {
+            "Usage: /disable <module|command>..."
+        } else {
+            "Usage: /enable <module|command>..."
+        };
+        return Err(anyhow!(BotError::new(usage)));
+    }
+
+    let db = &*ctx.db;
+    for name in names.iter() {
+        if disable {
+            if disabled_modules::Entity::find_by_id((chat, name.clone()))
+                .one(db)
+                .await?
+                .is_none()
+            {
+                disabled_modules::ActiveModel {
+                    chat_id: Set(chat),
+                    name: Set(name.clone()),
+                }
+                .insert(db)
+                .await?;
+            }
+        } else {
+            disabled_modules::Entity::delete_many()
+                .filter(disabled_modules::Column::ChatId.eq(chat))
+                .filter(disabled_modules::Column::Name.eq(name.clone()))
+                .exec(db)
+                .await?;
+        }
+    }
+    invalidate(&ctx.redis, &disabled_key(chat)).await?;
+
+    let verb = if disable { "Disabled" } else { "Enabled" };
+    ctx.tg
+        .reply(message, format!("{} {}", verb, names.join(", ")))
+        .await?;
+    Ok(())
+}
+
+async fn list_modules(ctx: &BotContext, message: &Message, registry: &Registry) -> Result<()> {
+    let disabled = get_disabled(ctx, message.chat.id).await?;
+    let mut text = registry
+        .modules()
+        .map(|module| {
+            let state = if disabled.contains(module.name()) {
+                "disabled"
+            } else {
+                "enabled"
+            };
+            format!("{} ({}): {}", module.name(), state, module.description())
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let mut commands: Vec<&String> = disabled.iter().filter(|n| n.starts_with('/')).collect();
+    if !commands.is_empty() {
+        commands.sort();
+        text.push_str("\n\nDisabled commands: ");
+        text.push_str(
+            &commands
+                .into_iter()
+                .map(|c| c.as_str())
+                .collect::<Vec<&str>>()
+                .join(", "),
+        );
+    }
+    ctx.tg.reply(message, text).await?;
+    Ok(())
+}
+
+async fn handle_command(ctx: &BotContext, message: &Message, registry: &Registry) -> Result<()> {
+    if let Some(text) = message.text() {
+        let command = parse_cmd(text)?;
+        if let Some(Arg::Arg(cmd)) = command.first() {
+            match cmd.as_str() {
+                "/disable" => set_disabled(ctx, message, &command, registry, true).await,
+                "/enable" => set_disabled(ctx, message, &command, registry, false).await,
+                "/modules" => list_modules(ctx, message, registry).await,
+                _ => Ok(()),
+            }?;
+        }
+    }
+    Ok(())
+}
+
+// handles the module management commands, run before any module sees the update
+pub async fn handle_update(ctx: &BotContext, update: &Update, registry: &Registry) {
+    if let UpdateKind::Message(ref message) = update.kind {
+        if let Err(err) = handle_command(ctx, message, registry).await {
+            info!("error {}", err);
+            if let Err(send_err) = ctx.tg.send(message.chat.id, err.to_string()).await {
+                log::error!("failed to send error message: {}", send_err);
+            }
+        }
+    }
+}
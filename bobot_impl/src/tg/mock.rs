@@ -0,0 +1,348 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use sea_orm::{DbBackend, MockDatabase};
+use serde_json::{json, Value};
+use teloxide::types::{InlineQueryResult, StickerSet, Update};
+
+use super::api::TgApi;
+use super::dialog::ConversationStore;
+use super::Result;
+use crate::config::Config;
+use crate::context::BotContext;
+use crate::persist;
+use crate::persist::codec::Codec;
+use crate::persist::redis::{RedisPoolBuilder, RedisStr};
+use crate::util::error::BotError;
+
+// mock contexts give up on a missing redis server quickly instead of
+// stalling every test that touches it
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+// everything a module asked telegram to do, in order
+#[derive(Clone, Debug)]
+pub(crate) enum Sent {
+    Message {
+        chat: i64,
+        text: String,
+        reply_to: Option<i32>,
+    },
+    Sticker {
+        chat: i64,
+        file_id: String,
+    },
+    Document {
+        chat: i64,
+        file_name: String,
+        data: Vec<u8>,
+    },
+    InlineAnswer {
+        query_id: String,
+        results: Vec<InlineQueryResult>,
+        next_offset: String,
+    },
+    Ban {
+        chat: i64,
+        user: i64,
+    },
+    Unban {
+        chat: i64,
+        user: i64,
+    },
+}
+
+// Telegram api that records requests instead of sending them
+#[derive(Default)]
+pub(crate) struct MockTg {
+    sent: Mutex<Vec<Sent>>,
+    admins: Mutex<HashSet<(i64, i64)>>,
+    files: Mutex<HashMap<String, Vec<u8>>>,
+    sticker_sets: Mutex<HashMap<String, StickerSet>>,
+}
+
+impl MockTg {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn sent(&self) -> Vec<Sent> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    // text of every message sent to a chat
+    pub(crate) fn messages(&self, chat: i64) -> Vec<String> {
+        self.sent()
+            .into_iter()
+            .filter_map(|s| match s {
+                Sent::Message { chat: c, text, .. } if c == chat => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub(crate) fn clear(&self) {
+        self.sent.lock().unwrap().clear();
+    }
+
+    pub(crate) fn set_admin(&self, chat: i64, user: i64) {
+        self.admins.lock().unwrap().insert((chat, user));
+    }
+
+    pub(crate) fn add_file<T: Into<String>>(&self, file_id: T, data: Vec<u8>) {
+        self.files.lock().unwrap().insert(file_id.into(), data);
+    }
+
+    pub(crate) fn add_sticker_set(&self, set: StickerSet) {
+        self.sticker_sets
+            .lock()
+            .unwrap()
+            .insert(set.name.clone(), set);
+    }
+
+    fn record(&self, sent: Sent) -> Result<()> {
+        self.sent.lock().unwrap().push(sent);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TgApi for MockTg {
+    async fn send_message(&self, chat: i64, text: String, reply_to: Option<i32>) -> Result<()> {
+        self.record(Sent::Message {
+            chat,
+            text,
+            reply_to,
+        })
+    }
+
+    async fn send_sticker(&self, chat: i64, file_id: String) -> Result<()> {
+        self.record(Sent::Sticker { chat, file_id })
+    }
+
+    async fn send_document(
+        &self,
+        chat: i64,
+        file_name: String,
+        data: Vec<u8>,
+        _reply_to: Option<i32>,
+    ) -> Result<()> {
+        self.record(Sent::Document {
+            chat,
+            file_name,
+            data,
+        })
+    }
+
+    async fn answer_inline_query(
+        &self,
+        query_id: String,
+        results: Vec<InlineQueryResult>,
+        next_offset: String,
+    ) -> Result<()> {
+        self.record(Sent::InlineAnswer {
+            query_id,
+            results,
+            next_offset,
+        })
+    }
+
+    async fn is_chat_admin(&self, chat: i64, user: i64) -> Result<bool> {
+        Ok(self.admins.lock().unwrap().contains(&(chat, user)))
+    }
+
+    async fn ban_chat_member(&self, chat: i64, user: i64) -> Result<()> {
+        self.record(Sent::Ban { chat, user })
+    }
+
+    async fn unban_chat_member(&self, chat: i64, user: i64) -> Result<()> {
+        self.record(Sent::Unban { chat, user })
+    }
+
+    async fn get_sticker_set(&self, name: String) -> Result<StickerSet> {
+        self.sticker_sets
+            .lock()
+            .unwrap()
+            .get(&name)
+            .cloned()
+            .ok_or_else(|| BotError::new(format!("no sticker set {}", name)))
+    }
+
+    async fn download_file(&self, file_id: &str) -> Result<Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(file_id)
+            .cloned()
+            .ok_or_else(|| BotError::new(format!("no file {}", file_id)))
+    }
+}
+
+// Conversation store kept in memory. Nothing expires, tests are too short
+// for that to matter
+#[derive(Default)]
+pub(crate) struct MemoryConversations {
+    vals: Mutex<HashMap<String, Vec<u8>>>,
+    hashes: Mutex<HashMap<String, HashMap<String, RedisStr>>>,
+}
+
+#[async_trait]
+impl ConversationStore for MemoryConversations {
+    async fn get(&self, key: &str) -> persist::Result<Option<Vec<u8>>> {
+        Ok(self.vals.lock().unwrap().get(key).cloned())
+    }
+
+    async fn set(&self, vals: Vec<(String, Vec<u8>)>) -> persist::Result<()> {
+        self.vals.lock().unwrap().extend(vals);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> persist::Result<()> {
+        self.vals.lock().unwrap().remove(key);
+        self.hashes.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn set_fields(
+        &self,
+        key: &str,
+        fields: Vec<(String, RedisStr)>,
+        _ttl: usize,
+    ) -> persist::Result<()> {
+        let mut hashes = self.hashes.lock().unwrap();
+        if fields.is_empty() {
+            hashes.remove(key);
+        } else {
+            hashes.insert(key.to_owned(), fields.into_iter().collect());
+        }
+        Ok(())
+    }
+
+    async fn update_fields(
+        &self,
+        key: &str,
+        fields: Vec<(String, RedisStr)>,
+    ) -> persist::Result<()> {
+        if !fields.is_empty() {
+            self.hashes
+                .lock()
+                .unwrap()
+                .entry(key.to_owned())
+                .or_default()
+                .extend(fields);
+        }
+        Ok(())
+    }
+
+    async fn get_fields(&self, key: &str) -> persist::Result<HashMap<String, RedisStr>> {
+        Ok(self
+            .hashes
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn codec(&self) -> Codec {
+        Codec::default()
+    }
+}
+
+fn message_json(chat: i64, user: i64) -> Value {
+    let chat = if chat < 0 {
+        json!({ "id": chat, "type": "group", "title": "test" })
+    } else {
+        json!({ "id": chat, "type": "private", "first_name": "test" })
+    };
+    json!({
+        "message_id": 1,
+        "date": 0,
+        "chat": chat,
+        "from": { "id": user, "is_bot": false, "first_name": "test" }
+    })
+}
+
+fn sticker_json(file_id: &str, set_name: Option<&str>) -> Value {
+    json!({
+        "file_id": file_id,
+        "file_unique_id": file_id,
+        "width": 512,
+        "height": 512,
+        "is_animated": false,
+        "is_video": false,
+        "emoji": "🙂",
+        "set_name": set_name
+    })
+}
+
+fn update(message: Value) -> Update {
+    serde_json::from_value(json!({ "update_id": 1, "message": message }))
+        .expect("invalid synthetic update")
+}
+
+// build a synthetic text message update. Negative chat ids are groups
+pub(crate) fn message_update(chat: i64, user: i64, text: &str) -> Update {
+    let mut message = message_json(chat, user);
+    message["text"] = text.into();
+    update(message)
+}
+
+// a sticker message, optionally from a sticker set
+pub(crate) fn sticker_update(
+    chat: i64,
+    user: i64,
+    file_id: &str,
+    set_name: Option<&str>,
+) -> Update {
+    let mut message = message_json(chat, user);
+    message["sticker"] = sticker_json(file_id, set_name);
+    update(message)
+}
+
+// a text message replying to a sticker
+pub(crate) fn reply_update(chat: i64, user: i64, text: &str, file_id: &str) -> Update {
+    let mut reply = message_json(chat, user);
+    reply["sticker"] = sticker_json(file_id, None);
+    let mut message = message_json(chat, user);
+    message["text"] = text.into();
+    message["reply_to_message"] = reply;
+    update(message)
+}
+
+pub(crate) fn sticker_set(name: &str, title: &str, file_ids: &[&str]) -> StickerSet {
+    let stickers: Vec<Value> = file_ids
+        .iter()
+        .map(|file_id| sticker_json(file_id, Some(name)))
+        .collect();
+    serde_json::from_value(json!({
+        "name": name,
+        "title": title,
+        "is_animated": false,
+        "is_video": false,
+        "contains_masks": false,
+        "stickers": stickers
+    }))
+    .expect("invalid synthetic sticker set")
+}
+
+// Context backed by a recording telegram api, a mock database answering
+// queries with the results queued on it, in order, and conversations kept in
+// memory. Redis only connects when first used
+pub(crate) async fn mock_context_with(db: MockDatabase) -> (BotContext, Arc<MockTg>) {
+    let tg = Arc::new(MockTg::new());
+    let redis = RedisPoolBuilder::new("redis://127.0.0.1/")
+        .connection_timeout(REDIS_TIMEOUT)
+        .build()
+        .await
+        .expect("invalid redis url");
+    let mut ctx = BotContext::new(Config::default(), db.into_connection(), redis, tg.clone());
+    ctx.conversations = Arc::new(MemoryConversations::default());
+    (ctx, tg)
+}
+
+// a context whose database has nothing queued, so every query fails
+pub(crate) async fn mock_context() -> (BotContext, Arc<MockTg>) {
+    mock_context_with(MockDatabase::new(DbBackend::Postgres)).await
+}
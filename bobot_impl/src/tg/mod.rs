@@ -2,8 +2,15 @@ use crate::util::error::BotError;
 
 pub type Result<T> = anyhow::Result<T, BotError>;
 
+pub mod api;
 pub mod client;
 #[allow(dead_code)]
 pub(crate) mod dialog;
+pub mod disable;
+#[cfg(test)]
+#[allow(dead_code)]
+pub(crate) mod mock;
+pub mod module;
 
+pub(crate) mod admin;
 pub(crate) mod command;
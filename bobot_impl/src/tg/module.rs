@@ -0,0 +1,532 @@
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use futures::future::join_all;
+use futures::FutureExt;
+use sea_orm::sea_query::TableCreateStatement;
+use sea_schema::migration::MigrationTrait;
+use teloxide::types::{Update, UpdateKind};
+
+use crate::config::Config;
+use crate::context::BotContext;
+use crate::persist::writecache::Flush;
+use crate::persist::Result;
+use crate::tg::disable::{self, Disabled};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tg::mock::{message_update, mock_context};
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Copy)]
+    enum Behavior {
+        Record,
+        Stop,
+        Panic,
+        Hang,
+    }
+
+    struct Recorder {
+        name: &'static str,
+        priority: i32,
+        behavior: Behavior,
+        dependencies: &'static [&'static str],
+        seen: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl BotModule for Recorder {
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn description(&self) -> &'static str {
+            "records updates"
+        }
+
+        fn priority(&self) -> i32 {
+            self.priority
+        }
+
+        fn dependencies(&self) -> &'static [&'static str] {
+            self.dependencies
+        }
+
+        async fn handle_update(&self, _: &BotContext, _: &Update) -> Propagation {
+            match self.behavior {
+                Behavior::Panic => panic!("module panicked"),
+                Behavior::Hang => tokio::time::sleep(std::time::Duration::from_secs(60)).await,
+                _ => (),
+            }
+            self.seen.lock().unwrap().push(self.name);
+            match self.behavior {
+                Behavior::Stop => Propagation::Stop,
+                _ => Propagation::Continue,
+            }
+        }
+    }
+
+    fn registry(
+        modules: &[(&'static str, i32, Behavior)],
+    ) -> (Registry, Arc<Mutex<Vec<&'static str>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let modules = modules
+            .iter()
+            .map(|&(name, priority, behavior)| -> Box<dyn BotModule> {
+                Box::new(Recorder {
+                    name,
+                    priority,
+                    behavior,
+                    dependencies: &[],
+                    seen: seen.clone(),
+                })
+            })
+            .collect();
+        (Registry::new(modules), seen)
+    }
+
+    #[tokio::test]
+    async fn priority_test() {
+        let (registry, seen) = registry(&[
+            ("low", -1, Behavior::Record),
+            ("high", 10, Behavior::Record),
+            ("mid", 0, Behavior::Record),
+        ]);
+        assert_eq!(registry.names(), vec!["high", "mid", "low"]);
+        let (ctx, _) = mock_context().await;
+        registry
+            .dispatch(&ctx, &message_update(1, 1, "hi"), &Disabled::default())
+            .await;
+        assert_eq!(*seen.lock().unwrap(), vec!["high", "mid", "low"]);
+    }
+
+    #[test]
+    fn schema_order_test() {
+        let module = |name, dependencies| -> Box<dyn BotModule> {
+            Box::new(Recorder {
+                name,
+                priority: 0,
+                behavior: Behavior::Record,
+                dependencies,
+                seen: Arc::new(Mutex::new(Vec::new())),
+            })
+        };
+        let names = |registry: &Registry| -> Vec<&'static str> {
+            registry
+                .schema_order()
+                .unwrap()
+                .into_iter()
+                .map(|module| module.name())
+                .collect()
+        };
+        let registry = Registry::new(vec![
+            module("a", &["c"]),
+            module("b", &[]),
+            module("c", &["b"]),
+        ]);
+        assert_eq!(names(&registry), vec!["b", "c", "a"]);
+
+        let registry = Registry::new(vec![module("a", &["b"]), module("b", &["a"])]);
+        assert!(registry.schema_order().is_err());
+        let registry = Registry::new(vec![module("a", &["missing"])]);
+        assert!(registry.schema_order().is_err());
+    }
+
+    #[tokio::test]
+    async fn isolation_test() {
+        let (registry, seen) = registry(&[
+            ("panics", 10, Behavior::Panic),
+            ("hangs", 10, Behavior::Hang),
+            ("stops", 10, Behavior::Stop),
+            ("same", 10, Behavior::Record),
+            ("later", 0, Behavior::Record),
+        ]);
+        let (mut ctx, _) = mock_context().await;
+        let mut config = (*ctx.config).clone();
+        config.dispatch.timeout_ms = 50;
+        ctx.config = Arc::new(config);
+        let mut disabled = HashSet::new();
+        disabled.insert("same".to_owned());
+        registry
+            .dispatch(&ctx, &message_update(1, 1, "hi"), &Disabled::new(disabled))
+            .await;
+        assert_eq!(*seen.lock().unwrap(), vec!["stops"]);
+    }
+
+    // owns /owned and records which of its handlers ran
+    struct Owner {
+        seen: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[async_trait]
+    impl BotModule for Owner {
+        fn name(&self) -> &'static str {
+            "owner"
+        }
+
+        fn description(&self) -> &'static str {
+            "owns a command"
+        }
+
+        fn commands(&self) -> &'static [ModuleCommand] {
+            &[ModuleCommand::new("/owned", "owned command")]
+        }
+
+        async fn handle_hooks(&self, _: &BotContext, _: &Update) -> Propagation {
+            self.seen.lock().unwrap().push("hooks");
+            Propagation::Continue
+        }
+
+        async fn handle_update(&self, _: &BotContext, _: &Update) -> Propagation {
+            self.seen.lock().unwrap().push("update");
+            Propagation::Continue
+        }
+    }
+
+    #[tokio::test]
+    async fn disabled_command_test() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let registry = Registry::new(vec![
+            Box::new(Owner { seen: seen.clone() }),
+            Box::new(Recorder {
+                name: "other",
+                priority: -1,
+                behavior: Behavior::Record,
+                dependencies: &[],
+                seen: seen.clone(),
+            }),
+        ]);
+        let (ctx, _) = mock_context().await;
+        let mut disabled = HashSet::new();
+        disabled.insert("/owned".to_owned());
+        let disabled = Disabled::new(disabled);
+
+        registry
+            .dispatch(&ctx, &message_update(1, 1, "/owned@bot x"), &disabled)
+            .await;
+        assert_eq!(*seen.lock().unwrap(), vec!["hooks", "other"]);
+
+        seen.lock().unwrap().clear();
+        registry
+            .dispatch(&ctx, &message_update(1, 1, "hi"), &disabled)
+            .await;
+        assert_eq!(*seen.lock().unwrap(), vec!["update", "other"]);
+    }
+}
+
+// the kinds of update a module can ask to receive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateType {
+    Message,
+    EditedMessage,
+    InlineQuery,
+    ChosenInlineResult,
+    CallbackQuery,
+    ChatMember,
+    Other,
+}
+
+impl UpdateType {
+    pub fn of(update: &Update) -> Self {
+        match update.kind {
+            UpdateKind::Message(_) => UpdateType::Message,
+            UpdateKind::EditedMessage(_) => UpdateType::EditedMessage,
+            UpdateKind::InlineQuery(_) => UpdateType::InlineQuery,
+            UpdateKind::ChosenInlineResult(_) => UpdateType::ChosenInlineResult,
+            UpdateKind::CallbackQuery(_) => UpdateType::CallbackQuery,
+            UpdateKind::ChatMember(_) | UpdateKind::MyChatMember(_) => UpdateType::ChatMember,
+            _ => UpdateType::Other,
+        }
+    }
+}
+
+// whether modules with a lower priority should still see an update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Propagation {
+    Continue,
+    Stop,
+}
+
+// a command a module handles, shown in /modules
+pub struct ModuleCommand {
+    pub name: &'static str,
+    pub help: &'static str,
+}
+
+impl ModuleCommand {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self { name, help }
+    }
+}
+
+// A bot feature living in its own file under modules/. Every module exports a
+// unit struct named Module implementing this, which autoimport! collects
+// into the registry
+#[async_trait]
+pub trait BotModule: Send + Sync {
+    // unique name, also used for /disable and config sections
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn version(&self) -> &'static str {
+        "0.1.0"
+    }
+
+    fn commands(&self) -> &'static [ModuleCommand] {
+        &[]
+    }
+
+    // updates of other kinds are never passed to handle_update
+    fn update_kinds(&self) -> &'static [UpdateType] {
+        &[UpdateType::Message]
+    }
+
+    // modules with a higher priority see each update first. Modules sharing a
+    // priority handle it concurrently
+    fn priority(&self) -> i32 {
+        0
+    }
+
+    // Migrations for this module's tables, oldest first. New migrations must
+    // be appended since the schema version is an index into this list
+    fn migrations(&self) -> Vec<Box<dyn MigrationTrait>> {
+        Vec::new()
+    }
+
+    // Tables this module's entities expect, referenced tables first. Compared
+    // against the live database by `bobot schema check`
+    fn entities(&self) -> Vec<TableCreateStatement> {
+        Vec::new()
+    }
+
+    // write caches the background flusher should drain for this module
+    fn write_caches(&self) -> Vec<&'static dyn Flush> {
+        Vec::new()
+    }
+
+    // modules whose tables must exist before this module's migrations run
+    fn dependencies(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn validate_config(&self, _: &Config) -> Vec<String> {
+        Vec::new()
+    }
+
+    async fn init(&self, _: &BotContext) -> Result<()> {
+        Ok(())
+    }
+
+    async fn shutdown(&self, _: &BotContext) -> Result<()> {
+        Ok(())
+    }
+
+    // Runs instead of handle_update when the update is one of this module's
+    // commands disabled in its chat. Work every message needs, like
+    // enforcement or bookkeeping, belongs here as well
+    async fn handle_hooks(&self, _: &BotContext, _: &Update) -> Propagation {
+        Propagation::Continue
+    }
+
+    async fn handle_update(&self, ctx: &BotContext, update: &Update) -> Propagation;
+}
+
+// every module compiled into the bot, ordered by priority
+pub struct Registry {
+    modules: Vec<Box<dyn BotModule>>,
+}
+
+impl Registry {
+    pub fn new(mut modules: Vec<Box<dyn BotModule>>) -> Self {
+        // stable, so equal priorities keep their declaration order
+        modules.sort_by_key(|module| -module.priority());
+        Self { modules }
+    }
+
+    pub fn modules(&self) -> impl DoubleEndedIterator<Item = &dyn BotModule> {
+        self.modules.iter().map(|module| module.as_ref())
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.modules().map(|module| module.name()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn BotModule> {
+        self.modules().find(|module| module.name() == name)
+    }
+
+    // the module handling a command, if any
+    pub fn command_owner(&self, command: &str) -> Option<&dyn BotModule> {
+        self.modules()
+            .find(|module| module.commands().iter().any(|c| c.name == command))
+    }
+
+    // modules that want this kind of update, in priority order
+    pub fn modules_for<'a>(&'a self, update: &Update) -> impl Iterator<Item = &'a dyn BotModule> {
+        let kind = UpdateType::of(update);
+        self.modules()
+            .filter(move |module| module.update_kinds().contains(&kind))
+    }
+
+    // run an update through every module that wants it and is not disabled
+    // in the update's chat. A disabled command only silences its handler
+    pub async fn process_update(&self, ctx: &BotContext, update: &Update) {
+        disable::handle_update(ctx, update, self).await;
+        let disabled = match disable::disabled_for(ctx, update).await {
+            Ok(disabled) => disabled,
+            Err(err) => {
+                log::error!("failed to get disabled modules: {}", err);
+                Disabled::default()
+            }
+        };
+        self.dispatch(ctx, update, &disabled).await;
+    }
+
+    // Modules run in groups of equal priority, highest first. A group runs
+    // concurrently and the next one only starts after it finishes, unless a
+    // module in it stopped propagation. The owner of a disabled command only
+    // runs its hooks
+    pub async fn dispatch(&self, ctx: &BotContext, update: &Update, disabled: &Disabled) {
+        let timeout = Duration::from_millis(ctx.config.dispatch.timeout_ms);
+        let silenced = disabled
+            .command(update)
+            .and_then(|cmd| self.command_owner(&cmd))
+            .map(|module| module.name());
+        let modules: Vec<&dyn BotModule> = self
+            .modules_for(update)
+            .filter(|module| !disabled.module(module.name()))
+            .collect();
+        let mut start = 0;
+        while start < modules.len() {
+            let priority = modules[start].priority();
+            let end = modules[start..]
+                .iter()
+                .position(|module| module.priority() != priority)
+                .map_or(modules.len(), |len| start + len);
+            let results = join_all(modules[start..end].iter().map(|module| {
+                let hooks_only = silenced == Some(module.name());
+                run_module(*module, ctx, update, timeout, hooks_only)
+            }))
+            .await;
+            if results.contains(&Propagation::Stop) {
+                break;
+            }
+            start = end;
+        }
+    }
+
+    // modules ordered so each comes after its dependencies, otherwise keeping
+    // priority order
+    pub fn schema_order(&self) -> Result<Vec<&dyn BotModule>> {
+        let mut order = Vec::new();
+        let mut visiting = Vec::new();
+        for module in self.modules() {
+            self.visit(module, &mut visiting, &mut order)?;
+        }
+        Ok(order)
+    }
+
+    fn visit<'a>(
+        &'a self,
+        module: &'a dyn BotModule,
+        visiting: &mut Vec<&'static str>,
+        order: &mut Vec<&'a dyn BotModule>,
+    ) -> Result<()> {
+        if order.iter().any(|m| m.name() == module.name()) {
+            return Ok(());
+        }
+        if visiting.contains(&module.name()) {
+            visiting.push(module.name());
+            return Err(anyhow!(
+                "module dependency cycle: {}",
+                visiting.join(" -> ")
+            ));
+        }
+        visiting.push(module.name());
+        for dep in module.dependencies() {
+            let dep = self.get(dep).ok_or_else(|| {
+                anyhow!("module {} depends on unknown module {}", module.name(), dep)
+            })?;
+            self.visit(dep, visiting, order)?;
+        }
+        visiting.pop();
+        order.push(module);
+        Ok(())
+    }
+
+    // every module's write caches, for the flusher
+    pub fn write_caches(&self) -> Vec<&'static dyn Flush> {
+        self.modules()
+            .flat_map(|module| module.write_caches().into_iter())
+            .collect()
+    }
+
+    pub fn validate_config(&self, config: &Config) -> Vec<String> {
+        self.modules()
+            .flat_map(|module| module.validate_config(config).into_iter())
+            .collect()
+    }
+
+    pub async fn init(&self, ctx: &BotContext) -> Result<()> {
+        for module in self.modules() {
+            module.init(ctx).await?;
+        }
+        Ok(())
+    }
+
+    // every module gets to shut down even if an earlier one fails
+    pub async fn shutdown(&self, ctx: &BotContext) {
+        for module in self.modules().rev() {
+            if let Err(err) = module.shutdown(ctx).await {
+                log::error!("failed to shut down {}: {}", module.name(), err);
+            }
+        }
+    }
+}
+
+// run one module's handler, logging instead of propagating panics and timeouts
+async fn run_module(
+    module: &dyn BotModule,
+    ctx: &BotContext,
+    update: &Update,
+    timeout: Duration,
+    hooks_only: bool,
+) -> Propagation {
+    let handler = if hooks_only {
+        module.handle_hooks(ctx, update)
+    } else {
+        module.handle_update(ctx, update)
+    };
+    let handler = AssertUnwindSafe(handler).catch_unwind();
+    match tokio::time::timeout(timeout, handler).await {
+        Ok(Ok(propagation)) => propagation,
+        Ok(Err(panic)) => {
+            let reason = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            log::error!(
+                "module {} panicked handling update {}: {}",
+                module.name(),
+                update.id,
+                reason
+            );
+            Propagation::Continue
+        }
+        Err(_) => {
+            log::error!(
+                "module {} timed out handling update {} after {}ms",
+                module.name(),
+                update.id,
+                timeout.as_millis()
+            );
+            Propagation::Continue
+        }
+    }
+}
@@ -22,6 +22,8 @@ pub enum BotError {
     IoError(#[from] std::io::Error),
     #[error("teloxide request error")]
     RequestError(#[from] teloxide::RequestError),
+    #[error("teloxide download error")]
+    DownloadError(#[from] teloxide::DownloadError),
 }
 
 impl BotError {
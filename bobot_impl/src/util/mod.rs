@@ -1,3 +1,4 @@
 #[allow(dead_code)]
 pub mod callback;
 pub mod error;
+pub mod time;
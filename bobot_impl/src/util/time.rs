@@ -0,0 +1,254 @@
+use anyhow::anyhow;
+use chrono::{DateTime, Duration, NaiveDate, NaiveDateTime, NaiveTime, Utc};
+
+use crate::persist::Result;
+use crate::util::error::BotError;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn duration_test() {
+        assert!(parse_duration("1d").unwrap() == Duration::days(1));
+        assert!(parse_duration("2h30m").unwrap() == Duration::minutes(150));
+        assert!(parse_duration("1w2d").unwrap() == Duration::days(9));
+        assert!(parse_duration("90").is_err());
+        assert!(parse_duration("h").is_err());
+        assert!(parse_duration("520w").unwrap() == Duration::weeks(520));
+        assert!(parse_duration("99999999999999w").is_err());
+        assert!(parse_duration("9223372036854775807s").is_err());
+        assert!(parse_duration("500w500w").is_err());
+    }
+
+    #[test]
+    fn when_test() {
+        let now = parse_datetime("2026-11-01T10:00").unwrap();
+        let (t, n) = parse_when_at(now, &["2h", "check", "oven"]).unwrap();
+        assert!(t == now + Duration::hours(2) && n == 1);
+        let (t, n) = parse_when_at(now, &["in", "2", "hours", "30", "minutes", "x"]).unwrap();
+        assert!(t == now + Duration::minutes(150) && n == 5);
+        let (t, n) = parse_when_at(now, &["in", "an", "hour"]).unwrap();
+        assert!(t == now + Duration::hours(1) && n == 3);
+        let (t, n) = parse_when_at(now, &["at", "09:30", "standup"]).unwrap();
+        assert!(t == parse_datetime("2026-11-02T09:30").unwrap() && n == 2);
+        let (t, n) = parse_when_at(now, &["tomorrow", "at", "12:00"]).unwrap();
+        assert!(t == parse_datetime("2026-11-02T12:00").unwrap() && n == 3);
+        let (t, n) = parse_when_at(now, &["2026-12-24", "18:00", "gifts"]).unwrap();
+        assert!(t == parse_datetime("2026-12-24T18:00").unwrap() && n == 2);
+        assert!(parse_when_at(now, &["check", "oven"]).is_err());
+        assert!(parse_when_at(now, &["99999999999999w", "x"]).is_err());
+        assert!(parse_when_at(now, &["in", "9999999999999", "days", "x"]).is_err());
+        assert!(parse_when_at(now, &["in", "9223372036854775807", "weeks"]).is_err());
+        assert!(parse_when_at(now, &["in", "500", "weeks", "500", "weeks"]).is_err());
+    }
+}
+
+const MINUTE_SECS: i64 = 60;
+const HOUR_SECS: i64 = 60 * MINUTE_SECS;
+const DAY_SECS: i64 = 24 * HOUR_SECS;
+const WEEK_SECS: i64 = 7 * DAY_SECS;
+
+// longest duration accepted from user input. Larger values would overflow
+// chrono's Duration and are never meant anyway
+const MAX_DURATION_SECS: i64 = 3660 * DAY_SECS;
+
+fn too_long() -> anyhow::Error {
+    anyhow!(BotError::new("Durations can be at most 10 years"))
+}
+
+// count times a unit given in seconds, refusing anything over the maximum
+fn unit_duration(count: i64, unit_secs: i64) -> Result<Duration> {
+    count
+        .checked_mul(unit_secs)
+        .filter(|secs| (0..=MAX_DURATION_SECS).contains(secs))
+        .map(Duration::seconds)
+        .ok_or_else(too_long)
+}
+
+fn add_duration(total: Duration, duration: Duration) -> Result<Duration> {
+    total
+        .checked_add(&duration)
+        .filter(|total| total.num_seconds() <= MAX_DURATION_SECS)
+        .ok_or_else(too_long)
+}
+
+// parse compact durations such as "1d", "2h30m" or "1w2d12h"
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    compact_duration(s)
+        .unwrap_or_else(|| Err(anyhow!(BotError::new(format!("invalid duration {}", s)))))
+}
+
+// None if s is not a compact duration at all, an error if it is one but too
+// long
+fn compact_duration(s: &str) -> Option<Result<Duration>> {
+    let total = compact_units(s)?
+        .into_iter()
+        .try_fold(Duration::zero(), |total, (num, unit)| {
+            // digits that don't fit an i64 are too long as well
+            let count = num.parse().map_err(|_| too_long())?;
+            add_duration(total, unit_duration(count, unit)?)
+        });
+    match total {
+        Ok(total) if total == Duration::zero() => None,
+        total => Some(total),
+    }
+}
+
+// split "1w2d" into its counts and their units in seconds
+fn compact_units(s: &str) -> Option<Vec<(&str, i64)>> {
+    let s = s.trim();
+    let mut units = Vec::new();
+    let mut start = 0;
+    for (idx, c) in s.char_indices() {
+        if c.is_ascii_digit() {
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            'w' => WEEK_SECS,
+            'd' => DAY_SECS,
+            'h' => HOUR_SECS,
+            'm' => MINUTE_SECS,
+            's' => 1,
+            _ => return None,
+        };
+        if idx == start {
+            return None;
+        }
+        units.push((&s[start..idx], unit));
+        start = idx + c.len_utf8();
+    }
+    if start != s.len() || units.is_empty() {
+        None
+    } else {
+        Some(units)
+    }
+}
+
+// parse an absolute UTC time such as "2026-11-01T10:00" or an rfc3339 timestamp
+pub fn parse_datetime(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    ["%Y-%m-%dT%H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d %H:%M"]
+        .iter()
+        .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+        .map(|t| DateTime::<Utc>::from_utc(t, Utc))
+        .ok_or_else(|| anyhow!(BotError::new(format!("invalid time {}", s))))
+}
+
+// length of a unit in seconds
+fn parse_unit(unit: &str) -> Option<i64> {
+    match unit.to_ascii_lowercase().as_str() {
+        "s" | "sec" | "secs" | "second" | "seconds" => Some(1),
+        "m" | "min" | "mins" | "minute" | "minutes" => Some(MINUTE_SECS),
+        "h" | "hr" | "hrs" | "hour" | "hours" => Some(HOUR_SECS),
+        "d" | "day" | "days" => Some(DAY_SECS),
+        "w" | "week" | "weeks" => Some(WEEK_SECS),
+        _ => None,
+    }
+}
+
+fn parse_count(count: &str) -> Option<i64> {
+    match count.to_ascii_lowercase().as_str() {
+        "a" | "an" | "one" => Some(1),
+        count => count.parse().ok().filter(|count| *count >= 0),
+    }
+}
+
+fn parse_time_of_day(s: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M").ok()
+}
+
+// parse a human readable point in time from the start of a list of words.
+// Returns the time and how many words were consumed. Accepted forms include
+// "2h30m", "in 2 hours 30 minutes", "at 17:30", "tomorrow at 9:00",
+// "2026-11-01 10:00" and "2026-11-01T10:00". Times of day are in UTC
+pub fn parse_when(words: &[&str]) -> Result<(DateTime<Utc>, usize)> {
+    parse_when_at(Utc::now(), words)
+}
+
+fn parse_when_at(now: DateTime<Utc>, words: &[&str]) -> Result<(DateTime<Utc>, usize)> {
+    let err = || anyhow!(BotError::new("I don't understand when that is"));
+    let mut i = if words.first().map(|w| w.eq_ignore_ascii_case("in")) == Some(true) {
+        1
+    } else {
+        0
+    };
+    let first = *words.get(i).ok_or_else(err)?;
+
+    if first.eq_ignore_ascii_case("tomorrow") || first.eq_ignore_ascii_case("at") {
+        let tomorrow = first.eq_ignore_ascii_case("tomorrow");
+        i += 1;
+        if tomorrow && words.get(i).map(|w| w.eq_ignore_ascii_case("at")) == Some(true) {
+            i += 1;
+        }
+        let time = words.get(i).and_then(|w| parse_time_of_day(w));
+        let date = if tomorrow {
+            now.date().naive_utc().succ()
+        } else {
+            now.date().naive_utc()
+        };
+        let when = match time {
+            Some(time) => {
+                i += 1;
+                DateTime::<Utc>::from_utc(date.and_time(time), Utc)
+            }
+            None if tomorrow => DateTime::<Utc>::from_utc(date.and_time(now.time()), Utc),
+            None => return Err(err()),
+        };
+        // "at" a time that already passed today means tomorrow
+        let when = if when <= now {
+            when + Duration::days(1)
+        } else {
+            when
+        };
+        return Ok((when, i));
+    }
+
+    if let Some(time) = parse_time_of_day(first) {
+        let when = DateTime::<Utc>::from_utc(now.date().naive_utc().and_time(time), Utc);
+        let when = if when <= now {
+            when + Duration::days(1)
+        } else {
+            when
+        };
+        return Ok((when, i + 1));
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(first, "%Y-%m-%d") {
+        let time = words.get(i + 1).and_then(|w| parse_time_of_day(w));
+        return match time {
+            Some(time) => Ok((DateTime::<Utc>::from_utc(date.and_time(time), Utc), i + 2)),
+            None => Ok((DateTime::<Utc>::from_utc(date.and_hms(0, 0, 0), Utc), i + 1)),
+        };
+    }
+
+    if let Ok(when) = parse_datetime(first) {
+        return Ok((when, i + 1));
+    }
+
+    let start = i;
+    let mut total = Duration::zero();
+    while let Some(word) = words.get(i) {
+        if let Some(duration) = compact_duration(word) {
+            total = add_duration(total, duration?)?;
+            i += 1;
+        } else if let (Some(count), Some(unit)) = (
+            parse_count(word),
+            words.get(i + 1).and_then(|u| parse_unit(u)),
+        ) {
+            total = add_duration(total, unit_duration(count, unit)?)?;
+            i += 2;
+        } else if word.eq_ignore_ascii_case("and") && i > start {
+            i += 1;
+        } else {
+            break;
+        }
+    }
+    if i == start {
+        Err(err())
+    } else {
+        Ok((now + total, i))
+    }
+}
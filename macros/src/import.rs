@@ -68,25 +68,15 @@ pub(crate) fn autoimport(input: TokenStream) -> TokenStream {
     let module_globs = glob_modules(input.value());
     assert!(module_globs.len() > 0);
     let mods = module_globs.clone().into_iter();
-    let updates = module_globs.clone().into_iter();
-    let funcs = module_globs.into_iter();
+    let modules = module_globs.into_iter();
     let output = quote! {
         #( mod #mods; )*
 
-        pub fn get_migrations() -> ::std::vec::Vec<::std::boxed::Box<dyn ::sea_schema::migration::MigrationTrait>> {
-            let mut _v = ::std::vec::Vec::<::std::boxed::Box<dyn ::sea_schema::migration::MigrationTrait>>::new();
-            #(
-                _v.append(&mut #funcs::get_migrations());
-            )*
-            _v
-        }
-
-        pub async fn process_updates(
-            update: ::teloxide::types::Update
-            ) -> () {
-            #(
-                #updates::handle_update(&update).await
-            )*
+        // every module compiled into the bot
+        pub fn registry() -> crate::tg::module::Registry {
+            crate::tg::module::Registry::new(vec![
+                #( ::std::boxed::Box::new(#modules::Module), )*
+            ])
         }
     };
     output
@@ -1,17 +1,143 @@
 use async_trait::async_trait;
 pub use sea_schema::migration::*;
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bobot_impl::persist::core::*;
+    use sea_orm::{
+        ConnectOptions, ConnectionTrait, Database, DatabaseConnection, DbBackend, EntityTrait,
+        Schema, Statement,
+    };
+    use std::collections::BTreeSet;
+
+    // needs an empty postgres database to scribble on, skipped otherwise
+    const TEST_DATABASE_VAR: &str = "BOBOT_TEST_DATABASE_URL";
+
+    const TABLES: &str = "'chat_members', 'conversation_states', 'conversation_transitions', \
+        'conversations', 'dialogs', 'disabled_modules', 'module_schemas'";
+
+    type Row = (String, String, String, String);
+
+    async fn execute(db: &DatabaseConnection, sql: &str) {
+        db.execute(Statement::from_string(DbBackend::Postgres, sql.to_owned()))
+            .await
+            .unwrap();
+    }
+
+    async fn rows(db: &DatabaseConnection, sql: String) -> BTreeSet<Row> {
+        db.query_all(Statement::from_string(DbBackend::Postgres, sql))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|row| {
+                (
+                    row.try_get("", "a").unwrap(),
+                    row.try_get("", "b").unwrap(),
+                    row.try_get("", "c").unwrap(),
+                    row.try_get("", "d").unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    // columns and constraints of the core tables in a postgres schema,
+    // ignoring names postgres or sea-query picked
+    async fn describe(db: &DatabaseConnection, schema: &str) -> (BTreeSet<Row>, BTreeSet<Row>) {
+        let columns = rows(
+            db,
+            format!(
+                "SELECT table_name::text AS a, column_name::text AS b, data_type::text AS c, \
+                 is_nullable::text AS d FROM information_schema.columns \
+                 WHERE table_schema = '{}' AND table_name IN ({})",
+                schema, TABLES
+            ),
+        )
+        .await;
+        let constraints = rows(
+            db,
+            format!(
+                "SELECT tc.table_name::text AS a, tc.constraint_type::text AS b, \
+                 string_agg(kcu.column_name::text, ',' ORDER BY kcu.column_name) AS c, \
+                 coalesce(rc.delete_rule::text, '') AS d \
+                 FROM information_schema.table_constraints tc \
+                 JOIN information_schema.key_column_usage kcu \
+                 ON kcu.constraint_name = tc.constraint_name AND kcu.table_schema = tc.table_schema \
+                 LEFT JOIN information_schema.referential_constraints rc \
+                 ON rc.constraint_name = tc.constraint_name AND rc.constraint_schema = tc.table_schema \
+                 WHERE tc.table_schema = '{}' AND tc.table_name IN ({}) \
+                 GROUP BY tc.table_name, tc.constraint_name, tc.constraint_type, rc.delete_rule",
+                schema, TABLES
+            ),
+        )
+        .await;
+        (columns, constraints)
+    }
+
+    async fn use_schema(db: &DatabaseConnection, schema: &str) {
+        execute(db, &format!("DROP SCHEMA IF EXISTS {} CASCADE", schema)).await;
+        execute(db, &format!("CREATE SCHEMA {}", schema)).await;
+        execute(db, &format!("SET search_path TO {}", schema)).await;
+    }
+
+    async fn create_from_entity<E: EntityTrait>(db: &DatabaseConnection, entity: E) {
+        let stmt = Schema::new(DbBackend::Postgres).create_table_from_entity(entity);
+        db.execute(db.get_database_backend().build(&stmt))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn schema_matches_entities_test() {
+        let url = match std::env::var(TEST_DATABASE_VAR) {
+            Ok(url) => url,
+            Err(_) => {
+                eprintln!("{} not set, skipping", TEST_DATABASE_VAR);
+                return;
+            }
+        };
+        // one connection so search_path sticks
+        let mut options = ConnectOptions::new(url);
+        options.max_connections(1);
+        let db = Database::connect(options).await.unwrap();
+
+        use_schema(&db, "bobot_migrated").await;
+        Migrator::up(&db, None).await.unwrap();
+        let migrated = describe(&db, "bobot_migrated").await;
+
+        // referenced tables first
+        use_schema(&db, "bobot_entities").await;
+        create_from_entity(&db, dialogs::Entity).await;
+        create_from_entity(&db, chat_members::Entity).await;
+        create_from_entity(&db, conversations::Entity).await;
+        create_from_entity(&db, conversation_states::Entity).await;
+        create_from_entity(&db, conversation_transitions::Entity).await;
+        create_from_entity(&db, disabled_modules::Entity).await;
+        create_from_entity(&db, module_schemas::Entity).await;
+        let expected = describe(&db, "bobot_entities").await;
+
+        execute(&db, "DROP SCHEMA bobot_migrated CASCADE").await;
+        execute(&db, "DROP SCHEMA bobot_entities CASCADE").await;
+        assert_eq!(migrated.0, expected.0);
+        assert_eq!(migrated.1, expected.1);
+    }
+}
+
 mod m20220101_000001_create_table;
+mod m20220508_000001_create_disabled_modules;
+mod m20220515_000001_fix_core_schema;
 
 pub struct Migrator;
 
 #[async_trait]
 impl MigratorTrait for Migrator {
+    // core tables only, module migrations are versioned per module in
+    // module_schemas and run by the bot at startup
     fn migrations() -> Vec<Box<dyn MigrationTrait>> {
-        let mut module_migrations = bobot_impl::modules::get_migrations();
-        let mut core_migrations: Vec<Box<dyn MigrationTrait>> =
-            vec![Box::new(m20220101_000001_create_table::Migration)];
-        core_migrations.append(&mut module_migrations);
-        core_migrations
+        vec![
+            Box::new(m20220101_000001_create_table::Migration),
+            Box::new(m20220508_000001_create_disabled_modules::Migration),
+            Box::new(m20220515_000001_fix_core_schema::Migration),
+        ]
     }
 }
@@ -0,0 +1,46 @@
+use bobot_impl::persist::core::*;
+use bobot_impl::persist::migrate::ManagerHelper;
+use sea_schema::migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220508_000001_create_disabled_modules"
+    }
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager
+            .create_table(
+                Table::create()
+                    .table(disabled_modules::Entity)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(disabled_modules::Column::ChatId)
+                            .big_integer()
+                            .not_null(),
+                    )
+                    .col(
+                        ColumnDef::new(disabled_modules::Column::Name)
+                            .text()
+                            .not_null(),
+                    )
+                    .primary_key(
+                        Index::create()
+                            .col(disabled_modules::Column::ChatId)
+                            .col(disabled_modules::Column::Name),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(disabled_modules::Entity).await?;
+        Ok(())
+    }
+}
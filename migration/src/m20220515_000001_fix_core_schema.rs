@@ -0,0 +1,250 @@
+use bobot_impl::persist::core::*;
+use bobot_impl::persist::migrate::ManagerHelper;
+use sea_orm::{ConnectionTrait, Statement};
+use sea_schema::migration::prelude::*;
+
+pub struct Migration;
+
+impl MigrationName for Migration {
+    fn name(&self) -> &str {
+        "m20220515_000001_fix_core_schema"
+    }
+}
+
+// Foreign keys created by the first migration, as (table, column, target,
+// target column). They were unnamed, so postgres called them
+// <table>_<column>_fkey
+const OLD_FOREIGN_KEYS: [(&str, &str, &str, &str); 3] = [
+    (
+        "conversation_transitions",
+        "start_state",
+        "conversation_states",
+        "state_id",
+    ),
+    (
+        "conversation_transitions",
+        "end_state",
+        "conversation_states",
+        "state_id",
+    ),
+    (
+        "conversation_states",
+        "parent",
+        "conversations",
+        "conversation_id",
+    ),
+];
+
+async fn execute(manager: &SchemaManager<'_>, sql: &str) -> Result<(), DbErr> {
+    manager
+        .get_connection()
+        .execute(Statement::from_string(
+            manager.get_database_backend(),
+            sql.to_owned(),
+        ))
+        .await?;
+    Ok(())
+}
+
+// Cascading foreign key matching the relation declared on the entity
+fn cascade<T, C, R, S>(name: &str, from: (T, C), to: (R, S)) -> ForeignKeyCreateStatement
+where
+    T: IntoTableRef,
+    C: IntoIden,
+    R: IntoTableRef,
+    S: IntoIden,
+{
+    ForeignKey::create()
+        .name(name)
+        .from(from.0, from.1)
+        .to(to.0, to.1)
+        .on_delete(ForeignKeyAction::Cascade)
+        .on_update(ForeignKeyAction::NoAction)
+        .to_owned()
+}
+
+#[async_trait::async_trait]
+impl MigrationTrait for Migration {
+    async fn up(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        // chat_members gets a real composite key in place of the unique index
+        manager
+            .drop_index(
+                Index::drop()
+                    .name("chatuser")
+                    .table(chat_members::Entity)
+                    .to_owned(),
+            )
+            .await?;
+        execute(
+            manager,
+            "ALTER TABLE chat_members ADD PRIMARY KEY (chat_id, user_id)",
+        )
+        .await?;
+
+        // members were always written after their dialog, but older rows may
+        // predate that
+        execute(
+            manager,
+            "INSERT INTO dialogs (chat_id, last_activity) \
+             SELECT DISTINCT chat_id, now() FROM chat_members \
+             ON CONFLICT DO NOTHING",
+        )
+        .await?;
+        manager
+            .create_foreign_key(cascade(
+                "fk_chat_members_dialogs",
+                (chat_members::Entity, chat_members::Column::ChatId),
+                (dialogs::Entity, dialogs::Column::ChatId),
+            ))
+            .await?;
+
+        // existing timestamps were written in UTC
+        execute(
+            manager,
+            "ALTER TABLE dialogs ALTER COLUMN last_activity \
+             TYPE timestamp with time zone USING last_activity AT TIME ZONE 'UTC'",
+        )
+        .await?;
+
+        for (table, column, _, _) in OLD_FOREIGN_KEYS {
+            execute(
+                manager,
+                &format!(
+                    "ALTER TABLE {} DROP CONSTRAINT IF EXISTS {}_{}_fkey",
+                    table, table, column
+                ),
+            )
+            .await?;
+        }
+        manager
+            .create_foreign_key(cascade(
+                "fk_conversation_transitions_start_state",
+                (
+                    conversation_transitions::Entity,
+                    conversation_transitions::Column::StartState,
+                ),
+                (
+                    conversation_states::Entity,
+                    conversation_states::Column::StateId,
+                ),
+            ))
+            .await?;
+        manager
+            .create_foreign_key(cascade(
+                "fk_conversation_transitions_end_state",
+                (
+                    conversation_transitions::Entity,
+                    conversation_transitions::Column::EndState,
+                ),
+                (
+                    conversation_states::Entity,
+                    conversation_states::Column::StateId,
+                ),
+            ))
+            .await?;
+        manager
+            .create_foreign_key(cascade(
+                "fk_conversation_states_conversations",
+                (
+                    conversation_states::Entity,
+                    conversation_states::Column::Parent,
+                ),
+                (conversations::Entity, conversations::Column::ConversationId),
+            ))
+            .await?;
+
+        execute(
+            manager,
+            "ALTER TABLE conversation_states \
+             ADD CONSTRAINT conversation_states_start_for_key UNIQUE (start_for)",
+        )
+        .await?;
+
+        // the bot also creates this on startup, so it may already exist
+        manager
+            .create_table(
+                Table::create()
+                    .table(module_schemas::Entity)
+                    .if_not_exists()
+                    .col(
+                        ColumnDef::new(module_schemas::Column::ModuleName)
+                            .text()
+                            .primary_key(),
+                    )
+                    .col(
+                        ColumnDef::new(module_schemas::Column::SchemaVersion)
+                            .integer()
+                            .not_null(),
+                    )
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn down(&self, manager: &SchemaManager) -> Result<(), DbErr> {
+        manager.drop_table_auto(module_schemas::Entity).await?;
+        execute(
+            manager,
+            "ALTER TABLE conversation_states DROP CONSTRAINT conversation_states_start_for_key",
+        )
+        .await?;
+
+        for (table, name) in [
+            (
+                "conversation_transitions",
+                "fk_conversation_transitions_start_state",
+            ),
+            (
+                "conversation_transitions",
+                "fk_conversation_transitions_end_state",
+            ),
+            (
+                "conversation_states",
+                "fk_conversation_states_conversations",
+            ),
+            ("chat_members", "fk_chat_members_dialogs"),
+        ] {
+            execute(
+                manager,
+                &format!("ALTER TABLE {} DROP CONSTRAINT {}", table, name),
+            )
+            .await?;
+        }
+        for (table, column, target, target_column) in OLD_FOREIGN_KEYS {
+            execute(
+                manager,
+                &format!(
+                    "ALTER TABLE {} ADD CONSTRAINT {}_{}_fkey FOREIGN KEY ({}) REFERENCES {} ({})",
+                    table, table, column, column, target, target_column
+                ),
+            )
+            .await?;
+        }
+
+        execute(
+            manager,
+            "ALTER TABLE dialogs ALTER COLUMN last_activity \
+             TYPE timestamp USING last_activity AT TIME ZONE 'UTC'",
+        )
+        .await?;
+
+        execute(
+            manager,
+            "ALTER TABLE chat_members DROP CONSTRAINT chat_members_pkey",
+        )
+        .await?;
+        manager
+            .create_index(
+                Index::create()
+                    .name("chatuser")
+                    .table(chat_members::Entity)
+                    .col(chat_members::Column::UserId)
+                    .col(chat_members::Column::ChatId)
+                    .unique()
+                    .to_owned(),
+            )
+            .await?;
+        Ok(())
+    }
+}